@@ -1,4 +1,4 @@
-use libc::{c_int, getsockopt, sockaddr_in, socklen_t};
+use libc::{c_int, getsockopt, recv, sockaddr_in, socklen_t, MSG_DONTWAIT, MSG_PEEK};
 use std::mem;
 use std::os::unix::io::AsRawFd;
 use tokio::net::TcpStream;
@@ -23,13 +23,26 @@ pub fn common_get_orig_dst(
     };
 
     if ret == -1 {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "获取原始目的地址失败",
-        ));
+        return Err(std::io::Error::other("获取原始目的地址失败"));
     }
 
     let ip = std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
     let port = u16::from_be(addr.sin_port);
     Ok(std::net::SocketAddr::new(std::net::IpAddr::V4(ip), port))
 }
+
+/* 非阻塞地预读socket中的数据，不取走数据; 没有数据时返回WouldBlock */
+pub fn common_try_peek(socket: &TcpStream, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+    let ret = unsafe {
+        recv(
+            socket.as_raw_fd(),
+            buffer.as_mut_ptr() as *mut _,
+            buffer.len(),
+            MSG_PEEK | MSG_DONTWAIT,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
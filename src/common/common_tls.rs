@@ -0,0 +1,60 @@
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, RootCertStore};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+/* 读取PEM格式的证书链 */
+pub fn common_tls_load_certs(file: &str) -> Result<Vec<CertificateDer<'static>>, std::io::Error> {
+    let mut reader = BufReader::new(File::open(file)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(std::io::Error::other(format!("{} 中没有证书", file)));
+    }
+    return Ok(certs);
+}
+
+/* 读取PEM格式的私钥(PKCS#1 / PKCS#8 / SEC1) */
+pub fn common_tls_load_key(file: &str) -> Result<PrivateKeyDer<'static>, std::io::Error> {
+    let mut reader = BufReader::new(File::open(file)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => return Ok(key),
+        None => return Err(std::io::Error::other(format!("{} 中没有私钥", file))),
+    }
+}
+
+/* 信任的根证书: ca_file为空时使用内置的根证书，否则只信任ca_file中的证书 */
+pub fn common_tls_root_store(ca_file: &str) -> Result<RootCertStore, std::io::Error> {
+    let mut roots = RootCertStore::empty();
+    if ca_file.is_empty() {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    } else {
+        for cert in common_tls_load_certs(ca_file)? {
+            roots.add(cert).map_err(std::io::Error::other)?;
+        }
+    }
+    return Ok(roots);
+}
+
+/*
+* 构造TLS客户端配置
+* 1. ca_file为空时使用内置的根证书，否则只信任ca_file中的证书
+* 2. cert_file/key_file不为空时，向服务端出示客户端证书(mTLS)
+*/
+pub fn common_tls_client_config(
+    ca_file: &str,
+    cert_file: &str,
+    key_file: &str,
+) -> Result<ClientConfig, std::io::Error> {
+    let roots = common_tls_root_store(ca_file)?;
+    let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(std::io::Error::other)?
+        .with_root_certificates(roots);
+    if cert_file.is_empty() {
+        return Ok(builder.with_no_client_auth());
+    }
+    let certs = common_tls_load_certs(cert_file)?;
+    let key = common_tls_load_key(key_file)?;
+    return builder.with_client_auth_cert(certs, key).map_err(std::io::Error::other);
+}
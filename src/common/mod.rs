@@ -1,2 +1,3 @@
 pub mod common_file;
 pub mod common_net;
+pub mod common_tls;
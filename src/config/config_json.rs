@@ -5,7 +5,8 @@ use serde_json;
 
 const CONFIG_JSON_FILE: &str = "/usr/setup/NetworkDLP/config/NDLP/NdlpConfig.json";
 
-#[derive(Serialize, Deserialize, Clone)]
+// 因为使用了 serde(default)，需要实现 Default
+#[derive(Serialize, Deserialize, Clone, Default)]
 #[serde(default, rename_all = "PascalCase")]
 pub struct ConfigJson {
     pub client_mode: String,
@@ -13,10 +14,7 @@ pub struct ConfigJson {
 
 impl ConfigJson {
     pub fn new() -> Option<Self> {
-        let content = match common_open_file(CONFIG_JSON_FILE) {
-            Some(c) => c,
-            None => return None,
-        };
+        let content = common_open_file(CONFIG_JSON_FILE)?;
 
         let config_json: ConfigJson = serde_json::from_str(&content).expect("Failed to parse JSON");
         return Some(config_json);
@@ -32,12 +30,3 @@ impl ConfigJson {
         return false;
     }
 }
-
-impl Default for ConfigJson {
-    // 因为使用了 serde(default)，需要实现 Default
-    fn default() -> Self {
-        Self {
-            client_mode: String::new(),
-        }
-    }
-}
@@ -1,6 +1,9 @@
 use crate::common::common_file::*;
 use futures::channel::mpsc::Receiver;
 use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 
 const LOCAL_JSON_FILE: &str = "/usr/setup/NetworkDLP/config/NDLP/Local.json";
 
@@ -10,26 +13,473 @@ pub struct LocalConfigMirror {
     pub _interface: String,
 }
 
+/* ICAPS: 通过TLS连接icap server */
+#[derive(Clone, PartialEq)]
+pub struct LocalConfigIcapTls {
+    // 信任的CA证书，为空时使用内置的根证书
+    pub ca_file: String,
+    // 客户端证书与私钥(mTLS)，为空时不出示客户端证书
+    pub cert_file: String,
+    pub key_file: String,
+    // SNI以及证书校验使用的主机名，为空时使用ip
+    pub server_name: String,
+}
+
+#[derive(Clone)]
+pub struct LocalConfigIcapServer {
+    pub ip: String,
+    pub port: u16,
+    pub weight: u32,
+    pub tls: Option<LocalConfigIcapTls>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LocalConfigIcapBalance {
+    RoundRobin,
+    LeastOutstanding,
+    ClientHash,
+}
+
+/* icap server不可用或者超时时的处理策略 */
+#[derive(Clone, Copy, PartialEq)]
+pub enum LocalConfigIcapFailPolicy {
+    // 不扫描直接放行，并记录日志
+    FailOpen,
+    // 返回阻断页面
+    FailClosed,
+}
+
+/* multipart/form-data 请求的扫描方式 */
+#[derive(Clone, Copy, PartialEq)]
+pub enum LocalConfigIcapMultipart {
+    // 整个请求体一起扫描
+    Whole,
+    // 整个请求体一起扫描，附带每个文件的信息头部
+    Headers,
+    // 每个文件单独发送一个REQMOD
+    PerFile,
+}
+
+/* 客户端ip对应的用户与用户组 */
+#[derive(Clone)]
+pub struct LocalConfigIcapUser {
+    pub user: String,
+    pub groups: Vec<String>,
+}
+
+/*
+* 发送给icap server的客户端身份头部
+* 头部名称为空表示不发送该头部
+*/
+#[derive(Clone)]
+pub struct LocalConfigIcapIdentity {
+    pub client_ip_header: String,
+    pub server_ip_header: String,
+    pub user_header: String,
+    pub groups_header: String,
+    // 用户与用户组是否以base64编码发送
+    pub base64: bool,
+    // 客户端ip到用户的映射
+    pub users: Arc<HashMap<IpAddr, LocalConfigIcapUser>>,
+}
+
 #[derive(Clone)]
 pub struct LocalConfigIcapRemote {
-    pub _enable: bool,
-    pub _ip: String,
-    pub _port: u16,
+    pub enable: bool,
+    pub servers: Vec<LocalConfigIcapServer>,
+    pub balance: LocalConfigIcapBalance,
+    pub fail_threshold: u32,
+    pub retry_interval: u64,
+    pub reqmod_service: String,
+    pub respmod_service: String,
+    pub pool_size: usize,
+    pub idle_timeout: u64,
+    pub connect_timeout: u64,
+    pub response_timeout: u64,
+    // icap响应中封装的http消息体解码后的最大字节数
+    pub max_body_size: usize,
+    pub reqmod_fail_policy: LocalConfigIcapFailPolicy,
+    pub respmod_fail_policy: LocalConfigIcapFailPolicy,
+    pub identity: LocalConfigIcapIdentity,
+    pub multipart: LocalConfigIcapMultipart,
+}
+
+/* 消息头超过限制时的处理策略 */
+#[derive(Clone, Copy, PartialEq)]
+pub enum LocalConfigHttpOversize {
+    // 请求返回431，响应返回502，并关闭连接
+    Reject,
+    // 记录告警，不再解析，直接透传
+    Alert,
+}
+
+/* 严格检查消息头分帧信息(请求走私)的处理策略 */
+#[derive(Clone, Copy, PartialEq)]
+pub enum LocalConfigHttpStrict {
+    // 不检查
+    Off,
+    // 请求返回400，响应返回502，并关闭连接
+    Reject,
+    // 重写为没有歧义的消息头，无法重写时拒绝
+    Normalize,
+    // 只记录告警
+    Alert,
+}
+
+/* http解析的限制 */
+#[derive(Clone)]
+pub struct LocalConfigHttp {
+    pub max_headers: usize,
+    pub max_header_size: usize,
+    pub oversize_policy: LocalConfigHttpOversize,
+    pub strict_policy: LocalConfigHttpStrict,
+    // 发送给icap之前解压Content-Encoding编码的消息体
+    pub decode_enable: bool,
+    // 解压后的最大字节数，以及解压后与解压前的最大比例
+    pub decode_max_size: usize,
+    pub decode_max_ratio: usize,
+    // 解析websocket帧，将文本与二进制消息发送给icap扫描
+    pub websocket_inspect: bool,
+    // 扫描的websocket消息的最大字节数，超过时不扫描直接转发
+    pub websocket_max_message: usize,
+    // 不使用preview时等待icap扫描而扣留的消息体的最大字节数，超过时按失败策略处理
+    pub max_held_body: usize,
+}
+
+/* http server证书校验失败时的处理策略 */
+#[derive(Clone, Copy, PartialEq)]
+pub enum LocalConfigTlsPolicy {
+    // 不完成与http client端的握手
+    Block,
+    // 不解密，原样转发，由http client端自己校验http server证书
+    Passthrough,
+    // 签发http client端不会接受的证书，重现http server证书的错误
+    Untrusted,
+}
+
+/* 按http server证书的错误类型分别配置处理策略 */
+#[derive(Clone, PartialEq)]
+pub struct LocalConfigTlsUpstream {
+    pub expired: LocalConfigTlsPolicy,
+    pub self_signed: LocalConfigTlsPolicy,
+    pub hostname_mismatch: LocalConfigTlsPolicy,
+    // 其它错误: 未知CA、吊销、签名错误等
+    pub other: LocalConfigTlsPolicy,
+}
+
+/* 解密https流量(TLS中间人) */
+#[derive(Clone, PartialEq)]
+pub struct LocalConfigTls {
+    pub intercept: bool,
+    // 需要解密的目的端口
+    pub ports: Vec<u16>,
+    // 签发证书的企业CA证书(可以包含中间CA证书链)与私钥
+    pub ca_cert: String,
+    pub ca_key: String,
+    // 校验http server证书使用的CA，为空时使用内置的根证书
+    pub upstream_ca: String,
+    pub upstream_policy: LocalConfigTlsUpstream,
+    // 两端都支持时通过ALPN协商http/2，否则使用http/1.1
+    pub http2: bool,
+    // 内存中缓存的签发证书数量
+    pub cache_size: usize,
+    // 签发证书的保存目录，为空时不保存
+    pub cache_dir: String,
+    // 签发证书的密钥轮换周期(秒)，超过后重新生成密钥并签发
+    pub key_rotation: u64,
+    // 不解密的主机名(完全匹配)、通配符(*.example.com)与目的ip(可以是网段)，命中时原样转发
+    pub bypass_domains: Vec<String>,
+    pub bypass_wildcards: Vec<String>,
+    pub bypass_ips: Vec<(IpAddr, u8)>,
 }
 
 #[derive(Clone)]
 pub struct LocalJson {
     pub _mirror: LocalConfigMirror,
-    pub _icap_remote: LocalConfigIcapRemote,
+    pub icap_remote: LocalConfigIcapRemote,
+    pub http: LocalConfigHttp,
+    pub tls: LocalConfigTls,
     pub thread_num: u16,
 }
 
+impl LocalConfigIcapServer {
+    /* icap server地址，同时用于icap请求的Request-URI与Host头; IPv6地址加上方括号 */
+    pub fn server(&self) -> String {
+        match self.ip.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, self.port).to_string(),
+            Err(_) => format!("{}:{}", self.ip, self.port),
+        }
+    }
+}
+
+impl LocalConfigIcapRemote {
+    /*
+    * 解析icap server列表
+    * 兼容旧的单个 ip/port 配置
+    */
+    fn parse_servers(json: &Value) -> Vec<LocalConfigIcapServer> {
+        let mut servers = Vec::new();
+        if let Some(list) = json["servers"].as_array() {
+            for server in list {
+                let ip = server["ip"].as_str().unwrap_or("").to_string();
+                if ip.is_empty() {
+                    continue;
+                }
+                servers.push(LocalConfigIcapServer {
+                    ip,
+                    port: server["port"].as_u64().unwrap_or(1344) as u16,
+                    weight: server["weight"].as_u64().map_or(1, |w| u32::try_from(w).unwrap_or(u32::MAX)),
+                    tls: Self::parse_tls(&server["tls"]),
+                });
+            }
+        }
+        let ip = json["ip"].as_str().unwrap_or("");
+        if servers.is_empty() && !ip.is_empty() {
+            servers.push(LocalConfigIcapServer {
+                ip: ip.to_string(),
+                port: json["port"].as_u64().unwrap_or(1344) as u16,
+                weight: 1,
+                tls: Self::parse_tls(&json["tls"]),
+            });
+        }
+        return servers;
+    }
+
+    /* 解析icap server的TLS配置，未启用时返回None */
+    fn parse_tls(json: &Value) -> Option<LocalConfigIcapTls> {
+        if !json["enable"].as_bool().unwrap_or(false) {
+            return None;
+        }
+        let field = |key: &str| json[key].as_str().unwrap_or("").to_string();
+        return Some(LocalConfigIcapTls {
+            ca_file: field("caFile"),
+            cert_file: field("certFile"),
+            key_file: field("keyFile"),
+            server_name: field("serverName"),
+        });
+    }
+
+    pub fn find_server(&self, server: &str) -> Option<&LocalConfigIcapServer> {
+        return self.servers.iter().find(|s| s.server() == server);
+    }
+
+    /*
+    * 解析客户端身份头部配置
+    * userMap 可以是映射文件的路径，也可以直接配置映射: { "ip": { "user": "...", "groups": [...] } }
+    */
+    fn parse_identity(json: &Value) -> LocalConfigIcapIdentity {
+        let headers = &json["identityHeaders"];
+        let name = |key: &str, default: &str| headers[key].as_str().unwrap_or(default).to_string();
+        let users = match &json["userMap"] {
+            Value::String(file) => match common_open_file(file).map(|c| serde_json::from_str::<Value>(&c)) {
+                Some(Ok(map)) => Self::parse_users(&map),
+                _ => {
+                    println!("failed to load user map {}", file);
+                    HashMap::new()
+                }
+            },
+            map => Self::parse_users(map),
+        };
+        return LocalConfigIcapIdentity {
+            client_ip_header: name("clientIp", "X-Client-IP"),
+            server_ip_header: name("serverIp", "X-Server-IP"),
+            user_header: name("user", "X-Authenticated-User"),
+            groups_header: name("groups", "X-Authenticated-Groups"),
+            base64: headers["base64"].as_bool().unwrap_or(true),
+            users: Arc::new(users),
+        };
+    }
+
+    fn parse_users(json: &Value) -> HashMap<IpAddr, LocalConfigIcapUser> {
+        let mut users = HashMap::new();
+        let map = match json.as_object() {
+            Some(map) => map,
+            None => return users,
+        };
+        for (ip, entry) in map {
+            let ip: IpAddr = match ip.parse() {
+                Ok(ip) => ip,
+                Err(_) => continue,
+            };
+            let user = entry["user"].as_str().unwrap_or("").to_string();
+            let groups = match entry["groups"].as_array() {
+                Some(groups) => groups.iter().filter_map(|g| g.as_str()).map(|g| g.to_string()).collect(),
+                None => Vec::new(),
+            };
+            users.insert(ip, LocalConfigIcapUser { user, groups });
+        }
+        return users;
+    }
+
+    /*
+    * 解析某个方向的失败策略: "open" / "closed"
+    * 未配置时使用公共的failPolicy, 默认fail-open
+    */
+    fn parse_fail_policy(json: &Value, key: &str) -> LocalConfigIcapFailPolicy {
+        let policy = json[key].as_str().or(json["failPolicy"].as_str()).unwrap_or("open");
+        match policy {
+            "closed" => return LocalConfigIcapFailPolicy::FailClosed,
+            _ => return LocalConfigIcapFailPolicy::FailOpen,
+        }
+    }
+}
+
+impl LocalConfigHttp {
+    pub fn new() -> Self {
+        Self {
+            max_headers: 256,
+            max_header_size: 65536,
+            oversize_policy: LocalConfigHttpOversize::Reject,
+            strict_policy: LocalConfigHttpStrict::Off,
+            decode_enable: false,
+            decode_max_size: 32 * 1024 * 1024,
+            decode_max_ratio: 100,
+            websocket_inspect: false,
+            websocket_max_message: 1024 * 1024,
+            max_held_body: 32 * 1024 * 1024,
+        }
+    }
+
+    /*
+    * 解析http配置
+    * 解压配置: "decode": { "enable": true, "maxSize": 33554432, "maxRatio": 100 }
+    * websocket配置: "websocket": { "inspect": true, "maxMessageSize": 1048576 }
+    * 扣留消息体上限: "maxHeldBody": 33554432
+    */
+    fn parse(json: &Value) -> Self {
+        let default = Self::new();
+        let decode = &json["decode"];
+        let websocket = &json["websocket"];
+        Self {
+            max_headers: std::cmp::max(json["maxHeaders"].as_u64().unwrap_or(default.max_headers as u64) as usize, 1),
+            max_header_size: json["maxHeaderSize"].as_u64().unwrap_or(default.max_header_size as u64) as usize,
+            oversize_policy: match json["oversizePolicy"].as_str().unwrap_or("reject") {
+                "alert" => LocalConfigHttpOversize::Alert,
+                _ => LocalConfigHttpOversize::Reject,
+            },
+            strict_policy: match json["strictFraming"].as_str().unwrap_or("off") {
+                "reject" => LocalConfigHttpStrict::Reject,
+                "normalize" => LocalConfigHttpStrict::Normalize,
+                "alert" => LocalConfigHttpStrict::Alert,
+                _ => LocalConfigHttpStrict::Off,
+            },
+            decode_enable: decode["enable"].as_bool().unwrap_or(default.decode_enable),
+            decode_max_size: decode["maxSize"].as_u64().unwrap_or(default.decode_max_size as u64) as usize,
+            decode_max_ratio: std::cmp::max(decode["maxRatio"].as_u64().unwrap_or(default.decode_max_ratio as u64) as usize, 1),
+            websocket_inspect: websocket["inspect"].as_bool().unwrap_or(default.websocket_inspect),
+            websocket_max_message: websocket["maxMessageSize"]
+                .as_u64()
+                .unwrap_or(default.websocket_max_message as u64) as usize,
+            max_held_body: json["maxHeldBody"].as_u64().unwrap_or(default.max_held_body as u64) as usize,
+        }
+    }
+}
+
+impl LocalConfigTlsUpstream {
+    /*
+    * 解析http server证书校验失败的处理策略: block / passthrough / untrusted
+    * 所有错误使用同一个策略: "upstreamPolicy": "block"
+    * 按错误类型: "upstreamPolicy": { "default": "block", "expired": "untrusted", "selfSigned": "passthrough", "hostnameMismatch": "untrusted" }
+    */
+    fn parse(json: &Value) -> Self {
+        let policy = |value: &Value, default: LocalConfigTlsPolicy| match value.as_str() {
+            Some("block") => LocalConfigTlsPolicy::Block,
+            Some("passthrough") => LocalConfigTlsPolicy::Passthrough,
+            Some("untrusted") => LocalConfigTlsPolicy::Untrusted,
+            _ => default,
+        };
+        if json.is_string() {
+            let all = policy(json, LocalConfigTlsPolicy::Block);
+            return Self {
+                expired: all,
+                self_signed: all,
+                hostname_mismatch: all,
+                other: all,
+            };
+        }
+        let default = policy(&json["default"], LocalConfigTlsPolicy::Block);
+        return Self {
+            expired: policy(&json["expired"], default),
+            self_signed: policy(&json["selfSigned"], default),
+            hostname_mismatch: policy(&json["hostnameMismatch"], default),
+            other: policy(&json["other"], default),
+        };
+    }
+}
+
+impl LocalConfigTls {
+    /*
+    * 解析TLS解密配置
+    * "tls": { "intercept": true, "ports": [443], "caCert": "ca.pem", "caKey": "ca.key", "upstreamCa": "",
+    *          "cache": { "size": 10000, "dir": "", "keyRotation": 604800 },
+    *          "bypass": { "domains": ["bank.com"], "wildcards": ["*.bank.com"], "ips": ["10.0.0.0/8"] },
+    *          "upstreamPolicy": "block", "http2": true }
+    */
+    fn parse(json: &Value) -> Self {
+        let field = |key: &str| json[key].as_str().unwrap_or("").to_string();
+        let ports = match json["ports"].as_array() {
+            Some(list) => list.iter().filter_map(|p| p.as_u64()).map(|p| p as u16).collect(),
+            None => vec![443],
+        };
+        Self {
+            intercept: json["intercept"].as_bool().unwrap_or(false),
+            ports,
+            ca_cert: field("caCert"),
+            ca_key: field("caKey"),
+            upstream_ca: field("upstreamCa"),
+            upstream_policy: LocalConfigTlsUpstream::parse(&json["upstreamPolicy"]),
+            http2: json["http2"].as_bool().unwrap_or(true),
+            cache_size: json["cache"]["size"].as_u64().unwrap_or(10000) as usize,
+            cache_dir: json["cache"]["dir"].as_str().unwrap_or("").to_string(),
+            key_rotation: json["cache"]["keyRotation"].as_u64().unwrap_or(7 * 24 * 3600),
+            bypass_domains: Self::parse_names(&json["bypass"]["domains"]),
+            bypass_wildcards: Self::parse_names(&json["bypass"]["wildcards"]),
+            bypass_ips: Self::parse_ips(&json["bypass"]["ips"]),
+        }
+    }
+
+    /* 主机名列表，统一为小写并去掉结尾的点 */
+    fn parse_names(json: &Value) -> Vec<String> {
+        let Some(list) = json.as_array() else {
+            return Vec::new();
+        };
+        return list
+            .iter()
+            .filter_map(|name| name.as_str())
+            .map(|name| name.trim().trim_end_matches('.').to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+    }
+
+    /* ip或者网段列表(1.2.3.4 / 10.0.0.0/8 / fd00::/8)，格式错误的项被忽略 */
+    fn parse_ips(json: &Value) -> Vec<(IpAddr, u8)> {
+        let mut ips = Vec::new();
+        for item in json.as_array().into_iter().flatten().filter_map(|ip| ip.as_str()) {
+            let (ip, prefix) = match item.split_once('/') {
+                Some((ip, prefix)) => (ip.trim(), prefix.trim().parse::<u8>().ok()),
+                None => (item.trim(), None),
+            };
+            let ip: IpAddr = match ip.parse() {
+                Ok(ip) => ip,
+                Err(_) => {
+                    println!("Invalid TLS bypass ip: {}", item);
+                    continue;
+                }
+            };
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            match prefix {
+                Some(prefix) if prefix <= max => ips.push((ip, prefix)),
+                None if !item.contains('/') => ips.push((ip, max)),
+                _ => println!("Invalid TLS bypass ip: {}", item),
+            }
+        }
+        return ips;
+    }
+}
+
 impl LocalJson {
     pub fn new() -> Option<Self> {
-        let content = match common_open_file(LOCAL_JSON_FILE) {
-            Some(c) => c,
-            None => return None,
-        };
+        let content = common_open_file(LOCAL_JSON_FILE)?;
 
         // 尝试将字符串解析为 JSON
         let json: Value = match serde_json::from_str(&content) {
@@ -47,16 +497,65 @@ impl LocalJson {
         };
 
         let icap_remote = LocalConfigIcapRemote {
-            _enable: json["icap-remote"]["enable"].as_bool().unwrap_or(false),
-            _ip: json["icap-remote"]["ip"].as_str().unwrap_or("").to_string(),
-            _port: json["icap-remote"]["port"].as_u64().unwrap_or(1344) as u16,
+            enable: json["icap-remote"]["enable"].as_bool().unwrap_or(false),
+            servers: LocalConfigIcapRemote::parse_servers(&json["icap-remote"]),
+            balance: match json["icap-remote"]["balance"].as_str().unwrap_or("round-robin") {
+                "least-outstanding" => LocalConfigIcapBalance::LeastOutstanding,
+                "hash" => LocalConfigIcapBalance::ClientHash,
+                _ => LocalConfigIcapBalance::RoundRobin,
+            },
+            fail_threshold: json["icap-remote"]["failThreshold"].as_u64().unwrap_or(3) as u32,
+            retry_interval: json["icap-remote"]["retryInterval"].as_u64().unwrap_or(10),
+            reqmod_service: json["icap-remote"]["reqmodService"]
+                .as_str()
+                .unwrap_or("reqmod")
+                .trim_start_matches('/')
+                .to_string(),
+            respmod_service: json["icap-remote"]["respmodService"]
+                .as_str()
+                .unwrap_or("respmod")
+                .trim_start_matches('/')
+                .to_string(),
+            pool_size: json["icap-remote"]["poolSize"].as_u64().unwrap_or(16) as usize,
+            idle_timeout: json["icap-remote"]["idleTimeout"].as_u64().unwrap_or(60),
+            connect_timeout: json["icap-remote"]["connectTimeout"].as_u64().unwrap_or(5),
+            response_timeout: json["icap-remote"]["responseTimeout"].as_u64().unwrap_or(30),
+            max_body_size: json["icap-remote"]["maxBodySize"].as_u64().unwrap_or(64 * 1024 * 1024) as usize,
+            reqmod_fail_policy: LocalConfigIcapRemote::parse_fail_policy(&json["icap-remote"], "reqmodFailPolicy"),
+            respmod_fail_policy: LocalConfigIcapRemote::parse_fail_policy(&json["icap-remote"], "respmodFailPolicy"),
+            identity: LocalConfigIcapRemote::parse_identity(&json["icap-remote"]),
+            multipart: match json["icap-remote"]["multipart"].as_str().unwrap_or("whole") {
+                "headers" => LocalConfigIcapMultipart::Headers,
+                "per-file" => LocalConfigIcapMultipart::PerFile,
+                _ => LocalConfigIcapMultipart::Whole,
+            },
         };
 
+        // 超时为0时每个连接与事务都会立即失败，拒绝该配置
+        for (name, timeout) in [("connectTimeout", icap_remote.connect_timeout), ("responseTimeout", icap_remote.response_timeout)] {
+            if timeout == 0 {
+                println!("Invalid icap-remote {}: 0", name);
+                return None;
+            }
+        }
+        // 权重决定轮询次数与一致性hash的虚拟节点数，超出范围时拒绝该配置
+        for server in icap_remote.servers.iter() {
+            if !(1..=1000).contains(&server.weight) {
+                println!("Invalid icap-remote weight {} for {}: must be 1..=1000", server.weight, server.server());
+                return None;
+            }
+        }
+
+        let http = LocalConfigHttp::parse(&json["http"]);
+        let tls = LocalConfigTls::parse(&json["tls"]);
+
         let thread_num = json["icap"]["threadCnt"].as_u64().unwrap_or(1) as u16;
 
         Some(Self {
             _mirror: mirror,
-            _icap_remote: icap_remote,
+            icap_remote,
+            http,
+            tls,
             thread_num,
         })
     }
@@ -1,11 +1,11 @@
+// 代码统一使用显式return
+#![allow(clippy::needless_return)]
 mod common;
 mod config;
 mod netio;
 mod protocol;
 mod proxy;
 use crate::netio::control::*;
-use env_logger;
-use tokio;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
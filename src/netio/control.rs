@@ -6,6 +6,8 @@ use tokio::sync::broadcast;
 use crate::config::config_json::ConfigJson;
 use crate::config::local_json::LocalJson;
 use crate::netio::work::Work;
+use crate::proxy::tls::TlsMitm;
+use std::sync::Arc;
 
 pub struct Control {
     pub local_json: LocalJson,
@@ -15,22 +17,18 @@ pub struct Control {
     pub config_watch_rx: mpsc::Receiver<()>,
     pub config_tx: broadcast::Sender<ConfigJson>,
     pub runtimes: Vec<Runtime>,
+    // 所有runtime共享，签发的证书在runtime之间复用
+    pub tls: Arc<TlsMitm>,
 }
 
 impl Control {
     pub fn new() -> Option<Self> {
         // 获取local_json
-        let local_json = match LocalJson::new() {
-            Some(json) => json,
-            None => return None,
-        };
+        let local_json = LocalJson::new()?;
         let local_watch_rx = LocalJson::watch();
 
         // 获取config_json
-        let config_json = match ConfigJson::new() {
-            Some(json) => json,
-            None => return None,
-        };
+        let config_json = ConfigJson::new()?;
         let config_watch_rx = ConfigJson::watch();
 
         // 创建 channel
@@ -41,13 +39,14 @@ impl Control {
         let runtimes = Self::create_runtimes(local_json.thread_num, 1);
 
         Some(Self {
-            local_json: local_json,
-            local_watch_rx: local_watch_rx,
-            local_tx: local_tx,
-            config_json: config_json,
-            config_watch_rx: config_watch_rx,
-            config_tx: config_tx,
-            runtimes: runtimes,
+            local_json,
+            local_watch_rx,
+            local_tx,
+            config_json,
+            config_watch_rx,
+            config_tx,
+            runtimes,
+            tls: Arc::new(TlsMitm::new()),
         })
     }
 
@@ -90,9 +89,10 @@ impl Control {
             .map(|(i, runtime)| {
                 let local_rx = self.local_tx.subscribe();
                 let config_rx = self.config_tx.subscribe();
+                let tls = self.tls.clone();
                 runtime.spawn(async move {
                     println!("运行时 {} 开始工作", i);
-                    let _ = Work::start_service(i, local_rx, config_rx).await;
+                    let _ = Work::start_service(i, local_rx, config_rx, tls).await;
                 })
             })
             .collect();
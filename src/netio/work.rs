@@ -2,8 +2,12 @@
 use tokio;
 //use tokio::net::{TcpListener, TcpStream};
 use crate::config::config_json::ConfigJson;
-use crate::config::local_json::LocalJson;
+use crate::config::local_json::{LocalConfigHttp, LocalConfigIcapRemote, LocalConfigTls, LocalJson};
 use crate::proxy::http::Http;
+use crate::proxy::icap::IcapClient;
+use crate::proxy::tls::TlsMitm;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::sync::broadcast::Receiver;
 
@@ -22,6 +26,11 @@ pub struct Work {
     pub thread_config_json: Option<ConfigJson>,
 
     pub thread_http_server: Option<TcpListener>,
+
+    // 本runtime内所有连接共享的icap service能力缓存、连接池与负载均衡状态
+    pub thread_icap: Arc<IcapClient>,
+    // 所有runtime共享的TLS解密CA与签发证书缓存
+    pub thread_tls: Arc<TlsMitm>,
 }
 
 impl Work {
@@ -29,8 +38,10 @@ impl Work {
         id: usize,
         mut _local_rx: Receiver<LocalJson>,
         mut config_rx: Receiver<ConfigJson>,
+        tls: Arc<TlsMitm>,
     ) -> Result<(), std::io::Error> {
-        let mut work = Work::new(id);
+        let mut work = Work::new(id, tls);
+        let mut evict_interval = tokio::time::interval(Duration::from_secs(10));
         loop {
             tokio::select! {
                 msg = _local_rx.recv() => {
@@ -45,13 +56,29 @@ impl Work {
                 }
                 _http_socket = Http::accept_service(&work.thread_http_server) => {
                     if let Ok(_socket) = _http_socket {
+                        // 新连接使用当前的icap配置，配置变化不影响已有连接
+                        let icap_remote = work.icap_remote();
+                        let http_config = work.http_config();
+                        let tls_config = work.tls_config();
+                        let tls = work.thread_tls.clone();
+                        let icap = work.thread_icap.clone();
                         tokio::spawn(async move {
-                            if let Err(e) = Http::process_service(_socket).await {
+                            if let Err(e) = Http::process_service(_socket, icap_remote, http_config, tls_config, tls, icap).await {
                                 println!("failed to process connection; error = {e}");
                             }
                         });
                     }
                 }
+                _ = evict_interval.tick() => {
+                    if let Some(icap_remote) = work.icap_remote() {
+                        work.thread_icap.pool.evict_idle(Duration::from_secs(icap_remote.idle_timeout));
+                        // 探测已标记为down的icap server
+                        let icap = work.thread_icap.clone();
+                        tokio::spawn(async move {
+                            icap.balancer.probe(&icap_remote, &icap.pool).await;
+                        });
+                    }
+                }
                 _ = tokio::signal::ctrl_c() => {
                     println!("接收到中断信号，正在停止所有任务");
                     break;
@@ -65,6 +92,32 @@ impl Work {
         self.thread_local_json = Some(local_json);
     }
 
+    /* 已启用的icap server配置 */
+    fn icap_remote(&self) -> Option<LocalConfigIcapRemote> {
+        let icap_remote = &self.thread_local_json.as_ref()?.icap_remote;
+        if !icap_remote.enable || icap_remote.servers.is_empty() {
+            return None;
+        }
+        return Some(icap_remote.clone());
+    }
+
+    /* http解析的限制，没有配置时使用默认值 */
+    fn http_config(&self) -> LocalConfigHttp {
+        match &self.thread_local_json {
+            Some(local_json) => return local_json.http.clone(),
+            None => return LocalConfigHttp::new(),
+        }
+    }
+
+    /* 已启用的TLS解密配置 */
+    fn tls_config(&self) -> Option<LocalConfigTls> {
+        let tls = &self.thread_local_json.as_ref()?.tls;
+        if !tls.intercept || tls.ports.is_empty() {
+            return None;
+        }
+        return Some(tls.clone());
+    }
+
     async fn update_config(&mut self, new_config_json: ConfigJson) {
         if !new_config_json.is_listen_mode() {
             self.thread_config_json = Some(new_config_json);
@@ -92,12 +145,14 @@ impl Work {
         return;
     }
 
-    pub fn new(id: usize) -> Self {
+    pub fn new(id: usize, tls: Arc<TlsMitm>) -> Self {
         return Work {
             _thread_id: id,
             thread_local_json: None,
             thread_config_json: None,
             thread_http_server: None,
+            thread_icap: Arc::new(IcapClient::new()),
+            thread_tls: tls,
         };
     }
 }
@@ -0,0 +1,535 @@
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+// 动态表中每个条目额外计算的字节数
+const HPACK_ENTRY_OVERHEAD: usize = 32;
+// 单个字符串解码后的最大长度
+const HPACK_STRING_MAX: usize = 256 * 1024;
+
+/* 静态表，索引从1开始 */
+const HPACK_STATIC: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/* Huffman编码表: (编码, 位数)，按符号排列，最后一项为EOS */
+const HPACK_HUFFMAN: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28), (0xfffffe5, 28),
+    (0xfffffe6, 28), (0xfffffe7, 28), (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28), (0xfffffed, 28), (0xfffffee, 28),
+    (0xfffffef, 28), (0xffffff0, 28), (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28),
+    (0xffffffa, 28), (0xffffffb, 28), (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10),
+    (0xf9, 8), (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6), (0x1a, 6), (0x1b, 6),
+    (0x1c, 6), (0x1d, 6), (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10), (0x1ffa, 13), (0x21, 6),
+    (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7),
+    (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7),
+    (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5),
+    (0x25, 6), (0x26, 6), (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5), (0x2b, 6), (0x76, 7),
+    (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14),
+    (0x1ffd, 13), (0xffffffc, 28), (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23), (0x3fffd6, 22), (0x7fffda, 23),
+    (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23),
+    (0x7fffe2, 23), (0x7fffe3, 23), (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24), (0x3fffda, 22), (0x1fffdd, 21),
+    (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22),
+    (0x7fffeb, 23), (0x7fffec, 23), (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23), (0xfffea, 20), (0x3fffe2, 22),
+    (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23),
+    (0x3fffe8, 22), (0x1ffffec, 25), (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25), (0x7fff2, 19), (0x1fffe3, 21),
+    (0x3ffffe6, 26), (0x7ffffe0, 27), (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28), (0x7ffffe3, 27),
+    (0x7ffffe4, 27), (0x7ffffe5, 27), (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23), (0x3fffea, 22), (0x3fffeb, 22),
+    (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27),
+    (0x7ffffe9, 27), (0x7ffffea, 27), (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26), (0x3fffffff, 30),
+];
+
+/* 头部字段: 名称与值 */
+pub type ProtoHpackField = (Vec<u8>, Vec<u8>);
+
+/*
+* HPACK解码，一个方向的连接共用一个解码器(动态表)
+* 解码失败时连接无法继续使用
+*/
+pub struct ProtoHpackDecoder {
+    dynamic: VecDeque<ProtoHpackField>,
+    size: usize,
+    // 对端通过表大小更新设置的动态表大小，不能超过本端SETTINGS_HEADER_TABLE_SIZE
+    max_size: usize,
+    limit: usize,
+    // 一个头部块解码后的字段数，以及按名称+值+32计算的总大小的上限
+    max_fields: usize,
+    max_list: usize,
+}
+
+/* HPACK编码，不使用动态表与Huffman编码，因此不需要保存状态 */
+pub struct ProtoHpackEncoder {}
+
+impl ProtoHpackDecoder {
+    pub fn new(limit: usize, max_fields: usize, max_list: usize) -> Self {
+        Self {
+            dynamic: VecDeque::new(),
+            size: 0,
+            max_size: limit,
+            limit,
+            max_fields,
+            max_list,
+        }
+    }
+
+    /*
+    * 解码一个完整的头部块
+    * 索引的字段可以很小的头部块展开为很大的头部列表，字段数与总大小超过限制时失败
+    */
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<ProtoHpackField>, String> {
+        let mut fields = Vec::new();
+        let mut list = 0;
+        let mut pos = 0;
+        while pos < block.len() {
+            let first = block[pos];
+            let field = if first & 0x80 != 0 {
+                // 索引的头部字段
+                let index = Self::integer(block, &mut pos, 7)?;
+                self.entry(index)?
+            } else if first & 0x40 != 0 {
+                // 字面值，加入动态表
+                let field = self.literal(block, &mut pos, 6)?;
+                self.insert(field.clone());
+                field
+            } else if first & 0x20 != 0 {
+                // 动态表大小更新，只能出现在头部块的开头
+                if !fields.is_empty() {
+                    return Err("HPACK table size update after header field".to_string());
+                }
+                let size = Self::integer(block, &mut pos, 5)?;
+                if size > self.limit {
+                    return Err(format!("HPACK table size {} exceeds {}", size, self.limit));
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // 不加入动态表的字面值(不索引、永不索引)
+                self.literal(block, &mut pos, 4)?
+            };
+            list += field.0.len() + field.1.len() + HPACK_ENTRY_OVERHEAD;
+            if fields.len() >= self.max_fields || list > self.max_list {
+                return Err(format!("HPACK header list exceeds {} fields or {} bytes", self.max_fields, self.max_list));
+            }
+            fields.push(field);
+        }
+        return Ok(fields);
+    }
+
+    fn literal(&self, data: &[u8], pos: &mut usize, prefix: u8) -> Result<ProtoHpackField, String> {
+        let index = Self::integer(data, pos, prefix)?;
+        let name = match index {
+            0 => Self::string(data, pos)?,
+            _ => self.entry(index)?.0,
+        };
+        let value = Self::string(data, pos)?;
+        return Ok((name, value));
+    }
+
+    fn entry(&self, index: usize) -> Result<ProtoHpackField, String> {
+        if index == 0 {
+            return Err("HPACK index 0".to_string());
+        }
+        if index <= HPACK_STATIC.len() {
+            let (name, value) = HPACK_STATIC[index - 1];
+            return Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        }
+        match self.dynamic.get(index - HPACK_STATIC.len() - 1) {
+            Some(field) => return Ok(field.clone()),
+            None => return Err(format!("HPACK index {} out of range", index)),
+        }
+    }
+
+    fn insert(&mut self, field: ProtoHpackField) {
+        let size = field.0.len() + field.1.len() + HPACK_ENTRY_OVERHEAD;
+        self.evict(size);
+        // 比整个动态表大的条目使动态表被清空
+        if size <= self.max_size {
+            self.size += size;
+            self.dynamic.push_front(field);
+        }
+    }
+
+    /* 淘汰最旧的条目，直到可以再放入extra字节 */
+    fn evict(&mut self, extra: usize) {
+        while self.size + extra > self.max_size {
+            let Some((name, value)) = self.dynamic.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + HPACK_ENTRY_OVERHEAD;
+        }
+    }
+
+    /* 带前缀的整数 */
+    fn integer(data: &[u8], pos: &mut usize, prefix: u8) -> Result<usize, String> {
+        let mask = (1u16 << prefix) as usize - 1;
+        let mut value = *data.get(*pos).ok_or("truncated HPACK integer")? as usize & mask;
+        *pos += 1;
+        if value < mask {
+            return Ok(value);
+        }
+        let mut shift = 0;
+        loop {
+            let byte = *data.get(*pos).ok_or("truncated HPACK integer")?;
+            *pos += 1;
+            if shift > 28 {
+                return Err("HPACK integer overflow".to_string());
+            }
+            value += ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn string(data: &[u8], pos: &mut usize) -> Result<Vec<u8>, String> {
+        let huffman = *data.get(*pos).ok_or("truncated HPACK string")? & 0x80 != 0;
+        let len = Self::integer(data, pos, 7)?;
+        if len > HPACK_STRING_MAX || data.len() - *pos < len {
+            return Err("invalid HPACK string length".to_string());
+        }
+        let raw = &data[*pos..*pos + len];
+        *pos += len;
+        if huffman {
+            return Self::huffman_decode(raw);
+        }
+        return Ok(raw.to_vec());
+    }
+
+    /*
+    * Huffman解码树: 每个节点两个子节点，正数为子节点索引，负数为-(符号+1)，0表示没有
+    * 第一次使用时由编码表生成
+    */
+    fn huffman_tree() -> &'static Vec<[i32; 2]> {
+        static TREE: OnceLock<Vec<[i32; 2]>> = OnceLock::new();
+        return TREE.get_or_init(|| {
+            let mut tree = vec![[0i32; 2]];
+            for (symbol, (code, bits)) in HPACK_HUFFMAN.iter().enumerate() {
+                let mut node = 0;
+                for i in (0..*bits).rev() {
+                    let bit = ((code >> i) & 1) as usize;
+                    if i == 0 {
+                        tree[node][bit] = -(symbol as i32 + 1);
+                    } else {
+                        if tree[node][bit] == 0 {
+                            tree.push([0, 0]);
+                            tree[node][bit] = (tree.len() - 1) as i32;
+                        }
+                        node = tree[node][bit] as usize;
+                    }
+                }
+            }
+            tree
+        });
+    }
+
+    fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, String> {
+        let tree = Self::huffman_tree();
+        let mut out = Vec::with_capacity(data.len() * 8 / 5);
+        let mut node = 0;
+        // 上一个符号之后的位数，以及这些位是否全部为1(EOS的前缀，用于填充)
+        let mut pending = 0;
+        let mut ones = true;
+        for byte in data {
+            for i in (0..8).rev() {
+                let bit = ((byte >> i) & 1) as usize;
+                pending += 1;
+                ones &= bit == 1;
+                match tree[node][bit] {
+                    0 => return Err("invalid HPACK Huffman code".to_string()),
+                    next if next > 0 => node = next as usize,
+                    leaf => {
+                        let symbol = (-leaf - 1) as usize;
+                        if symbol == 256 {
+                            return Err("HPACK Huffman EOS in string".to_string());
+                        }
+                        out.push(symbol as u8);
+                        node = 0;
+                        pending = 0;
+                        ones = true;
+                    }
+                }
+            }
+        }
+        if pending > 7 || !ones {
+            return Err("invalid HPACK Huffman padding".to_string());
+        }
+        return Ok(out);
+    }
+}
+
+impl ProtoHpackEncoder {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    /*
+    * 编码头部块
+    * 名称在静态表中时使用索引，值使用不索引的字面值
+    */
+    pub fn encode(&self, fields: &[ProtoHpackField]) -> Vec<u8> {
+        let mut block = Vec::new();
+        for (name, value) in fields {
+            match HPACK_STATIC.iter().position(|(n, _)| n.as_bytes() == name.as_slice()) {
+                Some(index) => Self::integer(&mut block, 0x00, 4, index + 1),
+                None => {
+                    block.push(0x00);
+                    Self::integer(&mut block, 0x00, 7, name.len());
+                    block.extend_from_slice(name);
+                }
+            }
+            Self::integer(&mut block, 0x00, 7, value.len());
+            block.extend_from_slice(value);
+        }
+        return block;
+    }
+
+    fn integer(block: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+        let mask = (1u16 << prefix) as usize - 1;
+        if value < mask {
+            block.push(flags | value as u8);
+            return;
+        }
+        block.push(flags | mask as u8);
+        value -= mask;
+        while value >= 0x80 {
+            block.push(0x80 | (value & 0x7f) as u8);
+            value >>= 7;
+        }
+        block.push(value as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        return digits.chunks(2).map(|d| u8::from_str_radix(std::str::from_utf8(d).unwrap(), 16).unwrap()).collect();
+    }
+
+    /* 解码一个头部块，检查解码结果、动态表大小与动态表中从新到旧的条目 */
+    fn check(decoder: &mut ProtoHpackDecoder, block: &str, expect: &[(&str, &str)], size: usize, table: &[(&str, &str)]) {
+        let fields = decoder.decode(&hex(block)).unwrap();
+        let pairs = |list: &[(&str, &str)]| list.iter().map(|(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec())).collect::<Vec<_>>();
+        assert_eq!(fields, pairs(expect));
+        assert_eq!(decoder.size, size);
+        assert_eq!(decoder.dynamic.iter().cloned().collect::<Vec<_>>(), pairs(table));
+    }
+
+    const C3_REQ1: [(&str, &str); 4] = [(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")];
+    const C3_REQ2: [(&str, &str); 5] =
+        [(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com"), ("cache-control", "no-cache")];
+    const C3_REQ3: [(&str, &str); 5] =
+        [(":method", "GET"), (":scheme", "https"), (":path", "/index.html"), (":authority", "www.example.com"), ("custom-key", "custom-value")];
+
+    const C5_DATE1: &str = "Mon, 21 Oct 2013 20:13:21 GMT";
+    const C5_DATE2: &str = "Mon, 21 Oct 2013 20:13:22 GMT";
+    const C5_LOCATION: &str = "https://www.example.com";
+    const C5_COOKIE: &str = "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1";
+
+    /* RFC 7541 C.3 与 C.4 的请求，动态表不发生淘汰 */
+    fn check_requests(blocks: [&str; 3]) {
+        let mut decoder = ProtoHpackDecoder::new(4096, 100, 65536);
+        check(&mut decoder, blocks[0], &C3_REQ1, 57, &[(":authority", "www.example.com")]);
+        check(&mut decoder, blocks[1], &C3_REQ2, 110, &[("cache-control", "no-cache"), (":authority", "www.example.com")]);
+        check(
+            &mut decoder,
+            blocks[2],
+            &C3_REQ3,
+            164,
+            &[("custom-key", "custom-value"), ("cache-control", "no-cache"), (":authority", "www.example.com")],
+        );
+    }
+
+    /* RFC 7541 C.5 与 C.6 的响应，动态表大小为256，后面的响应淘汰前面的条目 */
+    fn check_responses(blocks: [&str; 3]) {
+        let mut decoder = ProtoHpackDecoder::new(256, 100, 65536);
+        check(
+            &mut decoder,
+            blocks[0],
+            &[(":status", "302"), ("cache-control", "private"), ("date", C5_DATE1), ("location", C5_LOCATION)],
+            222,
+            &[("location", C5_LOCATION), ("date", C5_DATE1), ("cache-control", "private"), (":status", "302")],
+        );
+        check(
+            &mut decoder,
+            blocks[1],
+            &[(":status", "307"), ("cache-control", "private"), ("date", C5_DATE1), ("location", C5_LOCATION)],
+            222,
+            &[(":status", "307"), ("location", C5_LOCATION), ("date", C5_DATE1), ("cache-control", "private")],
+        );
+        check(
+            &mut decoder,
+            blocks[2],
+            &[
+                (":status", "200"),
+                ("cache-control", "private"),
+                ("date", C5_DATE2),
+                ("location", C5_LOCATION),
+                ("content-encoding", "gzip"),
+                ("set-cookie", C5_COOKIE),
+            ],
+            215,
+            &[("set-cookie", C5_COOKIE), ("content-encoding", "gzip"), ("date", C5_DATE2)],
+        );
+    }
+
+    #[test]
+    fn decode_rfc7541_requests() {
+        check_requests([
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ]);
+    }
+
+    #[test]
+    fn decode_rfc7541_requests_huffman() {
+        check_requests([
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ]);
+    }
+
+    #[test]
+    fn decode_rfc7541_responses() {
+        check_responses([
+            "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3120 474d 546e 1768
+             7474 7073 3a2f 2f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "4803 3330 37c1 c0bf",
+            "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220 474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153
+             444a 4b48 514b 425a 584f 5157 454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 3630 303b 2076 6572 7369 6f6e
+             3d31",
+        ]);
+    }
+
+    #[test]
+    fn decode_rfc7541_responses_huffman() {
+        check_responses([
+            "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0 82a6 2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8
+             e9ae 82ae 43d3",
+            "4883 640e ffc1 c0bf",
+            "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b d9ab 77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b
+             3960 d5af 2708 7f36 72c1 ab27 0fb5 291f 9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+        ]);
+    }
+
+    #[test]
+    fn decode_huffman_invalid_padding() {
+        // 'a'的编码为00011，填充3位1
+        let field = (b"a".to_vec(), b"x".to_vec());
+        assert_eq!(ProtoHpackDecoder::new(4096, 100, 65536).decode(&hex("0081 1f01 78")).unwrap(), vec![field]);
+        // 填充超过7位
+        assert!(ProtoHpackDecoder::new(4096, 100, 65536).decode(&hex("0082 1fff 0178")).is_err());
+        // 填充不全是1
+        assert!(ProtoHpackDecoder::new(4096, 100, 65536).decode(&hex("0081 1801 78")).is_err());
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let fields: Vec<ProtoHpackField> = C3_REQ3.iter().map(|(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec())).collect();
+        let block = ProtoHpackEncoder::new().encode(&fields);
+        assert_eq!(ProtoHpackDecoder::new(4096, 100, 65536).decode(&block).unwrap(), fields);
+    }
+
+    #[test]
+    fn decode_list_limits() {
+        // 静态表第2项(:method GET)重复展开
+        let block = vec![0x82; 10];
+        assert_eq!(ProtoHpackDecoder::new(4096, 10, 65536).decode(&block).unwrap().len(), 10);
+        assert!(ProtoHpackDecoder::new(4096, 9, 65536).decode(&block).is_err());
+        // 每个字段 7 + 3 + 32 字节
+        assert!(ProtoHpackDecoder::new(4096, 100, 42 * 10).decode(&block).is_ok());
+        assert!(ProtoHpackDecoder::new(4096, 100, 42 * 10 - 1).decode(&block).is_err());
+    }
+
+    #[test]
+    fn decode_table_size_update_position() {
+        let mut decoder = ProtoHpackDecoder::new(4096, 100, 65536);
+        assert!(decoder.decode(&[0x3f, 0xe1, 0x1f, 0x82]).is_ok());
+        assert!(decoder.decode(&[0x82, 0x20]).is_err());
+    }
+}
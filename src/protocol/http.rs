@@ -1,28 +1,423 @@
 use httparse::{Request, Response, Status};
+use std::collections::VecDeque;
+
+// chunk-size行以及trailer行的最大长度
+const HTTP_CHUNK_LINE_MAX: usize = 4096;
+// 保留trailers时trailer字段的最大数量
+const HTTP_TRAILERS_MAX: usize = 64;
+// 解析消息头时初始的头部数组大小，头部过多时成倍扩大，直到max_headers
+pub const HTTP_HEADERS_INIT: usize = 32;
+
+/* http消息体的分帧方式 */
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum ProtoHttpFraming {
+    // 没有消息体
+    NONE,
+    // Content-Length
+    LENGTH(u64),
+    // Transfer-Encoding: chunked
+    CHUNKED,
+    // 读取到连接关闭为止
+    CLOSE,
+}
+
+/* 消息头中可能导致请求走私的分帧问题 */
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum ProtoHttpAnomaly {
+    // 同时带有Content-Length与Transfer-Encoding
+    CONFLICT,
+    // 多个Content-Length
+    DUPLICATE,
+    // Content-Length不是数字
+    BADLENGTH,
+    // 请求的Transfer-Encoding最后一个编码不是chunked，或者HTTP/1.0请求使用Transfer-Encoding
+    BADCODING,
+    // 头部折行(obs-fold)
+    OBSFOLD,
+    // 只有LF的换行
+    BARELF,
+    // 头部名称与冒号之间有空白
+    SPACE,
+}
+
+/* chunked消息体的解析状态 */
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+enum ProtoHttpChunk {
+    // chunk-size行
+    SIZE,
+    // chunk-data，剩余的字节数
+    DATA(u64),
+    // chunk-data之后的CRLF
+    CRLF,
+    // last-chunk之后的trailer，以空行结束
+    TRAILER,
+}
+
+/*
+* http消息体的分帧状态机
+* 输入属于当前消息的数据，判断消息体在哪里结束
+*/
+pub struct ProtoHttpBody {
+    framing: ProtoHttpFraming,
+    remaining: u64,
+    chunk: ProtoHttpChunk,
+    // 未完整的chunk-size行或者trailer行
+    line: Vec<u8>,
+    done: bool,
+    // 解析出的trailer字段，None表示不保留
+    trailers: Option<Vec<ProtoHttpHeader>>,
+}
+
+/* 一个http头部，值中的非utf8字符被替换 */
+#[derive(Clone)]
+pub struct ProtoHttpHeader {
+    pub name: String,
+    pub value: String,
+}
+
+/* 按原始顺序保存的http头部列表，名称不区分大小写 */
+#[derive(Clone, Default)]
+pub struct ProtoHttpHeaders {
+    list: Vec<ProtoHttpHeader>,
+}
+
+/* 解析后的请求头 */
+#[derive(Clone, Default)]
+pub struct ProtoHttpReqMeta {
+    pub method: String,
+    // 请求行中的request-target，原样保存
+    pub target: String,
+    // HTTP/1.x 的 x
+    pub version: u8,
+    pub host: String,
+    pub content_type: String,
+    pub content_length: Option<u64>,
+    pub user_agent: String,
+    pub headers: ProtoHttpHeaders,
+}
+
+/* 解析后的响应头 */
+#[derive(Clone, Default)]
+pub struct ProtoHttpRespMeta {
+    pub code: u16,
+    // HTTP/1.x 的 x
+    pub version: u8,
+    pub content_type: String,
+    pub content_length: Option<u64>,
+    pub headers: ProtoHttpHeaders,
+}
+
+/* multipart消息中的一个part */
+#[derive(Clone)]
+pub struct ProtoHttpPart {
+    // Content-Disposition中的name与filename，没有filename的是普通表单字段
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: String,
+    // part内容在消息体中的位置与长度
+    pub offset: usize,
+    pub size: usize,
+}
 
 struct ProtoHttpReq {
     pub seen_header: bool,
-    pub http_method: String,
+    pub meta: ProtoHttpReqMeta,
     pub seen_bytes: u64,
+    pub body: ProtoHttpBody,
 }
 
 struct ProtoHttpResp {
     pub seen_header: bool,
-    pub http_status_code: u16,
+    pub meta: ProtoHttpRespMeta,
     pub seen_bytes: u64,
+    pub body: ProtoHttpBody,
 }
+/*
+* 一个http事务: 已放行的请求，按顺序等待对应的响应
+* 被阻断的请求在本地生成响应，轮到该事务时直接发送给http client端
+*/
+struct ProtoHttpTxn {
+    req: ProtoHttpReqMeta,
+    req_hdr: Vec<u8>,
+    local_resp: Option<Vec<u8>>,
+}
+
 pub struct ProtoHttpCtx {
     pub not_valid: bool,
+    // 消息头的头部数量或者长度超过限制
+    oversize: bool,
+    // Content-Length不合法或不一致，无法确定消息边界
+    bad_length: bool,
+    // 请求的Transfer-Encoding最后一个编码不是chunked，无法确定消息边界
+    bad_transfer: bool,
+    // 严格检查消息头的分帧信息，检查到的问题由调用者按策略处理
+    strict: bool,
+    anomalies: Vec<ProtoHttpAnomaly>,
+    max_headers: usize,
+    max_header_size: usize,
     req: ProtoHttpReq,
     resp: ProtoHttpResp,
+    // 等待响应的事务，支持keep-alive与pipeline
+    txns: VecDeque<ProtoHttpTxn>,
+}
+
+impl ProtoHttpBody {
+    pub fn new(framing: ProtoHttpFraming) -> Self {
+        Self {
+            framing,
+            remaining: match framing {
+                ProtoHttpFraming::LENGTH(len) => len,
+                _ => 0,
+            },
+            chunk: ProtoHttpChunk::SIZE,
+            line: Vec::new(),
+            done: matches!(framing, ProtoHttpFraming::NONE | ProtoHttpFraming::LENGTH(0)),
+            trailers: None,
+        }
+    }
+
+    /* 保留chunked消息体的trailer字段，由take_trailers取出 */
+    pub fn keep_trailers(&mut self) {
+        self.trailers = Some(Vec::new());
+    }
+
+    pub fn take_trailers(&mut self) -> Vec<ProtoHttpHeader> {
+        return self.trailers.take().unwrap_or_default();
+    }
+
+    pub fn framing(&self) -> ProtoHttpFraming {
+        self.framing
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /*
+    * 连接关闭，消息体结束
+    * 以连接关闭分帧的消息体正常结束; 其他分帧方式表示消息体被截断
+    */
+    pub fn close(&mut self) {
+        self.done = true;
+    }
+
+    /*
+    * 输入数据，返回其中属于当前消息体的字节数，剩余的数据属于下一个消息
+    * decoded 不为None时追加去掉chunked编码之后的内容
+    * chunked格式错误返回Err
+    */
+    pub fn feed(&mut self, data: &[u8], mut decoded: Option<&mut Vec<u8>>) -> Result<usize, String> {
+        if self.done {
+            return Ok(0);
+        }
+        match self.framing {
+            ProtoHttpFraming::NONE => return Ok(0),
+            ProtoHttpFraming::LENGTH(_) => {
+                let n = std::cmp::min(self.remaining, data.len() as u64) as usize;
+                self.remaining -= n as u64;
+                self.done = self.remaining == 0;
+                if let Some(decoded) = decoded {
+                    decoded.extend_from_slice(&data[0..n]);
+                }
+                return Ok(n);
+            }
+            ProtoHttpFraming::CLOSE => {
+                if let Some(decoded) = decoded {
+                    decoded.extend_from_slice(data);
+                }
+                return Ok(data.len());
+            }
+            ProtoHttpFraming::CHUNKED => {}
+        }
+
+        let mut pos = 0;
+        while pos < data.len() && !self.done {
+            match self.chunk {
+                ProtoHttpChunk::DATA(remaining) => {
+                    let n = std::cmp::min(remaining, (data.len() - pos) as u64) as usize;
+                    if let Some(decoded) = decoded.as_deref_mut() {
+                        decoded.extend_from_slice(&data[pos..pos + n]);
+                    }
+                    pos += n;
+                    self.chunk = if remaining == n as u64 { ProtoHttpChunk::CRLF } else { ProtoHttpChunk::DATA(remaining - n as u64) };
+                }
+                _ => {
+                    // 按行解析: chunk-size行、chunk-data之后的CRLF、trailer行
+                    let end = match data[pos..].iter().position(|b| *b == b'\n') {
+                        Some(end) => pos + end + 1,
+                        None => data.len(),
+                    };
+                    self.line.extend_from_slice(&data[pos..end]);
+                    pos = end;
+                    if self.line.len() > HTTP_CHUNK_LINE_MAX {
+                        return Err("chunk line too long".to_string());
+                    }
+                    if self.line.last() != Some(&b'\n') {
+                        continue;
+                    }
+                    let line = std::mem::take(&mut self.line);
+                    let line = line.strip_suffix(b"\n").unwrap_or(&line);
+                    let line = line.strip_suffix(b"\r").unwrap_or(line);
+                    self.parse_chunk_line(line)?;
+                }
+            }
+        }
+        return Ok(pos);
+    }
+
+    fn parse_chunk_line(&mut self, line: &[u8]) -> Result<(), String> {
+        match self.chunk {
+            ProtoHttpChunk::SIZE => {
+                // chunk-size [; chunk-ext]
+                let size = line.split(|b| *b == b';').next().unwrap_or(b"").trim_ascii();
+                let size = std::str::from_utf8(size)
+                    .ok()
+                    .filter(|v| !v.is_empty() && v.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .and_then(|v| u64::from_str_radix(v, 16).ok())
+                    .ok_or(format!("invalid chunk size {:?}", String::from_utf8_lossy(line)))?;
+                self.chunk = if size == 0 { ProtoHttpChunk::TRAILER } else { ProtoHttpChunk::DATA(size) };
+            }
+            ProtoHttpChunk::CRLF => {
+                if !line.is_empty() {
+                    return Err("missing CRLF after chunk data".to_string());
+                }
+                self.chunk = ProtoHttpChunk::SIZE;
+            }
+            ProtoHttpChunk::TRAILER => {
+                self.done = line.is_empty();
+                if let (Some(trailers), false) = (self.trailers.as_mut(), line.is_empty()) {
+                    let colon = line.iter().position(|b| *b == b':').ok_or("trailer field without colon")?;
+                    if trailers.len() >= HTTP_TRAILERS_MAX {
+                        return Err(format!("more than {} trailer fields", HTTP_TRAILERS_MAX));
+                    }
+                    trailers.push(ProtoHttpHeader {
+                        name: String::from_utf8_lossy(&line[..colon]).trim().to_string(),
+                        value: String::from_utf8_lossy(&line[colon + 1..]).trim().to_string(),
+                    });
+                }
+            }
+            ProtoHttpChunk::DATA(_) => {}
+        }
+        return Ok(());
+    }
+}
+
+impl ProtoHttpHeaders {
+    pub fn from_raw(headers: &[httparse::Header]) -> Self {
+        let list = headers
+            .iter()
+            .map(|h| ProtoHttpHeader {
+                name: h.name.to_string(),
+                value: String::from_utf8_lossy(h.value).trim().to_string(),
+            })
+            .collect();
+        return Self { list };
+    }
+
+    /* 第一个同名头部的值 */
+    pub fn get(&self, name: &str) -> Option<&str> {
+        return self.list.iter().find(|h| h.name.eq_ignore_ascii_case(name)).map(|h| h.value.as_str());
+    }
+
+    /* 所有同名头部的值 */
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        return self.list.iter().filter(move |h| h.name.eq_ignore_ascii_case(name)).map(|h| h.value.as_str());
+    }
+
+    /*
+    * Content-Length, 不合法时为None
+    * 多个Content-Length(或逗号分隔的列表)的值相同时按一个处理
+    * 只接受十进制数字，不接受符号与空值
+    */
+    pub fn content_length(&self) -> Option<u64> {
+        let mut length = None;
+        for len in self.get_all("Content-Length").flat_map(|v| v.split(',')) {
+            let len = len.trim();
+            if len.is_empty() || !len.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let len = len.parse::<u64>().ok()?;
+            if length.is_some_and(|first| first != len) {
+                return None;
+            }
+            length = Some(len);
+        }
+        return length;
+    }
+
+    /* 带有Content-Length，但无法确定长度 */
+    pub fn length_invalid(&self) -> bool {
+        return self.get("Content-Length").is_some() && self.content_length().is_none();
+    }
+
+    /* Transfer-Encoding最后一个编码是否为chunked，没有Transfer-Encoding时为None */
+    pub fn last_chunked(&self) -> Option<bool> {
+        return self
+            .get_all("Transfer-Encoding")
+            .last()
+            .map(|v| v.rsplit(',').next().unwrap_or("").trim().eq_ignore_ascii_case("chunked"));
+    }
+}
+
+impl ProtoHttpReqMeta {
+    fn from_raw(req: &Request) -> Self {
+        let headers = ProtoHttpHeaders::from_raw(req.headers);
+        let field = |name: &str| headers.get(name).unwrap_or("").to_string();
+        return Self {
+            method: req.method.unwrap_or("").to_string(),
+            target: req.path.unwrap_or("").to_string(),
+            version: req.version.unwrap_or(1),
+            host: field("Host"),
+            content_type: field("Content-Type"),
+            content_length: headers.content_length(),
+            user_agent: field("User-Agent"),
+            headers,
+        };
+    }
+
+    /*
+    * 请求切换协议: Connection中包含upgrade时返回Upgrade的值
+    * CONNECT请求返回"CONNECT"
+    */
+    pub fn upgrade(&self) -> Option<&str> {
+        if self.method == "CONNECT" {
+            return Some("CONNECT");
+        }
+        let connection = self
+            .headers
+            .get_all("Connection")
+            .flat_map(|v| v.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+        if !connection {
+            return None;
+        }
+        return self.headers.get("Upgrade").filter(|v| !v.is_empty());
+    }
+}
+
+impl ProtoHttpRespMeta {
+    fn from_raw(res: &Response) -> Self {
+        let headers = ProtoHttpHeaders::from_raw(res.headers);
+        return Self {
+            code: res.code.unwrap_or(0),
+            version: res.version.unwrap_or(1),
+            content_type: headers.get("Content-Type").unwrap_or("").to_string(),
+            content_length: headers.content_length(),
+            headers,
+        };
+    }
 }
 
 impl ProtoHttpReq {
     pub fn new() -> Self {
         Self {
             seen_header: false,
-            http_method: String::new(),
+            meta: ProtoHttpReqMeta::default(),
             seen_bytes: 0,
+            body: ProtoHttpBody::new(ProtoHttpFraming::NONE),
         }
     }
 }
@@ -31,18 +426,219 @@ impl ProtoHttpResp {
     pub fn new() -> Self {
         Self {
             seen_header: false,
-            http_status_code: 0,
+            meta: ProtoHttpRespMeta::default(),
             seen_bytes: 0,
+            body: ProtoHttpBody::new(ProtoHttpFraming::NONE),
         }
     }
 }
 
+impl ProtoHttpPart {
+    /* 从Content-Type中取出multipart的boundary，不是multipart返回None */
+    pub fn multipart_boundary(content_type: &str) -> Option<String> {
+        let mut params = Self::split_params(content_type).into_iter();
+        let media = params.next()?;
+        if !media.trim().to_ascii_lowercase().starts_with("multipart/") {
+            return None;
+        }
+        let boundary = params.find_map(|p| Self::param_value(&p, "boundary"))?;
+        if boundary.is_empty() || boundary.len() > 70 {
+            return None;
+        }
+        return Some(boundary);
+    }
+
+    /* 按分号拆分头部参数，引号内的分号不拆分 */
+    fn split_params(value: &str) -> Vec<String> {
+        let mut params = Vec::new();
+        let mut current = String::new();
+        let mut quoted = false;
+        let mut escaped = false;
+        for c in value.chars() {
+            if escaped {
+                current.push(c);
+                escaped = false;
+                continue;
+            }
+            match c {
+                '\\' if quoted => escaped = true,
+                '"' => {
+                    quoted = !quoted;
+                    current.push(c);
+                }
+                ';' if !quoted => params.push(std::mem::take(&mut current)),
+                _ => current.push(c),
+            }
+        }
+        params.push(current);
+        return params;
+    }
+
+    /* 参数 name=value 或 name="value"，名称匹配时返回去掉引号的值 */
+    fn param_value(param: &str, name: &str) -> Option<String> {
+        let (key, value) = param.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case(name) {
+            return None;
+        }
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        return Some(value.to_string());
+    }
+
+    /* RFC 5987 扩展参数: UTF-8''%xx 编码 */
+    fn param_ext_value(param: &str, name: &str) -> Option<String> {
+        let value = Self::param_value(param, &format!("{}*", name))?;
+        let (charset, rest) = value.split_once('\'')?;
+        let (_, encoded) = rest.split_once('\'')?;
+        let mut bytes = Vec::with_capacity(encoded.len());
+        let mut iter = encoded.bytes();
+        while let Some(b) = iter.next() {
+            if b != b'%' {
+                bytes.push(b);
+                continue;
+            }
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        }
+        if !charset.eq_ignore_ascii_case("utf-8") {
+            return Some(bytes.iter().map(|b| *b as char).collect());
+        }
+        return Some(String::from_utf8_lossy(&bytes).to_string());
+    }
+
+    /*
+    * 解析multipart消息体，返回所有part
+    * body为去掉chunked编码、解压之后的完整消息体
+    * 格式错误或者缺少结束分隔符返回Err
+    */
+    pub fn parse_multipart(body: &[u8], boundary: &str) -> Result<Vec<ProtoHttpPart>, String> {
+        let delimiter = format!("--{}", boundary).into_bytes();
+        let find = |from: usize, pattern: &[u8]| -> Option<usize> {
+            body.get(from..)?.windows(pattern.len()).position(|w| w == pattern).map(|p| from + p)
+        };
+
+        // 第一个分隔符之前是preamble
+        let mut pos = if body.starts_with(&delimiter) {
+            0
+        } else {
+            let mut pattern = b"\r\n".to_vec();
+            pattern.extend_from_slice(&delimiter);
+            find(0, &pattern).ok_or("multipart boundary not found")? + 2
+        };
+        let mut next_delimiter = b"\r\n".to_vec();
+        next_delimiter.extend_from_slice(&delimiter);
+
+        let mut parts = Vec::new();
+        loop {
+            pos += delimiter.len();
+            if body[pos..].starts_with(b"--") {
+                return Ok(parts);
+            }
+            // 分隔符之后可以有空白，然后是CRLF
+            let line_end = find(pos, b"\r\n").ok_or("multipart delimiter line not terminated")?;
+            if !body[pos..line_end].iter().all(|b| *b == b' ' || *b == b'\t') {
+                return Err("invalid multipart delimiter line".to_string());
+            }
+            pos = line_end + 2;
+
+            // part头部，part可以没有头部
+            let (header_end, headers) = if body[pos..].starts_with(b"\r\n") {
+                (pos + 2, ProtoHttpHeaders::default())
+            } else {
+                let mut raw = [httparse::EMPTY_HEADER; HTTP_HEADERS_INIT];
+                match httparse::parse_headers(&body[pos..], &mut raw) {
+                    Ok(Status::Complete((len, raw))) => (pos + len, ProtoHttpHeaders::from_raw(raw)),
+                    Ok(Status::Partial) => return Err("multipart part header incomplete".to_string()),
+                    Err(e) => return Err(format!("invalid multipart part header: {}", e)),
+                }
+            };
+            let content_end = find(header_end, &next_delimiter).ok_or("multipart close delimiter not found")?;
+
+            let mut part = ProtoHttpPart {
+                name: String::new(),
+                filename: None,
+                content_type: headers.get("Content-Type").unwrap_or("text/plain").to_string(),
+                offset: header_end,
+                size: content_end - header_end,
+            };
+            if let Some(disposition) = headers.get("Content-Disposition") {
+                let params = Self::split_params(disposition);
+                part.name = params.iter().find_map(|p| Self::param_value(p, "name")).unwrap_or_default();
+                // filename* 优先于 filename
+                part.filename = params
+                    .iter()
+                    .find_map(|p| Self::param_ext_value(p, "filename"))
+                    .or_else(|| params.iter().find_map(|p| Self::param_value(p, "filename")));
+            }
+            parts.push(part);
+            pos = content_end + 2;
+        }
+    }
+
+    /*
+    * 单独扫描该part时发送给icap server的http请求头
+    * 使用原始请求的请求行与头部，Content-Type与长度替换为该part的信息
+    */
+    pub fn build_part_header(&self, req_hdr: &[u8]) -> Vec<u8> {
+        let header = ProtoHttpCtx::remove_http_header(req_hdr, "Content-Type");
+        let header = ProtoHttpCtx::remove_http_header(&header, "Content-Encoding");
+        let mut data = ProtoHttpCtx::rebuild_http_header(&header, Some(self.size));
+        data.truncate(data.len() - 2);
+        data.extend_from_slice(format!("Content-Type: {}\r\n", self.content_type).as_bytes());
+        data.extend_from_slice(
+            format!("Content-Disposition: attachment; filename=\"{}\"\r\n", self.quoted_filename()).as_bytes(),
+        );
+        data.extend_from_slice(b"\r\n");
+        return data;
+    }
+
+    /* 用于头部的文件名 */
+    pub fn quoted_filename(&self) -> String {
+        return Self::quote(self.filename.as_deref().unwrap_or(""));
+    }
+
+    /* part的描述: name="..."; filename="..."; type="..."; size=N */
+    pub fn describe(&self) -> String {
+        return format!(
+            "name=\"{}\"; filename=\"{}\"; type=\"{}\"; size={}",
+            Self::quote(&self.name),
+            self.quoted_filename(),
+            Self::quote(&self.content_type),
+            self.size
+        );
+    }
+
+    /*
+    * 用于头部引号内的值
+    * 非ASCII、控制字符以及引号、反斜杠、百分号使用%xx编码
+    */
+    fn quote(raw: &str) -> String {
+        let mut value = String::new();
+        for b in raw.bytes() {
+            if (b.is_ascii_graphic() && b != b'"' && b != b'\\' && b != b'%') || b == b' ' {
+                value.push(b as char);
+            } else {
+                value.push_str(&format!("%{:02X}", b));
+            }
+        }
+        return value;
+    }
+}
+
 impl ProtoHttpCtx {
     pub fn new() -> Self {
         Self {
             not_valid: false,
+            oversize: false,
+            bad_length: false,
+            bad_transfer: false,
+            strict: false,
+            anomalies: Vec::new(),
+            max_headers: 256,
+            max_header_size: 65536,
             req: ProtoHttpReq::new(),
             resp: ProtoHttpResp::new(),
+            txns: VecDeque::new(),
         }
     }
 
@@ -72,8 +668,361 @@ impl ProtoHttpCtx {
         self.resp.seen_header
     }
 
-    pub fn _req_method(&self) -> String {
-        self.req.http_method.clone()
+    pub fn req_body(&mut self) -> &mut ProtoHttpBody {
+        &mut self.req.body
+    }
+    pub fn req_framing(&self) -> ProtoHttpFraming {
+        self.req.body.framing()
+    }
+    pub fn req_body_done(&self) -> bool {
+        self.req.body.is_done()
+    }
+    pub fn resp_body(&mut self) -> &mut ProtoHttpBody {
+        &mut self.resp.body
+    }
+    pub fn resp_framing(&self) -> ProtoHttpFraming {
+        self.resp.body.framing()
+    }
+    pub fn resp_body_done(&self) -> bool {
+        self.resp.body.is_done()
+    }
+
+    /* 1xx中间响应(101除外)，之后还有最终响应 */
+    pub fn resp_interim(&self) -> bool {
+        let code = self.resp.meta.code;
+        return (100..200).contains(&code) && code != 101;
+    }
+
+    /* 101 Switching Protocols 或者 CONNECT 2xx，之后的数据不再是http */
+    pub fn resp_tunnel(&self) -> bool {
+        let code = self.resp.meta.code;
+        return code == 101 || (self.resp_method() == "CONNECT" && (200..300).contains(&code));
+    }
+
+    /*
+    * 对应的请求确实要求切换协议，之后双向透传
+    * 请求没有Upgrade时的101不可信，不按隧道处理
+    */
+    pub fn resp_upgraded(&self) -> bool {
+        return self.resp_tunnel() && self.resp_req_meta().and_then(|req| req.upgrade()).is_some();
+    }
+
+    /* 切换到websocket协议的101响应 */
+    pub fn resp_websocket(&self) -> bool {
+        let upgrade = |value: Option<&str>| {
+            value.is_some_and(|v| v.split(',').any(|p| p.trim().eq_ignore_ascii_case("websocket")))
+        };
+        return self.resp.meta.code == 101
+            && upgrade(self.resp_req_meta().and_then(|req| req.upgrade()))
+            && upgrade(self.resp.meta.headers.get("Upgrade"));
+    }
+
+    /* 响应没有消息体: HEAD请求、1xx、204、304响应、CONNECT 2xx */
+    pub fn resp_no_body(method: &str, code: u16) -> bool {
+        return method == "HEAD"
+            || (100..200).contains(&code)
+            || code == 204
+            || code == 304
+            || (method == "CONNECT" && (200..300).contains(&code));
+    }
+
+    /*
+    * 根据Transfer-Encoding与Content-Length确定消息体的分帧方式
+    * 1. Transfer-Encoding最后一个编码为chunked，按chunked解析; 否则读取到连接关闭为止(请求在解析消息头时已被拒绝)
+    * 2. 否则按Content-Length解析
+    * 3. 都没有时，请求没有消息体，响应读取到连接关闭为止
+    * */
+    pub fn parse_framing(headers: &ProtoHttpHeaders, content_length: Option<u64>, request: bool) -> ProtoHttpFraming {
+        match (headers.last_chunked(), content_length) {
+            (Some(true), _) => return ProtoHttpFraming::CHUNKED,
+            (Some(false), _) => return ProtoHttpFraming::CLOSE,
+            (None, Some(0)) => return ProtoHttpFraming::NONE,
+            (None, Some(len)) => return ProtoHttpFraming::LENGTH(len),
+            (None, None) if request => return ProtoHttpFraming::NONE,
+            (None, None) => return ProtoHttpFraming::CLOSE,
+        }
+    }
+
+    pub fn req_method(&self) -> &str {
+        &self.req.meta.method
+    }
+    pub fn req_path(&self) -> &str {
+        &self.req.meta.target
+    }
+    /* 当前请求的请求头 */
+    pub fn req_meta(&self) -> &ProtoHttpReqMeta {
+        &self.req.meta
+    }
+    /*
+    * 当前请求带有 Expect: 100-continue，http client端在收到100之前不发送请求体
+    * HTTP/1.0 以及没有请求体的请求忽略该头部
+    */
+    pub fn req_expect_continue(&self) -> bool {
+        if self.req.meta.version == 0 || self.req.body.framing() == ProtoHttpFraming::NONE {
+            return false;
+        }
+        return self
+            .req
+            .meta
+            .headers
+            .get("Expect")
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("100-continue"));
+    }
+    /* 当前响应的响应头 */
+    pub fn resp_meta(&self) -> &ProtoHttpRespMeta {
+        &self.resp.meta
+    }
+
+    /*
+    * 当前请求已放行，加入等待响应的事务队列
+    * req_hdr 为发送给http server端的请求头，用于RESPMOD
+    */
+    pub fn txn_push(&mut self, req_hdr: Vec<u8>) {
+        self.txns.push_back(ProtoHttpTxn {
+            req: self.req.meta.clone(),
+            req_hdr,
+            local_resp: None,
+        });
+    }
+
+    /* 最后加入的事务不会收到http server端的响应，使用本地生成的响应 */
+    pub fn txn_set_local(&mut self, resp: Vec<u8>) {
+        if let Some(txn) = self.txns.back_mut() {
+            txn.local_resp = Some(resp);
+        }
+    }
+
+    /* 最早的事务使用本地生成的响应，且之前的响应都已发送完成，则取出该响应 */
+    pub fn txn_pop_local(&mut self) -> Option<Vec<u8>> {
+        if self.resp.seen_header || self.txns.front()?.local_resp.is_none() {
+            return None;
+        }
+        return self.txns.pop_front()?.local_resp;
+    }
+
+    /* 最早的事务使用本地生成的响应，http server端的下一个响应属于之后的事务 */
+    pub fn txn_front_local(&self) -> bool {
+        self.txns.front().is_some_and(|txn| txn.local_resp.is_some())
+    }
+
+    /* 所有事务的响应都已发送完成 */
+    pub fn txn_empty(&self) -> bool {
+        self.txns.is_empty()
+    }
+
+    /* 最早的事务已收到完整的响应 */
+    pub fn txn_finish(&mut self) {
+        self.txns.pop_front();
+    }
+
+    /* 当前响应对应的请求 */
+    pub fn resp_method(&self) -> &str {
+        self.txns.front().map_or("", |txn| &txn.req.method)
+    }
+    pub fn resp_path(&self) -> &str {
+        self.txns.front().map_or("", |txn| &txn.req.target)
+    }
+    pub fn resp_req_hdr(&self) -> &[u8] {
+        self.txns.front().map_or(&[], |txn| &txn.req_hdr)
+    }
+    pub fn resp_req_meta(&self) -> Option<&ProtoHttpReqMeta> {
+        self.txns.front().map(|txn| &txn.req)
+    }
+
+    pub fn set_header_limits(&mut self, max_headers: usize, max_header_size: usize) {
+        self.max_headers = max_headers;
+        self.max_header_size = max_header_size;
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /* 取出最近一次解析消息头时检查到的分帧问题 */
+    pub fn take_anomalies(&mut self) -> Vec<ProtoHttpAnomaly> {
+        std::mem::take(&mut self.anomalies)
+    }
+
+    /* 消息头的结束位置(包含结尾的空行)，兼容只有LF的换行; 不完整返回None */
+    pub fn head_end(data: &[u8]) -> Option<usize> {
+        let pos = data.iter().enumerate().position(|(i, b)| {
+            *b == b'\n' && (data[i + 1..].starts_with(b"\n") || data[i + 1..].starts_with(b"\r\n"))
+        })?;
+        if data[pos + 1] == b'\n' {
+            return Some(pos + 2);
+        }
+        return Some(pos + 3);
+    }
+
+    /* 按行拆分消息头，去掉CR; 返回每一行以及是否只有LF */
+    fn head_lines(head: &[u8]) -> Vec<(&[u8], bool)> {
+        let mut lines = Vec::new();
+        for line in head.split_inclusive(|b| *b == b'\n') {
+            let bare = !line.ends_with(b"\r\n");
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                lines.push((line, bare));
+                break;
+            }
+            lines.push((line, bare));
+        }
+        return lines;
+    }
+
+    /*
+    * 检查完整的消息头中可能导致请求走私的分帧问题
+    * head 为消息头(包含结尾的空行)
+    */
+    pub fn inspect_header(head: &[u8], request: bool) -> Vec<ProtoHttpAnomaly> {
+        let mut anomalies = Vec::new();
+        let mut add = |anomaly: ProtoHttpAnomaly| {
+            if !anomalies.contains(&anomaly) {
+                anomalies.push(anomaly);
+            }
+        };
+        let lines = Self::head_lines(head);
+        let http10 = lines.first().is_some_and(|(line, _)| line.ends_with(b"HTTP/1.0") || line.starts_with(b"HTTP/1.0"));
+        let mut lengths = Vec::new();
+        let mut coding: Option<String> = None;
+        for (i, (line, bare)) in lines.iter().enumerate() {
+            if *bare {
+                add(ProtoHttpAnomaly::BARELF);
+            }
+            if i == 0 || line.is_empty() {
+                continue;
+            }
+            if line[0] == b' ' || line[0] == b'\t' {
+                add(ProtoHttpAnomaly::OBSFOLD);
+                continue;
+            }
+            let Some(colon) = line.iter().position(|b| *b == b':') else {
+                continue;
+            };
+            let name = &line[..colon];
+            if name.ends_with(b" ") || name.ends_with(b"\t") {
+                add(ProtoHttpAnomaly::SPACE);
+            }
+            let name = name.trim_ascii();
+            let value = String::from_utf8_lossy(&line[colon + 1..]).trim().to_string();
+            if name.eq_ignore_ascii_case(b"Content-Length") {
+                for len in value.split(',') {
+                    let len = len.trim();
+                    if len.is_empty() || !len.bytes().all(|b| b.is_ascii_digit()) {
+                        add(ProtoHttpAnomaly::BADLENGTH);
+                    }
+                    lengths.push(len.to_string());
+                }
+            } else if name.eq_ignore_ascii_case(b"Transfer-Encoding") {
+                coding = Some(value.rsplit(',').next().unwrap_or("").trim().to_ascii_lowercase());
+            }
+        }
+        if lengths.len() > 1 {
+            add(ProtoHttpAnomaly::DUPLICATE);
+        }
+        if let Some(coding) = coding {
+            if !lengths.is_empty() {
+                add(ProtoHttpAnomaly::CONFLICT);
+            }
+            if request && (coding != "chunked" || http10) {
+                add(ProtoHttpAnomaly::BADCODING);
+            }
+        }
+        return anomalies;
+    }
+
+    /*
+    * 将有分帧问题的消息头重写为没有歧义的形式
+    * 1. 换行统一为CRLF，折行合并为一个空格
+    * 2. 同时带有Transfer-Encoding时删除Content-Length
+    * 3. 相同的多个Content-Length合并为一个
+    * 无法确定消息边界时(Content-Length不一致或不合法、名称后有空白、请求的编码不是chunked)返回Err
+    */
+    pub fn normalize_header(head: &[u8], request: bool) -> Result<Vec<u8>, String> {
+        let anomalies = Self::inspect_header(head, request);
+        for anomaly in [ProtoHttpAnomaly::BADLENGTH, ProtoHttpAnomaly::SPACE, ProtoHttpAnomaly::BADCODING] {
+            if anomalies.contains(&anomaly) {
+                return Err(format!("{:?} can not be normalized", anomaly));
+            }
+        }
+
+        // 合并折行
+        let mut fields: Vec<Vec<u8>> = Vec::new();
+        for (line, _) in Self::head_lines(head) {
+            if line.is_empty() {
+                break;
+            }
+            match fields.last_mut() {
+                Some(last) if line[0] == b' ' || line[0] == b'\t' => {
+                    last.push(b' ');
+                    last.extend_from_slice(line.trim_ascii());
+                }
+                _ => fields.push(line.to_vec()),
+            }
+        }
+
+        let chunked = anomalies.contains(&ProtoHttpAnomaly::CONFLICT);
+        let mut length: Option<Vec<u8>> = None;
+        let mut data = Vec::with_capacity(head.len());
+        for (i, field) in fields.iter().enumerate() {
+            let name = field.split(|b| *b == b':').next().unwrap_or(b"");
+            if i > 0 && name.eq_ignore_ascii_case(b"Content-Length") {
+                if chunked {
+                    continue;
+                }
+                // 没有冒号的行不是合法的头部
+                let Some(value) = field.get(name.len() + 1..) else {
+                    return Err("Content-Length without colon".to_string());
+                };
+                for len in value.split(|b| *b == b',') {
+                    match &length {
+                        Some(first) if first.as_slice() != len.trim_ascii() => {
+                            return Err("conflicting Content-Length".to_string());
+                        }
+                        Some(_) => {}
+                        None => length = Some(len.trim_ascii().to_vec()),
+                    }
+                }
+                continue;
+            }
+            data.extend_from_slice(field);
+            data.extend_from_slice(b"\r\n");
+        }
+        if let Some(length) = length {
+            data.extend_from_slice(b"Content-Length: ");
+            data.extend_from_slice(&length);
+            data.extend_from_slice(b"\r\n");
+        }
+        data.extend_from_slice(b"\r\n");
+        return Ok(data);
+    }
+
+    pub fn is_oversize(&self) -> bool {
+        self.oversize
+    }
+
+    /* 头部数组已满，返回扩大后的大小; 已达到max_headers返回None */
+    fn grow_headers(&self, count: usize) -> Option<usize> {
+        if count >= self.max_headers {
+            return None;
+        }
+        return Some(std::cmp::min(count * 2, self.max_headers));
+    }
+
+    /* 是否检查严格模式都需要拒绝的Content-Length，否则消息体会被当作下一个消息 */
+    pub fn is_bad_length(&self) -> bool {
+        self.bad_length
+    }
+
+    /* 请求的Transfer-Encoding最后一个编码不是chunked，请求体无法分帧，不论严格模式都需要拒绝 */
+    pub fn is_bad_transfer(&self) -> bool {
+        self.bad_transfer
+    }
+
+    /* 消息头超过限制，由调用者按策略处理 */
+    fn set_oversize(&mut self, reason: &str) {
+        println!("HTTP header over limit: {}", reason);
+        self.oversize = true;
     }
 
     pub fn is_valid(&self) -> bool {
@@ -84,33 +1033,205 @@ impl ProtoHttpCtx {
         self.not_valid = !valid;
     }
 
+    /* 删除http头中指定名称的头部 */
+    pub fn remove_http_header(header: &[u8], name: &str) -> Vec<u8> {
+        let mut data = Vec::with_capacity(header.len());
+        for line in header.split_inclusive(|b| *b == b'\n') {
+            let field = line.split(|b| *b == b':').next().unwrap_or(b"").trim_ascii();
+            if line.contains(&b':') && field.eq_ignore_ascii_case(name.as_bytes()) {
+                continue;
+            }
+            data.extend_from_slice(line);
+        }
+        return data;
+    }
+
+    /*  重建http头的长度信息
+     * 删除原有的Content-Length、Transfer-Encoding头
+     * content_length 为Some时添加新的Content-Length头
+     * */
+    pub fn rebuild_http_header(header: &[u8], content_length: Option<usize>) -> Vec<u8> {
+        let mut data = Vec::with_capacity(header.len() + 32);
+        for line in header.split(|b| *b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                continue;
+            }
+            let name = line.split(|b| *b == b':').next().unwrap_or(b"").trim_ascii();
+            if name.eq_ignore_ascii_case(b"Content-Length") || name.eq_ignore_ascii_case(b"Transfer-Encoding") {
+                continue;
+            }
+            data.extend_from_slice(line);
+            data.extend_from_slice(b"\r\n");
+        }
+        if let Some(len) = content_length {
+            data.extend_from_slice(format!("Content-Length: {}\r\n", len).as_bytes());
+        }
+        data.extend_from_slice(b"\r\n");
+        return data;
+    }
+
+    /*  根据icap返回的http头与body构造完整的http消息
+     * 1. 有body时使用Content-Length重新设置长度
+     * 2. 请求没有body时删除长度信息
+     * 3. 响应没有body时设置Content-Length: 0 (HEAD请求、1xx、204、304响应除外)
+     * method 为该响应对应的请求方法
+     * */
+    pub fn build_modified_message(&self, header: &[u8], request: bool, method: &str, has_body: bool, body: &[u8]) -> Vec<u8> {
+        let content_length = if has_body {
+            Some(body.len())
+        } else if request {
+            None
+        } else {
+            // 只需要状态码，头部数量不影响
+            let mut res = Response::new(&mut []);
+            let code = match res.parse(header) {
+                Ok(_) | Err(httparse::Error::TooManyHeaders) => res.code.unwrap_or(200),
+                Err(_) => 200,
+            };
+            if Self::resp_no_body(method, code) {
+                None
+            } else {
+                Some(0)
+            }
+        };
+        let mut data = Self::rebuild_http_header(header, content_length);
+        data.extend_from_slice(body);
+        return data;
+    }
+
+    /*
+    * 构造代理本地生成的响应页面
+    * close 为true时带上 Connection: close，返回后关闭连接
+    */
+    pub fn build_local_response(status: &str, title: &str, reason: &str, close: bool) -> Vec<u8> {
+        let body = format!(
+            "<html><head><title>{}</title></head><body><h1>{}</h1><p>{}</p></body></html>",
+            status, title, reason
+        );
+        let mut data = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/html\r\nContent-Length: {}\r\nCache-Control: no-cache\r\n",
+            status,
+            body.len()
+        );
+        if close {
+            data.push_str("Connection: close\r\n");
+        }
+        data.push_str("\r\n");
+        let mut data = data.into_bytes();
+        data.extend_from_slice(body.as_bytes());
+        return data;
+    }
+
+    /*
+    * 构造阻断页面
+    * icap server不可用且策略为fail-closed时返回给http client端
+    * close: 请求体没有发送，返回后关闭连接
+    */
+    pub fn build_block_page(reason: &str, close: bool) -> Vec<u8> {
+        return Self::build_local_response("403 Forbidden", "Access Denied", reason, close);
+    }
+
+    /* 请求头超过限制 */
+    pub fn build_header_too_large() -> Vec<u8> {
+        return Self::build_local_response(
+            "431 Request Header Fields Too Large",
+            "Request Header Fields Too Large",
+            "The request header exceeds the proxy limit.",
+            true,
+        );
+    }
+
+    /* 请求的分帧信息有歧义 */
+    pub fn build_bad_request() -> Vec<u8> {
+        return Self::build_local_response(
+            "400 Bad Request",
+            "Bad Request",
+            "The request framing is ambiguous.",
+            true,
+        );
+    }
+
+    /* 响应头超过限制 */
+    pub fn build_bad_gateway(reason: &str) -> Vec<u8> {
+        return Self::build_local_response("502 Bad Gateway", "Bad Gateway", reason, true);
+    }
+
+    /* 代替http server允许http client端继续发送请求体 */
+    pub fn build_continue() -> Vec<u8> {
+        return b"HTTP/1.1 100 Continue\r\n\r\n".to_vec();
+    }
+
     /*  解析请求头
      * 解析成功，返回解析到的字节数；
      * 如果解析失败，则返回0 且 设置http非法
+     * 如果头部数量或者长度超过限制，则返回0 且 设置oversize
      * 如果数据不够，则返回0
+     * Content-Length不合法时返回0 且 设置bad_length
+     * Transfer-Encoding最后一个编码不是chunked时返回0 且 设置bad_transfer
+     * 严格模式下记录消息头的分帧问题，由调用者通过take_anomalies处理
      * */
     pub fn parse_http_req_header(&mut self, data: &[u8]) -> usize {
-        self.resp_seen_head_set(false);
-
-        let mut headers = [httparse::EMPTY_HEADER; 32];
-        let mut req = Request::new(&mut headers);
+        self.bad_length = false;
+        self.bad_transfer = false;
+        if self.strict {
+            if let Some(end) = Self::head_end(data) {
+                self.anomalies = Self::inspect_header(&data[..end], true);
+            }
+        }
+        let mut count = std::cmp::min(HTTP_HEADERS_INIT, self.max_headers);
+        loop {
+            let mut headers = vec![httparse::EMPTY_HEADER; count];
+            let mut req = Request::new(&mut headers);
+            match req.parse(data) {
+                Err(httparse::Error::TooManyHeaders) => match self.grow_headers(count) {
+                    Some(grow) => count = grow,
+                    None => {
+                        self.set_oversize(&format!("request has more than {} headers", self.max_headers));
+                        return 0;
+                    }
+                },
+                result => return self.parse_http_req_result(result, req, data),
+            }
+        }
+    }
 
-        // 解析 Header
-        match req.parse(data) {
+    fn parse_http_req_result(
+        &mut self,
+        result: httparse::Result<usize>,
+        req: Request,
+        data: &[u8],
+    ) -> usize {
+        match result {
+            Ok(Status::Complete(header_end)) if header_end > self.max_header_size => {
+                self.set_oversize(&format!("request header {} bytes", header_end));
+                return 0;
+            }
             Ok(Status::Complete(header_end)) => {
-                self.req.http_method = req.method.unwrap().to_string();
-                println!("Request Headers parsed successfully:");
-                println!("Method: {}", self.req.http_method);
-                println!("Path: {}", req.path.unwrap());
-                for header in req.headers {
-                    println!("Header: {} => {}", header.name, String::from_utf8_lossy(header.value));
+                self.req.meta = ProtoHttpReqMeta::from_raw(&req);
+                let meta = &self.req.meta;
+                if meta.headers.length_invalid() {
+                    println!("Invalid request Content-Length: {:?}", meta.headers.get_all("Content-Length").collect::<Vec<_>>());
+                    self.bad_length = true;
+                    return 0;
                 }
+                if meta.headers.last_chunked() == Some(false) {
+                    println!("Invalid request Transfer-Encoding: {:?}", meta.headers.get_all("Transfer-Encoding").collect::<Vec<_>>());
+                    self.bad_transfer = true;
+                    return 0;
+                }
+                let framing = Self::parse_framing(&meta.headers, meta.content_length, true);
+                self.req.body = ProtoHttpBody::new(framing);
                 self.req_seen_head_set(true);
                 self.req_seen_bytes_inc(data.len() as u64);
                 return header_end;
             }
+            Ok(Status::Partial) if data.len() > self.max_header_size => {
+                self.set_oversize(&format!("request header exceeds {} bytes", self.max_header_size));
+                return 0;
+            }
             Ok(Status::Partial) => {
-                println!("Incomplete request headers. Waiting for more data...");
+                // 数据不够，继续收包
                 return 0;
             }
             Err(e) => {
@@ -124,35 +1245,71 @@ impl ProtoHttpCtx {
     /*  解析响应头
      * 解析成功，返回解析到的字节数；
      * 如果解析失败，则返回0 且 设置http非法
+     * 如果头部数量或者长度超过限制，则返回0 且 设置oversize
      * 如果数据不够，则返回0
+     * Content-Length不合法时返回0 且 设置bad_length
+     * 严格模式下记录消息头的分帧问题，由调用者通过take_anomalies处理
      * */
     pub fn parse_http_resp_header(&mut self, data: &[u8]) -> usize {
-        self.req_seen_head_set(false);
-
-        // 定义存储 HTTP 头部的数组
-        let mut headers = [httparse::EMPTY_HEADER; 32];
-        let mut res = Response::new(&mut headers);
+        self.bad_length = false;
+        if self.strict {
+            if let Some(end) = Self::head_end(data) {
+                self.anomalies = Self::inspect_header(&data[..end], false);
+            }
+        }
+        let mut count = std::cmp::min(HTTP_HEADERS_INIT, self.max_headers);
+        loop {
+            // 定义存储 HTTP 头部的数组
+            let mut headers = vec![httparse::EMPTY_HEADER; count];
+            let mut res = Response::new(&mut headers);
+            match res.parse(data) {
+                Err(httparse::Error::TooManyHeaders) => match self.grow_headers(count) {
+                    Some(grow) => count = grow,
+                    None => {
+                        self.set_oversize(&format!("response has more than {} headers", self.max_headers));
+                        return 0;
+                    }
+                },
+                result => return self.parse_http_resp_result(result, res, data),
+            }
+        }
+    }
 
-        // 解析 Header
-        match res.parse(data) {
+    fn parse_http_resp_result(
+        &mut self,
+        result: httparse::Result<usize>,
+        res: Response,
+        data: &[u8],
+    ) -> usize {
+        match result {
+            Ok(Status::Complete(header_end)) if header_end > self.max_header_size => {
+                self.set_oversize(&format!("response header {} bytes", header_end));
+                return 0;
+            }
             Ok(Status::Complete(header_end)) => {
-                println!("Response Headers parsed successfully:");
-                self.resp.http_status_code = res.code.unwrap();
-                println!("Response Status: {}", self.resp.http_status_code);
-
-                for header in res.headers {
-                    println!(
-                        "Header: {} => {}",
-                        header.name,
-                        String::from_utf8_lossy(header.value)
-                    );
+                self.resp.meta = ProtoHttpRespMeta::from_raw(&res);
+                let meta = &self.resp.meta;
+                if meta.headers.length_invalid() {
+                    println!("Invalid response Content-Length: {:?}", meta.headers.get_all("Content-Length").collect::<Vec<_>>());
+                    self.bad_length = true;
+                    return 0;
                 }
+                let framing = if Self::resp_no_body(self.resp_method(), meta.code) {
+                    ProtoHttpFraming::NONE
+                } else {
+                    Self::parse_framing(&meta.headers, meta.content_length, false)
+                };
+                self.resp.body = ProtoHttpBody::new(framing);
                 self.resp_seen_head_set(true);
                 self.resp_seen_bytes_inc(data.len() as u64);
                 return header_end;
             }
+            Ok(Status::Partial) if data.len() > self.max_header_size => {
+                self.set_oversize(&format!("response header exceeds {} bytes", self.max_header_size));
+                return 0;
+            }
             Ok(Status::Partial) => {
-                println!("Incomplete headers. Waiting for more data...");
+                // 数据不够，继续收包
                 return 0;
             }
             Err(e) => {
@@ -163,3 +1320,160 @@ impl ProtoHttpCtx {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_header_without_colon() {
+        let head = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length\r\n\r\n";
+        assert!(ProtoHttpCtx::normalize_header(head, true).is_err());
+        let head = b"POST / HTTP/1.1\r\nContent-Length\r\n\r\n";
+        assert!(ProtoHttpCtx::normalize_header(head, true).is_err());
+    }
+
+    #[test]
+    fn content_length_values() {
+        let headers = |values: &[&str]| ProtoHttpHeaders {
+            list: values
+                .iter()
+                .map(|v| ProtoHttpHeader { name: "Content-Length".to_string(), value: v.to_string() })
+                .collect(),
+        };
+        assert_eq!(headers(&["5"]).content_length(), Some(5));
+        assert_eq!(headers(&["5, 5"]).content_length(), Some(5));
+        assert_eq!(headers(&["5", "5"]).content_length(), Some(5));
+        assert_eq!(headers(&["5, 6"]).content_length(), None);
+        assert_eq!(headers(&["5", "6"]).content_length(), None);
+        assert_eq!(headers(&["+5"]).content_length(), None);
+        assert_eq!(headers(&["abc"]).content_length(), None);
+        assert_eq!(headers(&[""]).content_length(), None);
+        assert!(headers(&["+5"]).length_invalid());
+        assert!(!headers(&[]).length_invalid());
+    }
+
+    #[test]
+    fn invalid_length_rejected_without_strict() {
+        let mut ctx = ProtoHttpCtx::new();
+        assert_eq!(ctx.parse_http_req_header(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: +5\r\n\r\n"), 0);
+        assert!(ctx.is_bad_length());
+        assert!(!ctx.req_seen_head());
+        let head = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5, 5\r\n\r\n";
+        assert_eq!(ctx.parse_http_req_header(head), head.len());
+        assert!(!ctx.is_bad_length());
+        assert!(ctx.req_seen_head());
+    }
+
+    #[test]
+    fn non_chunked_transfer_rejected_without_strict() {
+        let mut ctx = ProtoHttpCtx::new();
+        assert_eq!(ctx.parse_http_req_header(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n"), 0);
+        assert!(ctx.is_bad_transfer());
+        assert!(!ctx.req_seen_head());
+        let head = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked, gzip\r\n\r\n";
+        assert_eq!(ctx.parse_http_req_header(head), 0);
+        assert!(ctx.is_bad_transfer());
+        let head = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        assert_eq!(ctx.parse_http_req_header(head), head.len());
+        assert!(!ctx.is_bad_transfer());
+        assert!(ctx.req_seen_head());
+        // 响应读取到连接关闭为止，不拒绝
+        let head = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n";
+        assert_eq!(ctx.parse_http_resp_header(head), head.len());
+    }
+
+    #[test]
+    fn multipart_boundary_values() {
+        assert_eq!(ProtoHttpPart::multipart_boundary("multipart/form-data; boundary=abc").as_deref(), Some("abc"));
+        assert_eq!(ProtoHttpPart::multipart_boundary("Multipart/Mixed; charset=x; BOUNDARY=\"a;b c\"").as_deref(), Some("a;b c"));
+        assert!(ProtoHttpPart::multipart_boundary("text/plain; boundary=abc").is_none());
+        assert!(ProtoHttpPart::multipart_boundary("multipart/form-data").is_none());
+        assert!(ProtoHttpPart::multipart_boundary("multipart/form-data; boundary=\"\"").is_none());
+        assert!(ProtoHttpPart::multipart_boundary(&format!("multipart/form-data; boundary={}", "x".repeat(71))).is_none());
+    }
+
+    #[test]
+    fn multipart_parts() {
+        let body = b"preamble\r\n--XY \r\n\
+Content-Disposition: form-data; name=\"field\"\r\n\r\n\
+value\r\n--XY\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a;b.txt\"\r\n\
+Content-Type: application/octet-stream\r\n\r\n\
+file\r\ndata\r\n--XY\r\n\
+Content-Disposition: attachment; filename=\"plain.txt\"; filename*=UTF-8''%E4%B8%AD.txt\r\n\r\n\
+x\r\n--XY\r\n\r\n\
+no headers\r\n--XY--\r\nepilogue";
+        let parts = ProtoHttpPart::parse_multipart(body, "XY").unwrap();
+        assert_eq!(parts.len(), 4);
+        let content = |part: &ProtoHttpPart| &body[part.offset..part.offset + part.size];
+
+        assert_eq!(parts[0].name, "field");
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].content_type, "text/plain");
+        assert_eq!(content(&parts[0]), b"value");
+
+        assert_eq!(parts[1].name, "file");
+        assert_eq!(parts[1].filename.as_deref(), Some("a;b.txt"));
+        assert_eq!(parts[1].content_type, "application/octet-stream");
+        assert_eq!(content(&parts[1]), b"file\r\ndata");
+
+        // filename* 优先
+        assert_eq!(parts[2].filename.as_deref(), Some("中.txt"));
+        assert_eq!(parts[2].quoted_filename(), "%E4%B8%AD.txt");
+        assert_eq!(content(&parts[3]), b"no headers");
+    }
+
+    #[test]
+    fn multipart_malformed() {
+        assert!(ProtoHttpPart::parse_multipart(b"no delimiter", "XY").is_err());
+        assert!(ProtoHttpPart::parse_multipart(b"--XY\r\n\r\nunterminated", "XY").is_err());
+        assert!(ProtoHttpPart::parse_multipart(b"--XYjunk\r\n\r\nx\r\n--XY--", "XY").is_err());
+        assert!(ProtoHttpPart::parse_multipart(b"--XY\r\nbad header\r\n\r\nx\r\n--XY--", "XY").is_err());
+        assert_eq!(ProtoHttpPart::parse_multipart(b"--XY--\r\n", "XY").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn multipart_part_header() {
+        let part = ProtoHttpPart {
+            name: "file".to_string(),
+            filename: Some("a\"b.txt".to_string()),
+            content_type: "image/png".to_string(),
+            offset: 0,
+            size: 10,
+        };
+        let req_hdr = b"POST /up HTTP/1.1\r\nHost: a\r\nContent-Type: multipart/form-data; boundary=XY\r\nContent-Length: 300\r\n\r\n";
+        let header = String::from_utf8(part.build_part_header(req_hdr)).unwrap();
+        assert!(header.starts_with("POST /up HTTP/1.1\r\n"));
+        assert!(header.contains("Host: a\r\n"));
+        assert!(header.contains("Content-Length: 10\r\n"));
+        assert!(!header.contains("multipart"));
+        assert!(header.ends_with("Content-Type: image/png\r\nContent-Disposition: attachment; filename=\"a%22b.txt\"\r\n\r\n"));
+        assert_eq!(part.describe(), "name=\"file\"; filename=\"a%22b.txt\"; type=\"image/png\"; size=10");
+    }
+
+    #[test]
+    fn chunk_size_without_sign() {
+        let mut body = ProtoHttpBody::new(ProtoHttpFraming::CHUNKED);
+        assert!(body.parse_chunk_line(b"+5").is_err());
+        let mut body = ProtoHttpBody::new(ProtoHttpFraming::CHUNKED);
+        assert!(body.parse_chunk_line(b"5;ext=1").is_ok());
+    }
+
+    #[test]
+    fn chunked_trailers_kept() {
+        let data = b"3\r\nabc\r\n0\r\ngrpc-status: 0\r\nGrpc-Message: fine\r\n\r\n";
+        let mut body = ProtoHttpBody::new(ProtoHttpFraming::CHUNKED);
+        body.keep_trailers();
+        let mut decoded = Vec::new();
+        assert_eq!(body.feed(data, Some(&mut decoded)).unwrap(), data.len());
+        assert!(body.is_done());
+        assert_eq!(decoded, b"abc");
+        let trailers: Vec<(String, String)> = body.take_trailers().into_iter().map(|h| (h.name, h.value)).collect();
+        assert_eq!(trailers, [("grpc-status".to_string(), "0".to_string()), ("Grpc-Message".to_string(), "fine".to_string())]);
+        // 默认不保留
+        let mut body = ProtoHttpBody::new(ProtoHttpFraming::CHUNKED);
+        assert_eq!(body.feed(data, None).unwrap(), data.len());
+        assert!(body.take_trailers().is_empty());
+    }
+}
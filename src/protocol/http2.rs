@@ -0,0 +1,766 @@
+use crate::protocol::hpack::ProtoHpackField;
+use crate::protocol::http::{ProtoHttpCtx, ProtoHttpFraming, ProtoHttpHeader, ProtoHttpHeaders};
+use httparse::{Request, Response, Status};
+
+/* http client端发送的连接前言 */
+pub const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/* 帧类型 */
+pub const H2_FRAME_DATA: u8 = 0x0;
+pub const H2_FRAME_HEADERS: u8 = 0x1;
+pub const H2_FRAME_RST_STREAM: u8 = 0x3;
+pub const H2_FRAME_SETTINGS: u8 = 0x4;
+pub const H2_FRAME_PUSH_PROMISE: u8 = 0x5;
+pub const H2_FRAME_PING: u8 = 0x6;
+pub const H2_FRAME_GOAWAY: u8 = 0x7;
+pub const H2_FRAME_WINDOW_UPDATE: u8 = 0x8;
+pub const H2_FRAME_CONTINUATION: u8 = 0x9;
+
+/* 帧标志 */
+pub const H2_FLAG_END_STREAM: u8 = 0x1;
+pub const H2_FLAG_ACK: u8 = 0x1;
+pub const H2_FLAG_END_HEADERS: u8 = 0x4;
+pub const H2_FLAG_PADDED: u8 = 0x8;
+pub const H2_FLAG_PRIORITY: u8 = 0x20;
+
+/* SETTINGS参数 */
+pub const H2_SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const H2_SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const H2_SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const H2_SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+/* 错误码 */
+pub const H2_NO_ERROR: u32 = 0x0;
+pub const H2_PROTOCOL_ERROR: u32 = 0x1;
+pub const H2_INTERNAL_ERROR: u32 = 0x2;
+pub const H2_FLOW_CONTROL_ERROR: u32 = 0x3;
+pub const H2_FRAME_SIZE_ERROR: u32 = 0x6;
+pub const H2_REFUSED_STREAM: u32 = 0x7;
+pub const H2_CANCEL: u32 = 0x8;
+pub const H2_COMPRESSION_ERROR: u32 = 0x9;
+pub const H2_ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// 协议规定的初始窗口、帧大小与头部表大小
+pub const H2_DEFAULT_WINDOW: u32 = 65535;
+pub const H2_DEFAULT_FRAME_SIZE: usize = 16384;
+pub const H2_DEFAULT_TABLE_SIZE: usize = 4096;
+// 一个头部块(HEADERS + CONTINUATION)的最大字节数
+const H2_HEADER_BLOCK_MAX: usize = 256 * 1024;
+
+/* 连接级的http/2特有头部，转换为http/2时删除 */
+const H2_CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/* 一个帧，HEADERS与CONTINUATION已合并为完整的头部块 */
+pub struct ProtoH2Frame {
+    pub kind: u8,
+    pub flags: u8,
+    pub stream: u32,
+    pub payload: Vec<u8>,
+}
+
+/*
+* http/2单个方向的帧解析
+* server为true时先读取http client端的连接前言
+* HEADERS之后的CONTINUATION合并到HEADERS的payload中，去掉填充与优先级
+*/
+pub struct ProtoH2Ctx {
+    buffer: Vec<u8>,
+    preface: bool,
+    // 本端SETTINGS_MAX_FRAME_SIZE
+    max_frame: usize,
+    // 正在接收CONTINUATION的HEADERS帧
+    headers: Option<ProtoH2Frame>,
+}
+
+/* 从http/1.1消息头转换得到的http/2头部 */
+pub struct ProtoH2Message {
+    // 消息头的字节数
+    pub size: usize,
+    // 响应状态码，请求为0
+    pub code: u16,
+    pub fields: Vec<ProtoHpackField>,
+    pub framing: ProtoHttpFraming,
+}
+
+/* HEADERS头部块的种类: 请求头、响应头、trailers */
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum ProtoH2Section {
+    REQUEST,
+    RESPONSE,
+    TRAILERS,
+}
+
+/* 由http/2的HEADERS转换得到的http/1.1消息头 */
+pub struct ProtoH2Head {
+    pub head: Vec<u8>,
+    // 消息体使用chunked编码
+    pub chunked: bool,
+}
+
+impl ProtoH2Ctx {
+    pub fn new(server: bool) -> Self {
+        Self {
+            buffer: Vec::new(),
+            preface: server,
+            max_frame: H2_DEFAULT_FRAME_SIZE,
+            headers: None,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /*
+    * 取出下一个完整的帧，数据不够时返回None
+    * 格式错误返回Err，连接无法继续使用
+    */
+    pub fn next_frame(&mut self) -> Result<Option<ProtoH2Frame>, String> {
+        if self.preface {
+            let n = std::cmp::min(self.buffer.len(), H2_PREFACE.len());
+            if self.buffer[..n] != H2_PREFACE[..n] {
+                return Err("invalid HTTP/2 connection preface".to_string());
+            }
+            if n < H2_PREFACE.len() {
+                return Ok(None);
+            }
+            self.buffer.drain(..n);
+            self.preface = false;
+        }
+        loop {
+            if self.buffer.len() < 9 {
+                return Ok(None);
+            }
+            let len = (self.buffer[0] as usize) << 16 | (self.buffer[1] as usize) << 8 | self.buffer[2] as usize;
+            if len > self.max_frame {
+                return Err(format!("HTTP/2 frame of {} bytes exceeds {}", len, self.max_frame));
+            }
+            if self.buffer.len() < 9 + len {
+                return Ok(None);
+            }
+            let frame = ProtoH2Frame {
+                kind: self.buffer[3],
+                flags: self.buffer[4],
+                stream: u32::from_be_bytes([self.buffer[5], self.buffer[6], self.buffer[7], self.buffer[8]]) & 0x7fff_ffff,
+                payload: self.buffer[9..9 + len].to_vec(),
+            };
+            self.buffer.drain(..9 + len);
+            if let Some(frame) = self.merge_headers(frame)? {
+                return Ok(Some(frame));
+            }
+        }
+    }
+
+    /* 合并HEADERS(PUSH_PROMISE)与CONTINUATION，头部块完整时返回 */
+    fn merge_headers(&mut self, mut frame: ProtoH2Frame) -> Result<Option<ProtoH2Frame>, String> {
+        if let Some(mut headers) = self.headers.take() {
+            if frame.kind != H2_FRAME_CONTINUATION || frame.stream != headers.stream {
+                return Err("HTTP/2 header block interrupted".to_string());
+            }
+            headers.payload.extend_from_slice(&frame.payload);
+            if headers.payload.len() > H2_HEADER_BLOCK_MAX {
+                return Err("HTTP/2 header block too large".to_string());
+            }
+            if frame.flags & H2_FLAG_END_HEADERS == 0 {
+                self.headers = Some(headers);
+                return Ok(None);
+            }
+            return Ok(Some(headers));
+        }
+        match frame.kind {
+            H2_FRAME_CONTINUATION => return Err("HTTP/2 CONTINUATION without HEADERS".to_string()),
+            H2_FRAME_HEADERS | H2_FRAME_PUSH_PROMISE => {
+                if frame.stream == 0 {
+                    return Err("HTTP/2 HEADERS on stream 0".to_string());
+                }
+                frame.payload = Self::strip(&frame)?;
+                if frame.flags & H2_FLAG_END_HEADERS == 0 {
+                    self.headers = Some(frame);
+                    return Ok(None);
+                }
+                return Ok(Some(frame));
+            }
+            H2_FRAME_DATA => {
+                if frame.stream == 0 {
+                    return Err("HTTP/2 DATA on stream 0".to_string());
+                }
+                return Ok(Some(frame));
+            }
+            _ => return Ok(Some(frame)),
+        }
+    }
+
+    /* 去掉HEADERS的填充与优先级 */
+    fn strip(frame: &ProtoH2Frame) -> Result<Vec<u8>, String> {
+        let mut payload = &frame.payload[..];
+        if frame.flags & H2_FLAG_PADDED != 0 {
+            let pad = *payload.first().ok_or("HTTP/2 padding without length")? as usize;
+            if pad + 1 > payload.len() {
+                return Err("HTTP/2 padding exceeds frame".to_string());
+            }
+            payload = &payload[1..payload.len() - pad];
+        }
+        if frame.kind == H2_FRAME_HEADERS && frame.flags & H2_FLAG_PRIORITY != 0 {
+            payload = payload.get(5..).ok_or("HTTP/2 HEADERS priority truncated")?;
+        }
+        return Ok(payload.to_vec());
+    }
+
+    /* DATA帧的数据(去掉填充) */
+    pub fn data_payload(frame: &ProtoH2Frame) -> Result<&[u8], String> {
+        if frame.flags & H2_FLAG_PADDED == 0 {
+            return Ok(&frame.payload);
+        }
+        let pad = *frame.payload.first().ok_or("HTTP/2 padding without length")? as usize;
+        if pad + 1 > frame.payload.len() {
+            return Err("HTTP/2 padding exceeds frame".to_string());
+        }
+        return Ok(&frame.payload[1..frame.payload.len() - pad]);
+    }
+
+    /* SETTINGS的参数列表 */
+    pub fn settings(frame: &ProtoH2Frame) -> Result<Vec<(u16, u32)>, String> {
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err("invalid HTTP/2 SETTINGS length".to_string());
+        }
+        let settings = frame
+            .payload
+            .chunks(6)
+            .map(|s| (u16::from_be_bytes([s[0], s[1]]), u32::from_be_bytes([s[2], s[3], s[4], s[5]])))
+            .collect();
+        return Ok(settings);
+    }
+
+    /* WINDOW_UPDATE的增量，RST_STREAM的错误码，GOAWAY的最后一个流 */
+    pub fn payload_u32(frame: &ProtoH2Frame) -> Result<u32, String> {
+        let bytes = frame.payload.get(..4).ok_or("HTTP/2 frame payload truncated")?;
+        return Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) & 0x7fff_ffff);
+    }
+
+    pub fn build_frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let len = payload.len();
+        let mut frame = vec![(len >> 16) as u8, (len >> 8) as u8, len as u8, kind, flags];
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        return frame;
+    }
+
+    pub fn build_settings(settings: &[(u16, u32)]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for (id, value) in settings {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        return Self::build_frame(H2_FRAME_SETTINGS, 0, 0, &payload);
+    }
+
+    pub fn build_window_update(stream: u32, increment: u32) -> Vec<u8> {
+        return Self::build_frame(H2_FRAME_WINDOW_UPDATE, 0, stream, &increment.to_be_bytes());
+    }
+
+    pub fn build_rst_stream(stream: u32, code: u32) -> Vec<u8> {
+        return Self::build_frame(H2_FRAME_RST_STREAM, 0, stream, &code.to_be_bytes());
+    }
+
+    pub fn build_goaway(last_stream: u32, code: u32) -> Vec<u8> {
+        let mut payload = last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        return Self::build_frame(H2_FRAME_GOAWAY, 0, 0, &payload);
+    }
+
+    /* 头部块按max_frame拆分为HEADERS与CONTINUATION */
+    pub fn build_headers(stream: u32, block: &[u8], end_stream: bool, max_frame: usize) -> Vec<u8> {
+        let mut frames = Vec::new();
+        let mut chunks = block.chunks(max_frame).peekable();
+        let mut kind = H2_FRAME_HEADERS;
+        let mut flags = if end_stream { H2_FLAG_END_STREAM } else { 0 };
+        if chunks.peek().is_none() {
+            return Self::build_frame(kind, flags | H2_FLAG_END_HEADERS, stream, &[]);
+        }
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_none() {
+                flags |= H2_FLAG_END_HEADERS;
+            }
+            frames.extend(Self::build_frame(kind, flags, stream, chunk));
+            kind = H2_FRAME_CONTINUATION;
+            flags = 0;
+        }
+        return frames;
+    }
+
+    /*
+    * http/2请求头转换为http/1.1请求头
+    * :authority转换为Host，多个cookie合并为一个
+    * 没有结束流且没有content-length时使用chunked编码
+    */
+    pub fn build_h1_request(fields: &[ProtoHpackField], end_stream: bool) -> Result<ProtoH2Head, String> {
+        let pseudo = |name: &str| fields.iter().find(|(n, _)| n == name.as_bytes()).map(|(_, v)| String::from_utf8_lossy(v).to_string());
+        let method = pseudo(":method").ok_or("HTTP/2 request without :method")?;
+        let path = pseudo(":path").unwrap_or_else(|| "/".to_string());
+        let mut head = format!("{} {} HTTP/1.1\r\n", method, path).into_bytes();
+        let has_host = fields.iter().any(|(n, _)| n == b"host");
+        if let (Some(authority), false) = (pseudo(":authority"), has_host) {
+            head.extend_from_slice(format!("Host: {}\r\n", authority).as_bytes());
+        }
+        let cookies: Vec<&[u8]> = fields.iter().filter(|(n, _)| n == b"cookie").map(|(_, v)| v.as_slice()).collect();
+        if !cookies.is_empty() {
+            head.extend_from_slice(b"Cookie: ");
+            head.extend_from_slice(&cookies.join(&b"; "[..]));
+            head.extend_from_slice(b"\r\n");
+        }
+        let chunked = Self::build_h1_fields(&mut head, fields, end_stream);
+        return Ok(ProtoH2Head { head, chunked });
+    }
+
+    /*
+    * http/2响应头转换为http/1.1响应头
+    * 结束流的响应补上Content-Length: 0，避免被当作读取到连接关闭的消息体
+    */
+    pub fn build_h1_response(fields: &[ProtoHpackField], end_stream: bool, method: &str) -> Result<ProtoH2Head, String> {
+        let status = fields.iter().find(|(n, _)| n == b":status").ok_or("HTTP/2 response without :status")?;
+        let code: u16 = std::str::from_utf8(&status.1).ok().and_then(|s| s.parse().ok()).ok_or("invalid HTTP/2 :status")?;
+        let mut head = format!("HTTP/1.1 {} {}\r\n", code, Self::reason(code)).into_bytes();
+        let no_body = ProtoHttpCtx::resp_no_body(method, code);
+        let has_length = fields.iter().any(|(n, _)| n == b"content-length");
+        if end_stream && !no_body && !has_length {
+            head.extend_from_slice(b"Content-Length: 0\r\n");
+        }
+        let chunked = Self::build_h1_fields(&mut head, fields, end_stream || no_body);
+        return Ok(ProtoH2Head { head, chunked });
+    }
+
+    /* 追加普通头部与消息头结尾，返回消息体是否使用chunked编码 */
+    fn build_h1_fields(head: &mut Vec<u8>, fields: &[ProtoHpackField], no_body: bool) -> bool {
+        for (name, value) in fields {
+            if name.starts_with(b":") || name == b"cookie" {
+                continue;
+            }
+            head.extend_from_slice(name);
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
+        }
+        let chunked = !no_body && !fields.iter().any(|(n, _)| n == b"content-length");
+        if chunked {
+            head.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        return chunked;
+    }
+
+    /* 一个chunk，空数据表示最后一个chunk */
+    pub fn build_chunk(data: &[u8]) -> Vec<u8> {
+        if data.is_empty() {
+            return b"0\r\n\r\n".to_vec();
+        }
+        let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(b"\r\n");
+        return chunk;
+    }
+
+    /* 带trailers的最后一个chunk，trailers为http/2的trailers */
+    pub fn build_last_chunk(trailers: &[ProtoHpackField]) -> Vec<u8> {
+        let mut chunk = b"0\r\n".to_vec();
+        for (name, value) in trailers {
+            chunk.extend_from_slice(name);
+            chunk.extend_from_slice(b": ");
+            chunk.extend_from_slice(value);
+            chunk.extend_from_slice(b"\r\n");
+        }
+        chunk.extend_from_slice(b"\r\n");
+        return chunk;
+    }
+
+    /* http/1.1 chunked消息体的trailers转换为http/2的trailers，去掉不允许出现在http/2中的字段 */
+    pub fn h2_trailers(trailers: &[ProtoHttpHeader]) -> Vec<ProtoHpackField> {
+        return trailers
+            .iter()
+            .map(|h| (h.name.to_ascii_lowercase().into_bytes(), h.value.as_bytes().to_vec()))
+            .filter(|field| Self::check_fields(std::slice::from_ref(field), ProtoH2Section::TRAILERS).is_ok())
+            .collect();
+    }
+
+    /*
+    * 解析http/1.1请求头，转换为http/2头部
+    * 数据不够时返回None
+    */
+    pub fn parse_h1_request(data: &[u8], max_headers: usize) -> Result<Option<ProtoH2Message>, String> {
+        let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+        let mut req = Request::new(&mut headers);
+        let end = match req.parse(data).map_err(|e| format!("invalid HTTP/1.1 request: {}", e))? {
+            Status::Complete(end) => end,
+            Status::Partial => return Ok(None),
+        };
+        let parsed = ProtoHttpHeaders::from_raw(req.headers);
+        let framing = ProtoHttpCtx::parse_framing(&parsed, parsed.content_length(), true);
+        let authority = parsed.get("Host").unwrap_or("");
+        let mut fields = vec![
+            (b":method".to_vec(), req.method.unwrap_or("GET").as_bytes().to_vec()),
+            (b":scheme".to_vec(), b"https".to_vec()),
+            (b":authority".to_vec(), authority.as_bytes().to_vec()),
+            (b":path".to_vec(), req.path.unwrap_or("/").as_bytes().to_vec()),
+        ];
+        fields.extend(Self::h2_fields(req.headers, true));
+        return Ok(Some(ProtoH2Message {
+            size: end,
+            code: 0,
+            fields,
+            framing,
+        }));
+    }
+
+    /* 解析http/1.1响应头，转换为http/2头部; method为对应请求的方法 */
+    pub fn parse_h1_response(data: &[u8], max_headers: usize, method: &str) -> Result<Option<ProtoH2Message>, String> {
+        let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+        let mut res = Response::new(&mut headers);
+        let end = match res.parse(data).map_err(|e| format!("invalid HTTP/1.1 response: {}", e))? {
+            Status::Complete(end) => end,
+            Status::Partial => return Ok(None),
+        };
+        let code = res.code.unwrap_or(0);
+        let parsed = ProtoHttpHeaders::from_raw(res.headers);
+        let framing = match ProtoHttpCtx::resp_no_body(method, code) {
+            true => ProtoHttpFraming::NONE,
+            false => ProtoHttpCtx::parse_framing(&parsed, parsed.content_length(), false),
+        };
+        let mut fields = vec![(b":status".to_vec(), code.to_string().into_bytes())];
+        fields.extend(Self::h2_fields(res.headers, false));
+        return Ok(Some(ProtoH2Message {
+            size: end,
+            code,
+            fields,
+            framing,
+        }));
+    }
+
+    /* 名称转为小写，去掉连接级头部与Connection中列出的头部; 请求的Host已转换为:authority */
+    fn h2_fields(headers: &[httparse::Header], request: bool) -> Vec<ProtoHpackField> {
+        let listed: Vec<String> = headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case("connection"))
+            .flat_map(|h| String::from_utf8_lossy(h.value).split(',').map(|v| v.trim().to_ascii_lowercase()).collect::<Vec<_>>())
+            .collect();
+        let mut fields = Vec::new();
+        for header in headers {
+            let name = header.name.to_ascii_lowercase();
+            if H2_CONNECTION_HEADERS.contains(&name.as_str()) || listed.contains(&name) || (request && name == "host") {
+                continue;
+            }
+            // TE只允许trailers
+            if name == "te" && !header.value.eq_ignore_ascii_case(b"trailers") {
+                continue;
+            }
+            fields.push((name.into_bytes(), header.value.to_vec()));
+        }
+        return fields;
+    }
+
+    /*
+    * 检查解码后的头部是否合法(RFC 9113 8.2.1、8.3)，不合法的流以PROTOCOL_ERROR复位
+    * 1. 名称只能是小写的token字符，值不能包含NUL、CR、LF，首尾不能是空白
+    * 2. 伪头部只能出现在普通头部之前，不能重复，且必须是该种类允许的伪头部
+    * 3. 不能有连接级头部，TE只允许trailers
+    * 4. 请求必须有:method、:scheme与:path，响应必须有三位数字的:status
+    * 转换为http/1.1时原样写入请求行与头部，因此不合法的头部可能被用于请求走私
+    */
+    pub fn check_fields(fields: &[ProtoHpackField], section: ProtoH2Section) -> Result<(), String> {
+        let mut regular = false;
+        let mut pseudos: Vec<&[u8]> = Vec::new();
+        for (name, value) in fields {
+            let lossy = String::from_utf8_lossy(name);
+            let blank = |b: Option<&u8>| b.is_some_and(|b| *b == b' ' || *b == b'\t');
+            if value.iter().any(|b| matches!(b, 0 | b'\r' | b'\n')) || blank(value.first()) || blank(value.last()) {
+                return Err(format!("invalid value of {}", lossy));
+            }
+            if let Some(pseudo) = name.strip_prefix(b":") {
+                let allowed: &[&[u8]] = match section {
+                    ProtoH2Section::REQUEST => &[b"method", b"scheme", b"authority", b"path"],
+                    ProtoH2Section::RESPONSE => &[b"status"],
+                    ProtoH2Section::TRAILERS => &[],
+                };
+                if regular || !allowed.contains(&pseudo) || pseudos.contains(&pseudo) {
+                    return Err(format!("unexpected pseudo-header {}", lossy));
+                }
+                pseudos.push(pseudo);
+                continue;
+            }
+            regular = true;
+            if name.is_empty() || !name.iter().all(|b| Self::token_char(*b) && !b.is_ascii_uppercase()) {
+                return Err(format!("invalid field name {:?}", lossy));
+            }
+            if H2_CONNECTION_HEADERS.iter().any(|h| h.as_bytes() == name.as_slice()) || (name == b"te" && value != b"trailers") {
+                return Err(format!("connection-specific field {}", lossy));
+            }
+        }
+        let pseudo = |name: &str| fields.iter().find(|(n, _)| n == name.as_bytes()).map(|(_, v)| v.as_slice());
+        match section {
+            ProtoH2Section::REQUEST => {
+                let method = pseudo(":method").ok_or("request without :method")?;
+                if method.is_empty() || !method.iter().all(|b| Self::token_char(*b)) {
+                    return Err("invalid :method".to_string());
+                }
+                // CONNECT没有:scheme与:path，不转换为http/1.1
+                if method == b"CONNECT" {
+                    return Ok(());
+                }
+                let path = pseudo(":path").ok_or("request without :path")?;
+                pseudo(":scheme").ok_or("request without :scheme")?;
+                let origin = path.first() == Some(&b'/') || (path == b"*" && method == b"OPTIONS");
+                if !origin || !path.iter().all(|b| b.is_ascii_graphic()) {
+                    return Err("invalid :path".to_string());
+                }
+                if !pseudo(":authority").unwrap_or(b"").iter().all(|b| b.is_ascii_graphic()) {
+                    return Err("invalid :authority".to_string());
+                }
+            }
+            ProtoH2Section::RESPONSE => {
+                let status = pseudo(":status").ok_or("response without :status")?;
+                if status.len() != 3 || !status.iter().all(|b| b.is_ascii_digit()) {
+                    return Err("invalid :status".to_string());
+                }
+            }
+            ProtoH2Section::TRAILERS => {}
+        }
+        return Ok(());
+    }
+
+    /* RFC 9110 token */
+    fn token_char(b: u8) -> bool {
+        return b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+    }
+
+    fn reason(code: u16) -> &'static str {
+        match code {
+            100 => return "Continue",
+            200 => return "OK",
+            201 => return "Created",
+            204 => return "No Content",
+            206 => return "Partial Content",
+            301 => return "Moved Permanently",
+            302 => return "Found",
+            304 => return "Not Modified",
+            400 => return "Bad Request",
+            401 => return "Unauthorized",
+            403 => return "Forbidden",
+            404 => return "Not Found",
+            500 => return "Internal Server Error",
+            502 => return "Bad Gateway",
+            503 => return "Service Unavailable",
+            _ => return "",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(list: &[(&str, &str)]) -> Vec<ProtoHpackField> {
+        return list.iter().map(|(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec())).collect();
+    }
+
+    #[test]
+    fn next_frame_preface_and_partial() {
+        let mut ctx = ProtoH2Ctx::new(true);
+        ctx.feed(&H2_PREFACE[..10]);
+        assert!(ctx.next_frame().unwrap().is_none());
+        ctx.feed(&H2_PREFACE[10..]);
+        let settings = ProtoH2Ctx::build_settings(&[(H2_SETTINGS_MAX_FRAME_SIZE, 32768)]);
+        ctx.feed(&settings[..12]);
+        assert!(ctx.next_frame().unwrap().is_none());
+        ctx.feed(&settings[12..]);
+        let frame = ctx.next_frame().unwrap().unwrap();
+        assert_eq!(ProtoH2Ctx::settings(&frame).unwrap(), vec![(H2_SETTINGS_MAX_FRAME_SIZE, 32768)]);
+
+        let mut ctx = ProtoH2Ctx::new(true);
+        ctx.feed(b"GET / HTTP/1.1\r\n\r\n");
+        assert!(ctx.next_frame().is_err());
+    }
+
+    #[test]
+    fn next_frame_merges_continuation() {
+        let mut ctx = ProtoH2Ctx::new(false);
+        let frames = ProtoH2Ctx::build_headers(3, b"abcdefgh", true, 3);
+        // HEADERS + 2个CONTINUATION
+        assert_eq!(frames.len(), 3 * 9 + 8);
+        ctx.feed(&frames);
+        ctx.feed(&ProtoH2Ctx::build_frame(H2_FRAME_PING, 0, 0, &[0; 8]));
+        let frame = ctx.next_frame().unwrap().unwrap();
+        assert_eq!((frame.kind, frame.stream, frame.payload.as_slice()), (H2_FRAME_HEADERS, 3, &b"abcdefgh"[..]));
+        assert_eq!(frame.flags & H2_FLAG_END_STREAM, H2_FLAG_END_STREAM);
+        assert_eq!(ctx.next_frame().unwrap().unwrap().kind, H2_FRAME_PING);
+        assert!(ctx.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn next_frame_rejects_interrupted_header_block() {
+        // CONTINUATION之前出现其他帧
+        let mut ctx = ProtoH2Ctx::new(false);
+        ctx.feed(&ProtoH2Ctx::build_frame(H2_FRAME_HEADERS, 0, 1, b"abc"));
+        ctx.feed(&ProtoH2Ctx::build_frame(H2_FRAME_PING, 0, 0, &[0; 8]));
+        assert!(ctx.next_frame().is_err());
+        // CONTINUATION属于其他流
+        let mut ctx = ProtoH2Ctx::new(false);
+        ctx.feed(&ProtoH2Ctx::build_frame(H2_FRAME_HEADERS, 0, 1, b"abc"));
+        ctx.feed(&ProtoH2Ctx::build_frame(H2_FRAME_CONTINUATION, H2_FLAG_END_HEADERS, 3, b"def"));
+        assert!(ctx.next_frame().is_err());
+        // 没有HEADERS的CONTINUATION
+        let mut ctx = ProtoH2Ctx::new(false);
+        ctx.feed(&ProtoH2Ctx::build_frame(H2_FRAME_CONTINUATION, H2_FLAG_END_HEADERS, 1, b"def"));
+        assert!(ctx.next_frame().is_err());
+        // 流0上的HEADERS
+        let mut ctx = ProtoH2Ctx::new(false);
+        ctx.feed(&ProtoH2Ctx::build_frame(H2_FRAME_HEADERS, H2_FLAG_END_HEADERS, 0, b"abc"));
+        assert!(ctx.next_frame().is_err());
+        // 超过最大帧大小
+        let mut ctx = ProtoH2Ctx::new(false);
+        ctx.feed(&ProtoH2Ctx::build_frame(H2_FRAME_DATA, 0, 1, &vec![0; H2_DEFAULT_FRAME_SIZE + 1]));
+        assert!(ctx.next_frame().is_err());
+    }
+
+    #[test]
+    fn next_frame_strips_padding_and_priority() {
+        let mut ctx = ProtoH2Ctx::new(false);
+        // 填充长度2，优先级5字节，头部块abc，填充2字节
+        let payload = [&[2u8][..], &[0, 0, 0, 1, 16], b"abc", &[0, 0]].concat();
+        let flags = H2_FLAG_END_HEADERS | H2_FLAG_PADDED | H2_FLAG_PRIORITY;
+        ctx.feed(&ProtoH2Ctx::build_frame(H2_FRAME_HEADERS, flags, 1, &payload));
+        assert_eq!(ctx.next_frame().unwrap().unwrap().payload, b"abc");
+        // 只有优先级
+        ctx.feed(&ProtoH2Ctx::build_frame(H2_FRAME_HEADERS, H2_FLAG_END_HEADERS | H2_FLAG_PRIORITY, 3, &[0, 0, 0, 0, 1, b'x']));
+        assert_eq!(ctx.next_frame().unwrap().unwrap().payload, b"x");
+        // 填充超过帧长度
+        ctx.feed(&ProtoH2Ctx::build_frame(H2_FRAME_HEADERS, H2_FLAG_END_HEADERS | H2_FLAG_PADDED, 5, &[4, b'x']));
+        assert!(ctx.next_frame().is_err());
+        // 优先级不完整
+        let mut ctx = ProtoH2Ctx::new(false);
+        ctx.feed(&ProtoH2Ctx::build_frame(H2_FRAME_HEADERS, H2_FLAG_END_HEADERS | H2_FLAG_PRIORITY, 1, &[0, 0, 0]));
+        assert!(ctx.next_frame().is_err());
+        // DATA的填充
+        let data = ProtoH2Frame {
+            kind: H2_FRAME_DATA,
+            flags: H2_FLAG_PADDED,
+            stream: 1,
+            payload: vec![1, b'h', b'i', 0],
+        };
+        assert_eq!(ProtoH2Ctx::data_payload(&data).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn h2_request_to_h1() {
+        let list = [
+            (":method", "POST"),
+            (":scheme", "https"),
+            (":authority", "a.test"),
+            (":path", "/upload?x=1"),
+            ("cookie", "a=1"),
+            ("content-type", "text/plain"),
+            ("cookie", "b=2"),
+        ];
+        let head = ProtoH2Ctx::build_h1_request(&fields(&list), false).unwrap();
+        let expect = "POST /upload?x=1 HTTP/1.1\r\nHost: a.test\r\nCookie: a=1; b=2\r\ncontent-type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(String::from_utf8_lossy(&head.head), expect);
+        assert!(head.chunked);
+
+        let list = [(":method", "GET"), (":scheme", "https"), (":authority", "a.test"), (":path", "/"), ("host", "b.test")];
+        let head = ProtoH2Ctx::build_h1_request(&fields(&list), true).unwrap();
+        assert_eq!(String::from_utf8_lossy(&head.head), "GET / HTTP/1.1\r\nhost: b.test\r\n\r\n");
+        assert!(!head.chunked);
+    }
+
+    #[test]
+    fn h2_response_to_h1() {
+        let head = ProtoH2Ctx::build_h1_response(&fields(&[(":status", "200"), ("content-length", "5")]), false, "GET").unwrap();
+        assert_eq!(String::from_utf8_lossy(&head.head), "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n");
+        assert!(!head.chunked);
+        // 结束流且没有content-length
+        let head = ProtoH2Ctx::build_h1_response(&fields(&[(":status", "200")]), true, "GET").unwrap();
+        assert_eq!(String::from_utf8_lossy(&head.head), "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        // 没有消息体的响应不使用chunked
+        let head = ProtoH2Ctx::build_h1_response(&fields(&[(":status", "304")]), false, "GET").unwrap();
+        assert!(!head.chunked);
+        let head = ProtoH2Ctx::build_h1_response(&fields(&[(":status", "200")]), false, "HEAD").unwrap();
+        assert!(!head.chunked);
+        let head = ProtoH2Ctx::build_h1_response(&fields(&[(":status", "200")]), false, "GET").unwrap();
+        assert!(head.chunked);
+        assert!(ProtoH2Ctx::build_h1_response(&fields(&[(":status", "abc")]), true, "GET").is_err());
+    }
+
+    #[test]
+    fn h1_request_to_h2() {
+        let data = b"POST /a HTTP/1.1\r\nHost: a.test\r\nConnection: keep-alive, X-Hop\r\nX-Hop: 1\r\nTE: gzip\r\nContent-Length: 3\r\nAccept: */*\r\n\r\nabc";
+        assert!(ProtoH2Ctx::parse_h1_request(&data[..20], 16).unwrap().is_none());
+        let message = ProtoH2Ctx::parse_h1_request(data, 16).unwrap().unwrap();
+        assert_eq!(message.size, data.len() - 3);
+        assert_eq!(message.framing, ProtoHttpFraming::LENGTH(3));
+        let expect = [
+            (":method", "POST"),
+            (":scheme", "https"),
+            (":authority", "a.test"),
+            (":path", "/a"),
+            ("content-length", "3"),
+            ("accept", "*/*"),
+        ];
+        assert_eq!(message.fields, fields(&expect));
+        assert!(ProtoH2Ctx::check_fields(&message.fields, ProtoH2Section::REQUEST).is_ok());
+    }
+
+    #[test]
+    fn h1_response_to_h2() {
+        let data = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTE: trailers\r\nServer: x\r\n\r\n";
+        let message = ProtoH2Ctx::parse_h1_response(data, 16, "GET").unwrap().unwrap();
+        assert_eq!((message.code, message.framing), (200, ProtoHttpFraming::CHUNKED));
+        assert_eq!(message.fields, fields(&[(":status", "200"), ("te", "trailers"), ("server", "x")]));
+        let message = ProtoH2Ctx::parse_h1_response(data, 16, "HEAD").unwrap().unwrap();
+        assert_eq!(message.framing, ProtoHttpFraming::NONE);
+    }
+
+    #[test]
+    fn h1_trailers_to_h2() {
+        let trailers = vec![
+            ProtoHttpHeader {
+                name: "Grpc-Status".to_string(),
+                value: "0".to_string(),
+            },
+            ProtoHttpHeader {
+                name: "Transfer-Encoding".to_string(),
+                value: "chunked".to_string(),
+            },
+        ];
+        let fields = ProtoH2Ctx::h2_trailers(&trailers);
+        assert_eq!(fields, vec![(b"grpc-status".to_vec(), b"0".to_vec())]);
+        assert_eq!(ProtoH2Ctx::build_last_chunk(&fields), b"0\r\ngrpc-status: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn check_request_fields() {
+        let ok = [(":method", "GET"), (":scheme", "https"), (":authority", "a.test"), (":path", "/x"), ("accept", "*/*")];
+        assert!(ProtoH2Ctx::check_fields(&fields(&ok), ProtoH2Section::REQUEST).is_ok());
+        let bad: [&[(&str, &str)]; 10] = [
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/x"), ("x", "a\r\nb: c")],
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/x"), ("x", "a\0")],
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/x"), ("X-Upper", "a")],
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/x"), ("x:y", "a")],
+            &[(":method", "GET"), (":scheme", "https"), ("accept", "*/*"), (":path", "/x")],
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/x"), (":path", "/y")],
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/x HTTP/1.1")],
+            &[(":method", "GET"), (":scheme", "https")],
+            &[(":method", "G T"), (":scheme", "https"), (":path", "/x")],
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/x"), ("transfer-encoding", "chunked")],
+        ];
+        for list in bad {
+            assert!(ProtoH2Ctx::check_fields(&fields(list), ProtoH2Section::REQUEST).is_err(), "{:?}", list);
+        }
+    }
+
+    #[test]
+    fn check_response_and_trailer_fields() {
+        assert!(ProtoH2Ctx::check_fields(&fields(&[(":status", "200"), ("server", "x")]), ProtoH2Section::RESPONSE).is_ok());
+        assert!(ProtoH2Ctx::check_fields(&fields(&[(":status", "20")]), ProtoH2Section::RESPONSE).is_err());
+        assert!(ProtoH2Ctx::check_fields(&fields(&[(":status", "200"), (":path", "/")]), ProtoH2Section::RESPONSE).is_err());
+        assert!(ProtoH2Ctx::check_fields(&fields(&[("grpc-status", "0")]), ProtoH2Section::TRAILERS).is_ok());
+        assert!(ProtoH2Ctx::check_fields(&fields(&[(":status", "200")]), ProtoH2Section::TRAILERS).is_err());
+    }
+}
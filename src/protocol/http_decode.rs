@@ -0,0 +1,308 @@
+use std::io::Write;
+
+/* 解压后的大小与压缩率限制，防止解压炸弹 */
+#[derive(Clone, Copy)]
+pub struct ProtoHttpDecodeLimit {
+    // 解压后的最大字节数
+    pub max_size: usize,
+    // 解压后与解压前的最大比例
+    pub max_ratio: usize,
+}
+
+// 解压后小于该大小时不检查压缩率，避免小的高压缩率消息被误判
+const HTTP_DECODE_RATIO_MIN: usize = 1024 * 1024;
+
+/* 解压输出，超过大小限制时写入失败 */
+struct ProtoHttpDecodeSink {
+    out: Vec<u8>,
+    max_size: usize,
+    // 超过了大小限制，与格式错误区分
+    overflow: bool,
+}
+
+impl Write for ProtoHttpDecodeSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.out.len() + buf.len() > self.max_size {
+            self.overflow = true;
+            return Err(std::io::Error::other(format!("decoded body exceeds {} bytes", self.max_size)));
+        }
+        self.out.extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+enum ProtoHttpDecodeStream {
+    GZIP(flate2::write::GzDecoder<ProtoHttpDecodeSink>),
+    ZLIB(flate2::write::ZlibDecoder<ProtoHttpDecodeSink>),
+    DEFLATE(flate2::write::DeflateDecoder<ProtoHttpDecodeSink>),
+    BR(Box<brotli_decompressor::DecompressorWriter<ProtoHttpDecodeSink>>),
+    // 使用zio::Writer，结束时可以检查最后一个frame是否完整
+    ZSTD(zstd::stream::zio::Writer<ProtoHttpDecodeSink, zstd::stream::raw::Decoder<'static>>),
+}
+
+/* Content-Encoding中支持的编码 */
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+enum ProtoHttpCoding {
+    GZIP,
+    DEFLATE,
+    BR,
+    ZSTD,
+}
+
+/*
+* 流式解压Content-Encoding编码的消息体
+* 输入去掉chunked编码之后的消息体，输出解压后的内容，发送给icap server扫描
+* 原始的消息体不受影响，仍然原样发送给对端
+*/
+pub struct ProtoHttpDecoder {
+    coding: ProtoHttpCoding,
+    // deflate在收到前两个字节后才能判断是zlib格式还是raw deflate
+    stream: Option<ProtoHttpDecodeStream>,
+    pending: Vec<u8>,
+    limit: ProtoHttpDecodeLimit,
+    input: usize,
+    done: bool,
+    error: Option<String>,
+}
+
+impl ProtoHttpDecoder {
+    /*
+    * 根据Content-Encoding创建解压器
+    * 没有编码或者identity返回None; 不支持的编码或者多重编码同样返回None，消息体原样扫描
+    */
+    pub fn new(content_encoding: &str, limit: ProtoHttpDecodeLimit) -> Option<Self> {
+        let codings: Vec<String> = content_encoding
+            .split(',')
+            .map(|c| c.trim().to_ascii_lowercase())
+            .filter(|c| !c.is_empty() && c != "identity")
+            .collect();
+        if codings.len() != 1 {
+            if codings.len() > 1 {
+                println!("Content-Encoding {} not decoded: multiple codings", content_encoding);
+            }
+            return None;
+        }
+        let coding = match codings[0].as_str() {
+            "gzip" | "x-gzip" => ProtoHttpCoding::GZIP,
+            "deflate" => ProtoHttpCoding::DEFLATE,
+            "br" => ProtoHttpCoding::BR,
+            "zstd" => ProtoHttpCoding::ZSTD,
+            other => {
+                println!("Content-Encoding {} not supported", other);
+                return None;
+            }
+        };
+        let mut decoder = Self {
+            coding,
+            stream: None,
+            pending: Vec::new(),
+            limit,
+            input: 0,
+            done: false,
+            error: None,
+        };
+        if coding != ProtoHttpCoding::DEFLATE {
+            decoder.stream = decoder.open_stream(&[]);
+        }
+        return Some(decoder);
+    }
+
+    fn sink(&self) -> ProtoHttpDecodeSink {
+        return ProtoHttpDecodeSink {
+            out: Vec::new(),
+            max_size: self.limit.max_size,
+            overflow: false,
+        };
+    }
+
+    /* head为deflate数据的前两个字节，用于判断是否带有zlib头 */
+    fn open_stream(&mut self, head: &[u8]) -> Option<ProtoHttpDecodeStream> {
+        let sink = self.sink();
+        match self.coding {
+            ProtoHttpCoding::GZIP => return Some(ProtoHttpDecodeStream::GZIP(flate2::write::GzDecoder::new(sink))),
+            ProtoHttpCoding::DEFLATE => {
+                // zlib头: CM为8，且 (CMF * 256 + FLG) 是31的倍数
+                let zlib = head.len() >= 2 && head[0] & 0x0f == 8 && (head[0] as u16 * 256 + head[1] as u16).is_multiple_of(31);
+                if zlib {
+                    return Some(ProtoHttpDecodeStream::ZLIB(flate2::write::ZlibDecoder::new(sink)));
+                }
+                return Some(ProtoHttpDecodeStream::DEFLATE(flate2::write::DeflateDecoder::new(sink)));
+            }
+            ProtoHttpCoding::BR => {
+                return Some(ProtoHttpDecodeStream::BR(Box::new(brotli_decompressor::DecompressorWriter::new(sink, 4096))))
+            }
+            ProtoHttpCoding::ZSTD => match zstd::stream::raw::Decoder::new() {
+                Ok(decoder) => return Some(ProtoHttpDecodeStream::ZSTD(zstd::stream::zio::Writer::new(sink, decoder))),
+                Err(e) => {
+                    self.error = Some(format!("zstd init failed: {}", e));
+                    return None;
+                }
+            },
+        }
+    }
+
+    fn stream_sink(&self) -> Option<&ProtoHttpDecodeSink> {
+        match self.stream.as_ref()? {
+            ProtoHttpDecodeStream::GZIP(s) => return Some(s.get_ref()),
+            ProtoHttpDecodeStream::ZLIB(s) => return Some(s.get_ref()),
+            ProtoHttpDecodeStream::DEFLATE(s) => return Some(s.get_ref()),
+            ProtoHttpDecodeStream::BR(s) => return Some(s.get_ref()),
+            ProtoHttpDecodeStream::ZSTD(s) => return Some(s.writer()),
+        }
+    }
+
+    fn stream_write(stream: &mut ProtoHttpDecodeStream, data: &[u8]) -> std::io::Result<()> {
+        match stream {
+            ProtoHttpDecodeStream::GZIP(s) => s.write_all(data).and_then(|_| s.flush()),
+            ProtoHttpDecodeStream::ZLIB(s) => s.write_all(data).and_then(|_| s.flush()),
+            ProtoHttpDecodeStream::DEFLATE(s) => s.write_all(data).and_then(|_| s.flush()),
+            ProtoHttpDecodeStream::BR(s) => s.write_all(data).and_then(|_| s.flush()),
+            ProtoHttpDecodeStream::ZSTD(s) => s.write_all(data).and_then(|_| s.flush()),
+        }
+    }
+
+    /* 数据不完整(被截断)时返回错误 */
+    fn stream_finish(stream: &mut ProtoHttpDecodeStream) -> std::io::Result<()> {
+        match stream {
+            ProtoHttpDecodeStream::GZIP(s) => s.try_finish(),
+            ProtoHttpDecodeStream::ZLIB(s) => s.try_finish(),
+            ProtoHttpDecodeStream::DEFLATE(s) => s.try_finish(),
+            ProtoHttpDecodeStream::BR(s) => s.close(),
+            ProtoHttpDecodeStream::ZSTD(s) => s.finish(),
+        }
+    }
+
+    /*
+    * 输入一段去掉chunked编码之后的消息体
+    * 解压失败或者超过限制时记录错误，之后的数据不再解压
+    */
+    pub fn feed(&mut self, data: &[u8]) {
+        if self.error.is_some() || self.done || data.is_empty() {
+            return;
+        }
+        self.input += data.len();
+        if self.stream.is_none() {
+            // deflate: 等待前两个字节
+            self.pending.extend_from_slice(data);
+            if self.pending.len() < 2 {
+                return;
+            }
+            let head = std::mem::take(&mut self.pending);
+            self.stream = self.open_stream(&head);
+            self.write(&head);
+            return;
+        }
+        self.write(data);
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+        if let Err(e) = Self::stream_write(stream, data) {
+            self.error = Some(format!("decode failed: {}", e));
+            return;
+        }
+        self.check_ratio();
+    }
+
+    /* 消息体结束 */
+    pub fn finish(&mut self) {
+        if self.error.is_some() || self.done {
+            return;
+        }
+        self.done = true;
+        if self.stream.is_none() && !self.pending.is_empty() {
+            let head = std::mem::take(&mut self.pending);
+            self.stream = self.open_stream(&head);
+            self.write(&head);
+            if self.error.is_some() {
+                return;
+            }
+        }
+        if let Some(stream) = self.stream.as_mut() {
+            if let Err(e) = Self::stream_finish(stream) {
+                self.error = Some(format!("decode failed: {}", e));
+                return;
+            }
+        }
+        self.check_ratio();
+    }
+
+    fn check_ratio(&mut self) {
+        let output = self.output().len();
+        if output > HTTP_DECODE_RATIO_MIN && output / std::cmp::max(self.input, 1) > self.limit.max_ratio {
+            self.error = Some(format!(
+                "decompression ratio exceeds {} ({} -> {} bytes)",
+                self.limit.max_ratio, self.input, output
+            ));
+        }
+    }
+
+    /* 已经解压的内容 */
+    pub fn output(&self) -> &[u8] {
+        match self.stream_sink() {
+            Some(sink) => return &sink.out,
+            None => return &[],
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /* 解压失败或者超过限制的原因 */
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /* 解压后超过了大小限制，已解压的内容不完整但格式没有错误 */
+    pub fn is_oversize(&self) -> bool {
+        return self.stream_sink().is_some_and(|sink| sink.overflow);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: ProtoHttpDecodeLimit = ProtoHttpDecodeLimit {
+        max_size: 4096,
+        max_ratio: 100,
+    };
+
+    fn decode(encoding: &str, data: &[u8], limit: ProtoHttpDecodeLimit) -> ProtoHttpDecoder {
+        let mut decoder = ProtoHttpDecoder::new(encoding, limit).unwrap();
+        decoder.feed(data);
+        decoder.finish();
+        return decoder;
+    }
+
+    #[test]
+    fn zstd_complete_and_truncated() {
+        let data = zstd::encode_all(&b"hello zstd"[..], 3).unwrap();
+        let decoder = decode("zstd", &data, LIMIT);
+        assert_eq!((decoder.output(), decoder.error()), (&b"hello zstd"[..], None));
+        let decoder = decode("zstd", &data[..data.len() - 2], LIMIT);
+        assert!(decoder.error().is_some());
+        assert!(!decoder.is_oversize());
+    }
+
+    #[test]
+    fn oversize_reported() {
+        let data = zstd::encode_all(&vec![b'a'; 8192][..], 3).unwrap();
+        let decoder = decode("zstd", &data, LIMIT);
+        assert!(decoder.error().is_some());
+        assert!(decoder.is_oversize());
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&[b'a'; 8192]).unwrap();
+        let decoder = decode("gzip", &gzip.finish().unwrap(), LIMIT);
+        assert!(decoder.is_oversize());
+    }
+}
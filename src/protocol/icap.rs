@@ -1,22 +1,109 @@
-use icaparse::{Response, Status};
+use icaparse::{InvalidChunkSize, Response, Status};
+use std::time::{Duration, Instant};
+
+// 解析icap响应头时初始的头部数组大小
+const ICAP_HEADERS_INIT: usize = 32;
+// OPTIONS响应允许的最大头部数量
+const ICAP_OPTIONS_HEADERS_MAX: usize = 256;
+// OPTIONS响应opt-body的最大字节数
+const ICAP_OPTIONS_BODY_MAX: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum IcapMode {
+    REQMOD,
+    RESPMOD,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum IcapTransfer {
+    PREVIEW,
+    IGNORE,
+    COMPLETE,
+}
+
+/* icap service通过OPTIONS通告的能力 */
+#[derive(Clone)]
+pub struct ProtoIcapOptions {
+    pub server: String,
+    pub service: String,
+    pub methods: Vec<String>,
+    pub preview: Option<usize>,
+    pub transfer_preview: Vec<String>,
+    pub transfer_ignore: Vec<String>,
+    pub transfer_complete: Vec<String>,
+    pub max_connections: Option<u32>,
+    pub options_ttl: Option<u64>,
+    pub istag: String,
+    pub allow_204: bool,
+    pub update_time: Instant,
+}
 
 pub struct ProtoIcapCtx {
+    mode: IcapMode,
+    // icap响应的Encapsulated头
+    encapsulated: String,
+    // icap 200响应中封装的http头(req-hdr或res-hdr)，以及解码后的http body
+    http_header: Vec<u8>,
+    http_request: bool,
+    http_has_body: bool,
     body: Vec<u8>,
     code: u16,
     seen_header: bool,
     not_vaild: bool,
+    pending: bool,
+    // 已经发送给icap server的body字节数
+    body_sent: usize,
+    // 已经发送了结束块
+    body_done: bool,
+    // preview后收到了100 Continue
+    continued: bool,
+    // icap响应带有 Connection: close
+    conn_close: bool,
+    // icap响应头的最大头部数量
+    max_headers: usize,
+    // icap响应中封装的http消息体的最大字节数
+    max_body: usize,
 }
 
 impl ProtoIcapCtx {
     pub fn new() -> Self {
         Self {
+            mode: IcapMode::REQMOD,
+            encapsulated: String::new(),
+            http_header: Vec::new(),
+            http_request: false,
+            http_has_body: false,
             body: Vec::new(),
-            code: 204,
+            code: 0,
             seen_header: false,
             not_vaild: false,
+            pending: false,
+            body_sent: 0,
+            body_done: false,
+            continued: false,
+            conn_close: false,
+            max_headers: 256,
+            max_body: usize::MAX,
         }
     }
 
+    pub fn set_max_headers(&mut self, max_headers: usize) {
+        self.max_headers = max_headers;
+    }
+
+    pub fn set_max_body(&mut self, max_body: usize) {
+        self.max_body = max_body;
+    }
+
+    pub fn set_mode(&mut self, mode: IcapMode) {
+        self.mode = mode;
+    }
+    pub fn get_mode(&self) -> IcapMode {
+        self.mode
+    }
+
     pub fn set_body(&mut self, body: Vec<u8>) {
         self.body = body;
     }
@@ -45,24 +132,314 @@ impl ProtoIcapCtx {
         self.not_vaild = !valid;
     }
     
+    pub fn set_pending(&mut self, pending: bool) {
+        self.pending = pending;
+    }
+    pub fn get_pending(&self) -> bool {
+        self.pending
+    }
+
+    pub fn set_body_sent(&mut self, sent: usize, done: bool) {
+        self.body_sent = sent;
+        self.body_done = done;
+    }
+    pub fn get_body_sent(&self) -> usize {
+        self.body_sent
+    }
+    pub fn get_body_done(&self) -> bool {
+        self.body_done
+    }
+
+    pub fn set_continued(&mut self, continued: bool) {
+        self.continued = continued;
+    }
+    pub fn get_continued(&self) -> bool {
+        self.continued
+    }
+
+    /* 事务正常结束(请求已完整发送，或者preview之后直接返回)，连接才能复用 */
+    pub fn get_reusable(&self) -> bool {
+        self.get_vaild() && !self.conn_close && (self.body_done || !self.continued)
+    }
+
+    /* icap 200响应中封装的http消息是否为请求(req-hdr) */
+    pub fn get_http_request(&self) -> bool {
+        self.http_request
+    }
+    pub fn get_http_header(&self) -> &[u8] {
+        &self.http_header
+    }
+    /* 封装的http消息是否有body(res-body/req-body，而不是null-body) */
+    pub fn get_http_has_body(&self) -> bool {
+        self.http_has_body
+    }
+
     pub fn reset(&mut self) {
+        self.encapsulated.clear();
+        self.http_header.clear();
+        self.http_request = false;
+        self.http_has_body = false;
         self.body.clear();
-        self.code = 204;
+        self.code = 0;
         self.seen_header = false;
         self.not_vaild = false;
+        self.pending = false;
+        self.body_sent = 0;
+        self.body_done = false;
+        self.continued = false;
+        self.conn_close = false;
+    }
+
+    /* 解析下一个icap响应前清理响应头状态(100 Continue之后) */
+    pub fn reset_head(&mut self) {
+        self.encapsulated.clear();
+        self.body.clear();
+        self.code = 0;
+        self.seen_header = false;
+    }
+
+    /*  构造REQMOD请求
+     * Encapsulated: req-hdr=0, req-body=N; 没有body时为 null-body=N
+     * req_hdr 为完整的http请求头(包含结尾的空行)，body 以 chunked 方式发送
+     * preview 为Some(ieof)时，body 作为preview发送; 否则 body 为完整的请求体
+     * extra 为附加的icap头部: 客户端身份(X-Client-IP等)、multipart的part信息
+     * */
+    pub fn build_reqmod(
+        options: &ProtoIcapOptions,
+        extra: &[(String, String)],
+        req_hdr: &[u8],
+        req_body: &[u8],
+        preview: Option<bool>,
+    ) -> Vec<u8> {
+        return Self::build_modify(
+            "REQMOD",
+            options,
+            extra,
+            &[("req-hdr", req_hdr)],
+            ("req-body", req_body),
+            preview,
+        );
+    }
+
+    /*  构造RESPMOD请求
+     * Encapsulated: req-hdr=0, res-hdr=N, res-body=M; 没有body时为 null-body=M
+     * req_hdr 为原始的http请求头，res_hdr 为http响应头，body 以 chunked 方式发送
+     * preview、extra 同 build_reqmod
+     * */
+    pub fn build_respmod(
+        options: &ProtoIcapOptions,
+        extra: &[(String, String)],
+        req_hdr: &[u8],
+        res_hdr: &[u8],
+        res_body: &[u8],
+        preview: Option<bool>,
+    ) -> Vec<u8> {
+        return Self::build_modify(
+            "RESPMOD",
+            options,
+            extra,
+            &[("req-hdr", req_hdr), ("res-hdr", res_hdr)],
+            ("res-body", res_body),
+            preview,
+        );
+    }
+
+    fn build_modify(
+        method: &str,
+        options: &ProtoIcapOptions,
+        extra: &[(String, String)],
+        headers: &[(&str, &[u8])],
+        body: (&str, &[u8]),
+        preview: Option<bool>,
+    ) -> Vec<u8> {
+        let (body_name, body) = body;
+        let has_body = !body.is_empty() || preview == Some(false);
+
+        // 计算各个封装段的偏移
+        let mut offset = 0;
+        let mut sections = Vec::new();
+        for (name, header) in headers {
+            sections.push(format!("{}={}", name, offset));
+            offset += header.len();
+        }
+        if has_body {
+            sections.push(format!("{}={}", body_name, offset));
+        } else {
+            sections.push(format!("null-body={}", offset));
+        }
+
+        let mut data = Vec::with_capacity(offset + body.len() + 256);
+        data.extend_from_slice(options.request_line(method).as_bytes());
+        // 只有icap server通告了 Allow: 204 才允许返回204
+        if options.allow_204 {
+            data.extend_from_slice(b"Allow: 204\r\n");
+        }
+        for (name, value) in extra {
+            data.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        if preview.is_some() && has_body {
+            data.extend_from_slice(format!("Preview: {}\r\n", body.len()).as_bytes());
+        }
+        data.extend_from_slice(format!("Encapsulated: {}\r\n", sections.join(", ")).as_bytes());
+        data.extend_from_slice(b"\r\n");
+        for (_, header) in headers {
+            data.extend_from_slice(header);
+        }
+        if !has_body {
+            return data;
+        }
+        if !body.is_empty() {
+            Self::build_chunk(&mut data, body);
+        }
+        match preview {
+            // body 已经全部包含在preview中
+            Some(true) => data.extend_from_slice(b"0; ieof\r\n\r\n"),
+            _ => Self::build_chunk(&mut data, &[]),
+        }
+        return data;
+    }
+
+    /*  按照chunked格式追加数据
+     * data 为空时追加结束块 0\r\n\r\n
+     * */
+    pub fn build_chunk(out: &mut Vec<u8>, data: &[u8]) {
+        out.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+        out.extend_from_slice(data);
+        out.extend_from_slice(b"\r\n");
+    }
+
+    /*  解析chunked数据
+     * 数据完整时返回 (消耗的字节数, 解码后的数据)
+     * 数据不够时返回Partial，解码后的数据超过limit时返回错误
+     * */
+    pub fn parse_chunked(data: &[u8], limit: usize) -> Result<Status<(usize, Vec<u8>)>, InvalidChunkSize> {
+        let mut pos = 0;
+        let mut body = Vec::new();
+        loop {
+            let (size_len, size) = match icaparse::parse_chunk_size(&data[pos..])? {
+                Status::Complete(v) => v,
+                Status::Partial => return Ok(Status::Partial),
+            };
+            pos += size_len;
+            if size == 0 {
+                break;
+            }
+            let size = usize::try_from(size).map_err(|_| InvalidChunkSize)?;
+            if size > limit - body.len() {
+                return Err(InvalidChunkSize);
+            }
+            let end = pos.checked_add(size).and_then(|v| v.checked_add(2)).ok_or(InvalidChunkSize)?;
+            if data.len() < end {
+                return Ok(Status::Partial);
+            }
+            body.extend_from_slice(&data[pos..pos + size]);
+            pos += size;
+            if &data[pos..pos + 2] != b"\r\n" {
+                return Err(InvalidChunkSize);
+            }
+            pos += 2;
+        }
+        // 跳过trailer，直到空行
+        loop {
+            let line_end = match data[pos..].windows(2).position(|w| w == b"\r\n") {
+                Some(end) => end,
+                None => return Ok(Status::Partial),
+            };
+            pos += line_end + 2;
+            if line_end == 0 {
+                break;
+            }
+        }
+        return Ok(Status::Complete((pos, body)));
+    }
+
+    /*  解析icap 200响应中封装的http消息
+     * data 为icap响应头之后的数据
+     * 数据完整返回Ok(true)，并保存http头与解码后的body；数据不够返回Ok(false)
+     * */
+    pub fn parse_icap_encapsulated(&mut self, data: &[u8]) -> Result<bool, String> {
+        let mut sections = Vec::new();
+        for section in self.encapsulated.split(',') {
+            let (name, offset) = match section.trim().split_once('=') {
+                Some(v) => v,
+                None => return Err(format!("Invalid Encapsulated header: {}", self.encapsulated)),
+            };
+            let offset = match offset.trim().parse::<usize>() {
+                Ok(offset) => offset,
+                Err(_) => return Err(format!("Invalid Encapsulated header: {}", self.encapsulated)),
+            };
+            sections.push((name.trim().to_ascii_lowercase(), offset));
+        }
+        // 最后一个段必须是body段
+        let (body_name, body_offset) = match sections.pop() {
+            Some(body) if body.0.ends_with("-body") => body,
+            _ => return Err(format!("Invalid Encapsulated header: {}", self.encapsulated)),
+        };
+        if data.len() < body_offset {
+            return Ok(false);
+        }
+
+        // 取出修改后的http头，优先使用res-hdr
+        self.http_header.clear();
+        self.http_request = false;
+        for (i, (name, offset)) in sections.iter().enumerate() {
+            let end = match sections.get(i + 1) {
+                Some((_, next)) => *next,
+                None => body_offset,
+            };
+            if *offset > end {
+                return Err(format!("Invalid Encapsulated header: {}", self.encapsulated));
+            }
+            if name == "res-hdr" || (name == "req-hdr" && self.http_header.is_empty()) {
+                self.http_header = data[*offset..end].to_vec();
+                self.http_request = name == "req-hdr";
+            }
+        }
+        if self.http_header.is_empty() {
+            return Err(format!("No http header in Encapsulated: {}", self.encapsulated));
+        }
+
+        // 解码chunked body
+        self.http_has_body = body_name != "null-body";
+        if !self.http_has_body {
+            self.body.clear();
+            return Ok(true);
+        }
+        match Self::parse_chunked(&data[body_offset..], self.max_body) {
+            Ok(Status::Complete((_, body))) => {
+                self.body = body;
+                return Ok(true);
+            }
+            Ok(Status::Partial) => return Ok(false),
+            Err(e) => return Err(format!("Error parsing ICAP body: {}", e)),
+        }
     }
 
     pub fn parse_icap_resp(&mut self, data: &[u8]) -> usize {
-        let mut headers = [icaparse::EMPTY_HEADER; 32];
-        let mut res = Response::new(&mut headers);
-        match res.parse(data) {
+        // 头部数组不够时成倍扩大后重新解析，直到max_headers
+        let mut count = std::cmp::min(ICAP_HEADERS_INIT, self.max_headers);
+        loop {
+            let mut headers = vec![icaparse::EMPTY_HEADER; count];
+            let mut res = Response::new(&mut headers);
+            match res.parse(data) {
+                Err(icaparse::Error::TooManyHeaders) if count < self.max_headers => {
+                    count = std::cmp::min(count * 2, self.max_headers);
+                }
+                result => return self.parse_icap_result(result, res, data),
+            }
+        }
+    }
+
+    fn parse_icap_result(&mut self, result: icaparse::Result<usize>, res: Response, data: &[u8]) -> usize {
+        match result {
             Ok(Status::Complete(header_end)) => {
-                println!("ICAP Response Parsed Successfully!");
-                println!("Version: {}", res.version.unwrap());
-                println!("Status: {}", res.code.unwrap());
-                println!("Reason: {}", res.reason.unwrap());
-                for header in res.headers {
-                    println!("Header: {} => {}", header.name, String::from_utf8_lossy(&header.value));
+                for header in res.headers.iter() {
+                    if header.name.eq_ignore_ascii_case("Encapsulated") {
+                        self.encapsulated = String::from_utf8_lossy(header.value).to_string();
+                    }
+                    if header.name.eq_ignore_ascii_case("Connection") {
+                        self.conn_close = header.value.eq_ignore_ascii_case(b"close");
+                    }
                 }
                 self.set_code(res.code.unwrap());
                 self.set_body(data[header_end..].to_vec());
@@ -70,7 +447,7 @@ impl ProtoIcapCtx {
                 return header_end;
             }
             Ok(Status::Partial) => {
-                println!("ICAP Response is incomplete. Waiting for more data...");
+                // 数据不够，继续收包
                 return 0;
             }
             Err(e) => {
@@ -80,5 +457,283 @@ impl ProtoIcapCtx {
             }
         }
     }
+}
 
+impl ProtoIcapOptions {
+    pub fn new(server: &str, service: &str) -> Self {
+        Self {
+            server: server.to_string(),
+            service: service.to_string(),
+            methods: Vec::new(),
+            preview: None,
+            transfer_preview: Vec::new(),
+            transfer_ignore: Vec::new(),
+            transfer_complete: Vec::new(),
+            max_connections: None,
+            options_ttl: None,
+            istag: String::new(),
+            allow_204: false,
+            update_time: Instant::now(),
+        }
+    }
+
+    /* 请求行及Host头 */
+    pub fn request_line(&self, method: &str) -> String {
+        return format!(
+            "{} icap://{}/{} ICAP/1.0\r\nHost: {}\r\n",
+            method, self.server, self.service, self.server
+        );
+    }
+
+    pub fn build_options(&self) -> Vec<u8> {
+        let mut data = self.request_line("OPTIONS");
+        data.push_str("Encapsulated: null-body=0\r\n\r\n");
+        return data.into_bytes();
+    }
+
+    /*  解析OPTIONS响应
+     * 解析成功，返回解析到的字节数(包含opt-body)；
+     * 如果数据不够，则返回Ok(0)
+     * 如果解析失败或者返回码不是200，则返回Err
+     * */
+    pub fn parse_options_resp(&mut self, data: &[u8]) -> Result<usize, String> {
+        // OPTIONS响应很少，先确定足够的头部数组大小
+        let mut count = ICAP_HEADERS_INIT;
+        while count < ICAP_OPTIONS_HEADERS_MAX {
+            let mut headers = vec![icaparse::EMPTY_HEADER; count];
+            match Response::new(&mut headers).parse(data) {
+                Err(icaparse::Error::TooManyHeaders) => count *= 2,
+                _ => break,
+            }
+        }
+        let mut headers = vec![icaparse::EMPTY_HEADER; count];
+        let mut res = Response::new(&mut headers);
+        let header_end = match res.parse(data) {
+            Ok(Status::Complete(header_end)) => header_end,
+            Ok(Status::Partial) => return Ok(0),
+            Err(e) => return Err(format!("Error parsing ICAP OPTIONS response: {}", e)),
+        };
+        let code = res.code.unwrap();
+        if code != 200 {
+            return Err(format!("ICAP OPTIONS returned {}", code));
+        }
+
+        let mut opt_body = false;
+        for header in res.headers.iter() {
+            let value = String::from_utf8_lossy(header.value).trim().to_string();
+            let list = || -> Vec<String> {
+                value
+                    .split(',')
+                    .map(|v| v.trim().to_ascii_lowercase())
+                    .filter(|v| !v.is_empty())
+                    .collect()
+            };
+            match header.name.to_ascii_lowercase().as_str() {
+                "methods" => self.methods = list().iter().map(|m| m.to_ascii_uppercase()).collect(),
+                "preview" => self.preview = value.parse::<usize>().ok(),
+                "transfer-preview" => self.transfer_preview = list(),
+                "transfer-ignore" => self.transfer_ignore = list(),
+                "transfer-complete" => self.transfer_complete = list(),
+                "max-connections" => self.max_connections = value.parse::<u32>().ok(),
+                "options-ttl" => self.options_ttl = value.parse::<u64>().ok(),
+                "istag" => self.istag = value.trim_matches('"').to_string(),
+                "allow" => self.allow_204 = list().iter().any(|v| v == "204"),
+                "encapsulated" => opt_body = value.contains("opt-body"),
+                _ => {}
+            }
+        }
+        self.update_time = Instant::now();
+
+        // opt-body 不使用，但需要消耗掉
+        if !opt_body {
+            return Ok(header_end);
+        }
+        match ProtoIcapCtx::parse_chunked(&data[header_end..], ICAP_OPTIONS_BODY_MAX) {
+            Ok(Status::Complete((size, _))) => return Ok(header_end + size),
+            Ok(Status::Partial) => return Ok(0),
+            Err(e) => return Err(format!("Error parsing ICAP OPTIONS body: {}", e)),
+        }
+    }
+
+    /* Options-TTL 超时后需要重新获取 */
+    pub fn is_expired(&self) -> bool {
+        match self.options_ttl {
+            Some(ttl) => self.update_time.elapsed() >= Duration::from_secs(ttl),
+            None => false,
+        }
+    }
+
+    pub fn support_method(&self, method: &str) -> bool {
+        return self.methods.iter().any(|m| m == method);
+    }
+
+    /*  根据url的文件后缀判断传输方式
+     * 优先级: Transfer-Complete > Transfer-Ignore > Transfer-Preview
+     * 列表中的 "*" 匹配其他所有后缀
+     * */
+    pub fn transfer_type(&self, path: &str) -> IcapTransfer {
+        let path = path.split(['?', '#']).next().unwrap_or("");
+        let file = path.rsplit('/').next().unwrap_or("");
+        let ext = match file.rsplit_once('.') {
+            Some((_, ext)) => ext.to_ascii_lowercase(),
+            None => String::new(),
+        };
+        let lists = [
+            (&self.transfer_complete, IcapTransfer::COMPLETE),
+            (&self.transfer_ignore, IcapTransfer::IGNORE),
+            (&self.transfer_preview, IcapTransfer::PREVIEW),
+        ];
+        for (list, transfer) in lists.iter() {
+            if !ext.is_empty() && list.contains(&ext) {
+                return *transfer;
+            }
+        }
+        for (list, transfer) in lists.iter() {
+            if list.iter().any(|v| v == "*") {
+                return *transfer;
+            }
+        }
+        return IcapTransfer::PREVIEW;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* 拆分icap请求: (icap头部, 封装的数据) */
+    fn split_modify(data: &[u8]) -> (String, &[u8]) {
+        let end = data.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        return (String::from_utf8(data[..end].to_vec()).unwrap(), &data[end..]);
+    }
+
+    #[test]
+    fn build_reqmod_offsets() {
+        let mut options = ProtoIcapOptions::new("127.0.0.1:1344", "reqmod");
+        options.allow_204 = true;
+        let req_hdr = b"POST /upload HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\n";
+        let extra = vec![("X-Client-IP".to_string(), "10.0.0.1".to_string())];
+        let data = ProtoIcapCtx::build_reqmod(&options, &extra, req_hdr, b"hello", None);
+        let (head, rest) = split_modify(&data);
+        assert!(head.starts_with("REQMOD icap://127.0.0.1:1344/reqmod ICAP/1.0\r\nHost: 127.0.0.1:1344\r\n"));
+        assert!(head.contains("Allow: 204\r\n"));
+        assert!(head.contains("X-Client-IP: 10.0.0.1\r\n"));
+        assert!(!head.contains("Preview"));
+        assert!(head.contains(&format!("Encapsulated: req-hdr=0, req-body={}\r\n", req_hdr.len())));
+        assert_eq!(&rest[..req_hdr.len()], req_hdr);
+        assert_eq!(&rest[req_hdr.len()..], b"5\r\nhello\r\n0\r\n\r\n");
+
+        // 没有body
+        let req_hdr = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        let data = ProtoIcapCtx::build_reqmod(&options, &[], req_hdr, b"", None);
+        let (head, rest) = split_modify(&data);
+        assert!(head.contains(&format!("Encapsulated: req-hdr=0, null-body={}\r\n", req_hdr.len())));
+        assert_eq!(rest, req_hdr);
+    }
+
+    #[test]
+    fn build_reqmod_preview() {
+        let options = ProtoIcapOptions::new("icap", "reqmod");
+        let req_hdr = b"POST / HTTP/1.1\r\nHost: a\r\n\r\n";
+        // body全部包含在preview中
+        let data = ProtoIcapCtx::build_reqmod(&options, &[], req_hdr, b"abc", Some(true));
+        let (head, rest) = split_modify(&data);
+        assert!(!head.contains("Allow: 204"));
+        assert!(head.contains("Preview: 3\r\n"));
+        assert_eq!(&rest[req_hdr.len()..], b"3\r\nabc\r\n0; ieof\r\n\r\n");
+        // 还有后续数据
+        let data = ProtoIcapCtx::build_reqmod(&options, &[], req_hdr, b"abc", Some(false));
+        let (_, rest) = split_modify(&data);
+        assert_eq!(&rest[req_hdr.len()..], b"3\r\nabc\r\n0\r\n\r\n");
+        // preview为空但还有后续数据时仍然封装body
+        let data = ProtoIcapCtx::build_reqmod(&options, &[], req_hdr, b"", Some(false));
+        let (head, rest) = split_modify(&data);
+        assert!(head.contains("Preview: 0\r\n"));
+        assert!(head.contains(&format!("req-body={}\r\n", req_hdr.len())));
+        assert_eq!(&rest[req_hdr.len()..], b"0\r\n\r\n");
+    }
+
+    #[test]
+    fn build_respmod_offsets() {
+        let options = ProtoIcapOptions::new("icap", "respmod");
+        let req_hdr = b"GET /a HTTP/1.1\r\nHost: a\r\n\r\n";
+        let res_hdr = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n";
+        let data = ProtoIcapCtx::build_respmod(&options, &[], req_hdr, res_hdr, b"ok", None);
+        let (head, rest) = split_modify(&data);
+        assert!(head.starts_with("RESPMOD icap://icap/respmod ICAP/1.0\r\n"));
+        let encapsulated = format!(
+            "Encapsulated: req-hdr=0, res-hdr={}, res-body={}\r\n",
+            req_hdr.len(),
+            req_hdr.len() + res_hdr.len()
+        );
+        assert!(head.contains(&encapsulated));
+        assert_eq!(&rest[..req_hdr.len()], req_hdr);
+        assert_eq!(&rest[req_hdr.len()..req_hdr.len() + res_hdr.len()], res_hdr);
+        assert_eq!(&rest[req_hdr.len() + res_hdr.len()..], b"2\r\nok\r\n0\r\n\r\n");
+
+        // 没有body的响应
+        let res_hdr = b"HTTP/1.1 304 Not Modified\r\n\r\n";
+        let data = ProtoIcapCtx::build_respmod(&options, &[], req_hdr, res_hdr, b"", None);
+        let (head, rest) = split_modify(&data);
+        let encapsulated = format!(
+            "Encapsulated: req-hdr=0, res-hdr={}, null-body={}\r\n",
+            req_hdr.len(),
+            req_hdr.len() + res_hdr.len()
+        );
+        assert!(head.contains(&encapsulated));
+        assert_eq!(rest.len(), req_hdr.len() + res_hdr.len());
+    }
+
+    #[test]
+    fn parse_options_resp_fields() {
+        let data = b"ICAP/1.0 200 OK\r\n\
+Methods: RESPMOD, reqmod\r\n\
+ISTag: \"tag-1\"\r\n\
+Preview: 1024\r\n\
+Transfer-Preview: *\r\n\
+Transfer-Ignore: JPG, gif\r\n\
+Transfer-Complete: exe\r\n\
+Max-Connections: 10\r\n\
+Options-TTL: 3600\r\n\
+Allow: 204\r\n\
+Encapsulated: null-body=0\r\n\r\n";
+        let mut options = ProtoIcapOptions::new("icap", "respmod");
+        assert_eq!(options.parse_options_resp(&data[..20]), Ok(0));
+        assert_eq!(options.parse_options_resp(data), Ok(data.len()));
+        assert!(options.support_method("REQMOD") && options.support_method("RESPMOD"));
+        assert_eq!(options.istag, "tag-1");
+        assert_eq!(options.preview, Some(1024));
+        assert_eq!(options.max_connections, Some(10));
+        assert_eq!(options.options_ttl, Some(3600));
+        assert!(options.allow_204);
+        assert!(!options.is_expired());
+        assert!(matches!(options.transfer_type("/a/b.Jpg?x=1"), IcapTransfer::IGNORE));
+        assert!(matches!(options.transfer_type("/setup.exe"), IcapTransfer::COMPLETE));
+        assert!(matches!(options.transfer_type("/index.html"), IcapTransfer::PREVIEW));
+    }
+
+    #[test]
+    fn parse_options_resp_body_and_errors() {
+        // opt-body需要消耗掉
+        let data = b"ICAP/1.0 200 OK\r\nMethods: REQMOD\r\nEncapsulated: opt-body=0\r\n\r\n3\r\nabc\r\n0\r\n\r\n";
+        let mut options = ProtoIcapOptions::new("icap", "reqmod");
+        assert_eq!(options.parse_options_resp(&data[..data.len() - 2]), Ok(0));
+        assert_eq!(options.parse_options_resp(data), Ok(data.len()));
+        assert!(!options.allow_204);
+        assert_eq!(options.preview, None);
+
+        let mut options = ProtoIcapOptions::new("icap", "reqmod");
+        assert!(options.parse_options_resp(b"ICAP/1.0 404 Not Found\r\nEncapsulated: null-body=0\r\n\r\n").is_err());
+        assert!(options.parse_options_resp(b"HTTP/1.1 200 OK\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn parse_chunked_limits() {
+        let data = b"5\r\nhello\r\n0\r\n\r\n";
+        assert!(matches!(ProtoIcapCtx::parse_chunked(data, 5), Ok(Status::Complete((15, ref body))) if body == b"hello"));
+        assert!(ProtoIcapCtx::parse_chunked(data, 4).is_err());
+        assert!(matches!(ProtoIcapCtx::parse_chunked(b"5\r\nhel", 5), Ok(Status::Partial)));
+        // 长度加上位置溢出
+        assert!(ProtoIcapCtx::parse_chunked(b"ffffffffffffffff\r\nx", usize::MAX).is_err());
+    }
 }
@@ -1,2 +1,7 @@
+pub mod hpack;
 pub mod http;
+pub mod http2;
+pub mod http_decode;
 pub mod icap;
+pub mod tls;
+pub mod websocket;
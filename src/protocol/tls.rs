@@ -0,0 +1,252 @@
+/* TLS记录与握手消息类型 */
+const TLS_RECORD_HANDSHAKE: u8 = 0x16;
+const TLS_HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+/* ClientHello扩展 */
+const TLS_EXT_SERVER_NAME: u16 = 0;
+const TLS_EXT_ALPN: u16 = 16;
+// ClientHello的最大字节数，超过时视为非法
+pub const TLS_HELLO_MAX_SIZE: usize = 64 * 1024;
+
+/* ClientHello中用于决定是否解密的字段 */
+pub struct ProtoTlsHello {
+    // SNI主机名(小写)，没有时为None
+    pub sni: Option<String>,
+    // ALPN协议列表，按客户端的优先顺序
+    pub alpn: Vec<String>,
+}
+
+/* 按顺序读取字节的游标，越界时返回错误 */
+struct ProtoTlsReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoTlsReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < n {
+            return Err("truncated TLS ClientHello".to_string());
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        return Ok(bytes);
+    }
+
+    fn u8(&mut self) -> Result<usize, String> {
+        return Ok(self.bytes(1)?[0] as usize);
+    }
+
+    fn u16(&mut self) -> Result<usize, String> {
+        let bytes = self.bytes(2)?;
+        return Ok((bytes[0] as usize) << 8 | bytes[1] as usize);
+    }
+
+    /* 以1或2字节长度开头的向量 */
+    fn vec8(&mut self) -> Result<&'a [u8], String> {
+        let n = self.u8()?;
+        return self.bytes(n);
+    }
+
+    fn vec16(&mut self) -> Result<&'a [u8], String> {
+        let n = self.u16()?;
+        return self.bytes(n);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+impl ProtoTlsHello {
+    /*
+    * 从http client端发送的原始数据中解析ClientHello，数据不会被修改
+    * ClientHello可能跨越多个TLS记录
+    * 返回Ok(None)表示数据不够，Err表示不是TLS或者格式错误
+    */
+    pub fn parse(data: &[u8]) -> Result<Option<Self>, String> {
+        // 取出握手层数据
+        let mut handshake = Vec::new();
+        let mut pos = 0;
+        loop {
+            if handshake.len() >= 4 {
+                let len = (handshake[1] as usize) << 16 | (handshake[2] as usize) << 8 | handshake[3] as usize;
+                if handshake[0] != TLS_HANDSHAKE_CLIENT_HELLO || len > TLS_HELLO_MAX_SIZE {
+                    return Err("not a TLS ClientHello".to_string());
+                }
+                if handshake.len() >= 4 + len {
+                    return Self::parse_hello(&handshake[4..4 + len]).map(Some);
+                }
+            }
+            if data.len() < pos + 5 {
+                return Ok(None);
+            }
+            if data[pos] != TLS_RECORD_HANDSHAKE || data[pos + 1] != 0x03 {
+                return Err("not a TLS handshake record".to_string());
+            }
+            let len = (data[pos + 3] as usize) << 8 | data[pos + 4] as usize;
+            if data.len() < pos + 5 + len {
+                return Ok(None);
+            }
+            handshake.extend_from_slice(&data[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+        }
+    }
+
+    /* 数据以TLS握手记录开始，用于区分格式错误的ClientHello与非TLS的流量 */
+    pub fn is_handshake(data: &[u8]) -> bool {
+        return data.first() == Some(&TLS_RECORD_HANDSHAKE);
+    }
+
+    fn parse_hello(body: &[u8]) -> Result<Self, String> {
+        let mut hello = Self { sni: None, alpn: Vec::new() };
+        let mut reader = ProtoTlsReader::new(body);
+        // legacy_version, random, session_id, cipher_suites, compression_methods
+        reader.bytes(2 + 32)?;
+        reader.vec8()?;
+        reader.vec16()?;
+        reader.vec8()?;
+        if reader.is_empty() {
+            return Ok(hello);
+        }
+        let mut extensions = ProtoTlsReader::new(reader.vec16()?);
+        while !extensions.is_empty() {
+            let kind = extensions.u16()? as u16;
+            let mut ext = ProtoTlsReader::new(extensions.vec16()?);
+            match kind {
+                TLS_EXT_SERVER_NAME => {
+                    let mut names = ProtoTlsReader::new(ext.vec16()?);
+                    while !names.is_empty() {
+                        let name_type = names.u8()?;
+                        let name = names.vec16()?;
+                        // 0: host_name
+                        if name_type == 0 && hello.sni.is_none() {
+                            let name = std::str::from_utf8(name).map_err(|_| "invalid TLS SNI".to_string())?;
+                            hello.sni = Some(name.trim_end_matches('.').to_ascii_lowercase());
+                        }
+                    }
+                }
+                TLS_EXT_ALPN => {
+                    let mut protocols = ProtoTlsReader::new(ext.vec16()?);
+                    while !protocols.is_empty() {
+                        hello.alpn.push(String::from_utf8_lossy(protocols.vec8()?).to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        return Ok(hello);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(data);
+        return out;
+    }
+
+    /* ClientHello握手消息(包含4字节的握手头) */
+    fn client_hello(sni: Option<&str>, alpn: &[&str]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0);
+        body.extend_from_slice(&vec16(&[0x13, 0x01]));
+        body.extend_from_slice(&[1, 0]);
+        let mut extensions = Vec::new();
+        if let Some(sni) = sni {
+            let mut name = vec![0];
+            name.extend_from_slice(&vec16(sni.as_bytes()));
+            extensions.extend_from_slice(&TLS_EXT_SERVER_NAME.to_be_bytes());
+            extensions.extend_from_slice(&vec16(&vec16(&name)));
+        }
+        if !alpn.is_empty() {
+            let mut protocols = Vec::new();
+            for p in alpn {
+                protocols.push(p.len() as u8);
+                protocols.extend_from_slice(p.as_bytes());
+            }
+            extensions.extend_from_slice(&TLS_EXT_ALPN.to_be_bytes());
+            extensions.extend_from_slice(&vec16(&vec16(&protocols)));
+        }
+        // 未知扩展被忽略
+        extensions.extend_from_slice(&[0xff, 0x01]);
+        extensions.extend_from_slice(&vec16(&[0]));
+        body.extend_from_slice(&vec16(&extensions));
+
+        let mut hello = vec![TLS_HANDSHAKE_CLIENT_HELLO, 0, (body.len() >> 8) as u8, body.len() as u8];
+        hello.extend_from_slice(&body);
+        return hello;
+    }
+
+    fn record(fragment: &[u8]) -> Vec<u8> {
+        let mut out = vec![TLS_RECORD_HANDSHAKE, 0x03, 0x01];
+        out.extend_from_slice(&vec16(fragment));
+        return out;
+    }
+
+    #[test]
+    fn parse_single_record() {
+        let data = record(&client_hello(Some("WWW.Example.com."), &["h2", "http/1.1"]));
+        assert!(ProtoTlsHello::is_handshake(&data));
+        let hello = ProtoTlsHello::parse(&data).unwrap().unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("www.example.com"));
+        assert_eq!(hello.alpn, ["h2", "http/1.1"]);
+
+        let hello = ProtoTlsHello::parse(&record(&client_hello(None, &[]))).unwrap().unwrap();
+        assert!(hello.sni.is_none() && hello.alpn.is_empty());
+    }
+
+    #[test]
+    fn parse_multiple_records() {
+        let hello = client_hello(Some("a.example"), &["h2"]);
+        let mut data = Vec::new();
+        for fragment in [&hello[..3], &hello[3..40], &hello[40..]] {
+            data.extend_from_slice(&record(fragment));
+        }
+        // 之后的应用数据不影响解析
+        data.extend_from_slice(b"\x17\x03\x03\x00\x01x");
+        let parsed = ProtoTlsHello::parse(&data).unwrap().unwrap();
+        assert_eq!(parsed.sni.as_deref(), Some("a.example"));
+        assert_eq!(parsed.alpn, ["h2"]);
+    }
+
+    #[test]
+    fn parse_truncated() {
+        let hello = client_hello(Some("a.example"), &["h2"]);
+        let mut data = record(&hello[..20]);
+        data.extend_from_slice(&record(&hello[20..]));
+        for len in 0..data.len() {
+            assert!(matches!(ProtoTlsHello::parse(&data[..len]), Ok(None)), "prefix {}", len);
+        }
+        assert!(ProtoTlsHello::parse(&data).unwrap().is_some());
+
+        // 记录完整但ClientHello内部的长度越界
+        let mut hello = client_hello(Some("a.example"), &[]);
+        // 扩展列表的长度
+        hello[45] = 0xff;
+        assert!(ProtoTlsHello::parse(&record(&hello)).is_err());
+    }
+
+    #[test]
+    fn parse_not_tls() {
+        let data = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert!(!ProtoTlsHello::is_handshake(data));
+        assert!(ProtoTlsHello::parse(data).is_err());
+        // 握手记录但不是ClientHello
+        let mut hello = client_hello(None, &[]);
+        hello[0] = 0x02;
+        assert!(ProtoTlsHello::parse(&record(&hello)).is_err());
+        // 超过最大长度
+        assert!(ProtoTlsHello::parse(&record(&[TLS_HANDSHAKE_CLIENT_HELLO, 0x01, 0x00, 0x01])).is_err());
+        // 记录版本不是0x03
+        let mut data = record(&client_hello(None, &[]));
+        data[1] = 0x02;
+        assert!(ProtoTlsHello::parse(&data).is_err());
+    }
+}
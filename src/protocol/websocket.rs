@@ -0,0 +1,387 @@
+use flate2::{Decompress, FlushDecompress, Status};
+use std::collections::VecDeque;
+
+/* websocket帧的opcode */
+const WS_OPCODE_CONTINUATION: u8 = 0x0;
+const WS_OPCODE_TEXT: u8 = 0x1;
+const WS_OPCODE_BINARY: u8 = 0x2;
+const WS_OPCODE_CLOSE: u8 = 0x8;
+const WS_OPCODE_PING: u8 = 0x9;
+const WS_OPCODE_PONG: u8 = 0xa;
+
+// 关闭码: 消息违反策略
+pub const WS_CLOSE_POLICY: u16 = 1008;
+
+/* 一个完整的文本或二进制消息，以及其在数据流中的位置 */
+pub struct ProtoWsMessage {
+    pub text: bool,
+    // 去掉掩码、解压之后的内容
+    pub payload: Vec<u8>,
+    // 消息第一个帧的起始偏移
+    pub start: u64,
+}
+
+/* 正在解析的帧 */
+struct ProtoWsFrame {
+    fin: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    // 剩余的payload字节数，以及已经处理的payload字节数(用于掩码)
+    remaining: u64,
+    pos: u64,
+}
+
+/* 正在接收的消息，可能由多个帧组成 */
+struct ProtoWsPartial {
+    text: bool,
+    compressed: bool,
+    payload: Vec<u8>,
+    start: u64,
+    // 超过大小限制，不再保存内容
+    oversize: bool,
+}
+
+/*
+* websocket单个方向的帧解析
+* 输入该方向的原始数据，输出完整的文本与二进制消息; 控制帧不输出
+* 原始数据不受影响，由调用者根据safe_offset决定可以转发的位置
+*/
+pub struct ProtoWsCtx {
+    // 不完整的帧头
+    head: Vec<u8>,
+    frame: Option<ProtoWsFrame>,
+    message: Option<ProtoWsPartial>,
+    messages: VecDeque<ProtoWsMessage>,
+    // 已经解析的字节数，以及最后一个完整帧的结束偏移
+    offset: u64,
+    boundary: u64,
+    // permessage-deflate，None表示没有协商压缩
+    inflate: Option<Decompress>,
+    no_context_takeover: bool,
+    max_size: usize,
+    // 有消息超过大小限制没有扫描，由调用者按失败策略处理
+    oversize: bool,
+    // 保留压缩上下文时超过限制的消息之后无法再解压，之后的数据都不再解析
+    passthrough: bool,
+}
+
+impl ProtoWsCtx {
+    /*
+    * deflate: 是否协商了permessage-deflate，以及该方向是否不保留压缩上下文
+    * max_size: 消息的最大字节数(解压后)，超过时不再扫描
+    */
+    pub fn new(deflate: Option<bool>, max_size: usize) -> Self {
+        Self {
+            head: Vec::new(),
+            frame: None,
+            message: None,
+            messages: VecDeque::new(),
+            offset: 0,
+            boundary: 0,
+            inflate: deflate.map(|_| Decompress::new(false)),
+            no_context_takeover: deflate.unwrap_or(false),
+            max_size,
+            oversize: false,
+            passthrough: false,
+        }
+    }
+
+    /*
+    * 根据101响应的Sec-WebSocket-Extensions判断是否协商了permessage-deflate
+    * client为true时返回http client端发送方向的参数，否则为http server端发送方向
+    * 返回Some(no_context_takeover)
+    */
+    pub fn deflate_params(extensions: Option<&str>, client: bool) -> Option<bool> {
+        let extensions = extensions?;
+        for extension in extensions.split(',') {
+            let mut params = extension.split(';').map(|p| p.trim().to_ascii_lowercase());
+            if params.next().as_deref() != Some("permessage-deflate") {
+                continue;
+            }
+            let takeover = match client {
+                true => "client_no_context_takeover",
+                false => "server_no_context_takeover",
+            };
+            return Some(params.any(|p| p == takeover));
+        }
+        return None;
+    }
+
+    /*
+    * 输入一段原始数据
+    * 帧格式错误或者解压失败时返回错误，之后不能再继续解析
+    */
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut i = 0;
+        while i < data.len() {
+            if self.passthrough {
+                self.offset += (data.len() - i) as u64;
+                return Ok(());
+            }
+            let Some(frame) = self.frame.as_mut() else {
+                self.head.push(data[i]);
+                i += 1;
+                self.offset += 1;
+                if let Some(frame) = Self::parse_head(&self.head)? {
+                    let start = self.offset - self.head.len() as u64;
+                    self.head.clear();
+                    self.begin_frame(frame, start)?;
+                }
+                continue;
+            };
+            let n = std::cmp::min(frame.remaining, (data.len() - i) as u64) as usize;
+            let chunk = &data[i..i + n];
+            if frame.opcode == WS_OPCODE_CONTINUATION || frame.opcode == WS_OPCODE_TEXT || frame.opcode == WS_OPCODE_BINARY {
+                if let Some(message) = self.message.as_mut() {
+                    if !message.oversize && message.payload.len() + n > self.max_size {
+                        println!("WebSocket message exceeds {} bytes", self.max_size);
+                        message.oversize = true;
+                        message.payload = Vec::new();
+                        self.oversize = true;
+                    }
+                    if !message.oversize {
+                        let begin = message.payload.len();
+                        message.payload.extend_from_slice(chunk);
+                        if let Some(mask) = frame.mask {
+                            for (k, b) in message.payload[begin..].iter_mut().enumerate() {
+                                *b ^= mask[((frame.pos + k as u64) % 4) as usize];
+                            }
+                        }
+                    }
+                }
+            }
+            frame.remaining -= n as u64;
+            frame.pos += n as u64;
+            self.offset += n as u64;
+            i += n;
+            if frame.remaining == 0 {
+                self.end_frame()?;
+            }
+        }
+        return Ok(());
+    }
+
+    /* 解析帧头，数据不够时返回None */
+    fn parse_head(head: &[u8]) -> Result<Option<(ProtoWsFrame, bool)>, String> {
+        if head.len() < 2 {
+            return Ok(None);
+        }
+        let masked = head[1] & 0x80 != 0;
+        let (len_size, len) = match head[1] & 0x7f {
+            126 => (2, None),
+            127 => (8, None),
+            len => (0, Some(len as u64)),
+        };
+        let size = 2 + len_size + if masked { 4 } else { 0 };
+        if head.len() < size {
+            return Ok(None);
+        }
+        let len = match len {
+            Some(len) => len,
+            None => head[2..2 + len_size].iter().fold(0u64, |acc, b| acc << 8 | *b as u64),
+        };
+        if len >> 63 != 0 {
+            return Err("websocket frame length overflow".to_string());
+        }
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        match opcode {
+            WS_OPCODE_CONTINUATION | WS_OPCODE_TEXT | WS_OPCODE_BINARY => {}
+            WS_OPCODE_CLOSE | WS_OPCODE_PING | WS_OPCODE_PONG if fin && len <= 125 => {}
+            _ => return Err(format!("invalid websocket frame: opcode {:#x} length {}", opcode, len)),
+        }
+        if head[0] & 0x30 != 0 {
+            return Err("websocket frame uses unknown extension bits".to_string());
+        }
+        let mask = match masked {
+            true => Some([head[size - 4], head[size - 3], head[size - 2], head[size - 1]]),
+            false => None,
+        };
+        let frame = ProtoWsFrame {
+            fin,
+            opcode,
+            mask,
+            remaining: len,
+            pos: 0,
+        };
+        return Ok(Some((frame, head[0] & 0x40 != 0)));
+    }
+
+    /* 帧头解析完成，rsv1表示消息被压缩 */
+    fn begin_frame(&mut self, frame: (ProtoWsFrame, bool), start: u64) -> Result<(), String> {
+        let (frame, rsv1) = frame;
+        if rsv1 && (self.inflate.is_none() || frame.opcode == WS_OPCODE_CONTINUATION || frame.opcode >= WS_OPCODE_CLOSE) {
+            return Err("unexpected compressed websocket frame".to_string());
+        }
+        match frame.opcode {
+            WS_OPCODE_CONTINUATION if self.message.is_none() => {
+                return Err("websocket continuation frame without message".to_string());
+            }
+            WS_OPCODE_TEXT | WS_OPCODE_BINARY if self.message.is_some() => {
+                return Err("websocket message interrupted by a new message".to_string());
+            }
+            WS_OPCODE_TEXT | WS_OPCODE_BINARY => {
+                self.message = Some(ProtoWsPartial {
+                    text: frame.opcode == WS_OPCODE_TEXT,
+                    compressed: rsv1,
+                    payload: Vec::new(),
+                    start,
+                    oversize: false,
+                });
+            }
+            _ => {}
+        }
+        let empty = frame.remaining == 0;
+        self.frame = Some(frame);
+        if empty {
+            self.end_frame()?;
+        }
+        return Ok(());
+    }
+
+    /* 帧结束，消息的最后一个帧结束时输出消息 */
+    fn end_frame(&mut self) -> Result<(), String> {
+        let Some(frame) = self.frame.take() else {
+            return Ok(());
+        };
+        self.boundary = self.offset;
+        if !frame.fin || frame.opcode >= WS_OPCODE_CLOSE {
+            return Ok(());
+        }
+        let Some(message) = self.message.take() else {
+            return Ok(());
+        };
+        if message.oversize {
+            return Ok(());
+        }
+        let mut payload = message.payload;
+        if message.compressed {
+            match self.inflate_message(&payload)? {
+                Some(data) => payload = data,
+                None => {
+                    println!("WebSocket message exceeds {} bytes after decompression", self.max_size);
+                    self.oversize = true;
+                    return Ok(());
+                }
+            }
+        }
+        self.messages.push_back(ProtoWsMessage {
+            text: message.text,
+            payload,
+            start: message.start,
+        });
+        return Ok(());
+    }
+
+    /* 解压permessage-deflate消息，超过大小限制返回None */
+    fn inflate_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let Some(inflate) = self.inflate.as_mut() else {
+            return Ok(Some(payload.to_vec()));
+        };
+        // 发送端去掉了结尾的 00 00 ff ff
+        let mut input = payload.to_vec();
+        input.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+        let mut out = Vec::with_capacity(std::cmp::min(input.len() * 4, self.max_size) + 64);
+        let mut pos = 0;
+        let mut oversize = false;
+        loop {
+            let before = inflate.total_in();
+            let status = inflate
+                .decompress_vec(&input[pos..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| format!("websocket message decompression failed: {}", e))?;
+            pos += (inflate.total_in() - before) as usize;
+            if out.len() > self.max_size {
+                oversize = true;
+                break;
+            }
+            if status == Status::StreamEnd || (pos >= input.len() && out.len() < out.capacity()) {
+                break;
+            }
+            out.reserve(std::cmp::max(out.capacity(), 4096));
+        }
+        if self.no_context_takeover || oversize {
+            inflate.reset(false);
+        }
+        // 超过限制时压缩上下文不完整，之后的消息都无法解压
+        if oversize && !self.no_context_takeover {
+            self.passthrough = true;
+        }
+        if oversize {
+            return Ok(None);
+        }
+        return Ok(Some(out));
+    }
+
+    /* 取出下一个完整的消息 */
+    pub fn next_message(&mut self) -> Option<ProtoWsMessage> {
+        self.messages.pop_front()
+    }
+
+    /* 取出并清除超过大小限制的标记 */
+    pub fn take_oversize(&mut self) -> bool {
+        return std::mem::take(&mut self.oversize);
+    }
+
+    /* 有尚未取出的完整消息 */
+    pub fn has_message(&self) -> bool {
+        !self.messages.is_empty()
+    }
+
+    /* 最后一个完整帧的结束偏移，之前的位置都是帧的边界 */
+    pub fn boundary(&self) -> u64 {
+        self.boundary
+    }
+
+    /*
+    * 可以转发的数据结束位置: 之前的数据都是控制帧、不扫描的消息或者已取出的消息
+    * 尚未完整或者尚未取出的消息从其第一个帧开始扣留; 不再解析之后的数据都可以转发
+    */
+    pub fn safe_offset(&self) -> u64 {
+        let mut safe = match self.passthrough {
+            true => self.offset,
+            false => self.boundary,
+        };
+        if let Some(message) = self.message.as_ref() {
+            if message.oversize {
+                // 不扫描的消息，正在接收的帧也可以转发
+                if self.frame.as_ref().is_some_and(|f| f.opcode < WS_OPCODE_CLOSE) {
+                    safe = self.offset;
+                }
+            } else {
+                safe = std::cmp::min(safe, message.start);
+            }
+        }
+        if let Some(message) = self.messages.front() {
+            safe = std::cmp::min(safe, message.start);
+        }
+        return safe;
+    }
+
+    /* 构造http server端发送给http client端的关闭帧(不带掩码) */
+    pub fn build_close(code: u16, reason: &str) -> Vec<u8> {
+        let reason = &reason.as_bytes()[..std::cmp::min(reason.len(), 123)];
+        let mut data = vec![0x80 | WS_OPCODE_CLOSE, (2 + reason.len()) as u8];
+        data.extend_from_slice(&code.to_be_bytes());
+        data.extend_from_slice(reason);
+        return data;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversize_message_reported() {
+        let mut ctx = ProtoWsCtx::new(None, 4);
+        // 不带掩码的文本帧 "hi"
+        ctx.feed(&[0x81, 0x02, b'h', b'i']).unwrap();
+        assert!(!ctx.take_oversize());
+        assert_eq!(ctx.next_message().unwrap().payload, b"hi");
+        ctx.feed(&[0x81, 0x05, b'h', b'e', b'l', b'l', b'o']).unwrap();
+        assert!(ctx.take_oversize());
+        assert!(!ctx.take_oversize());
+        assert!(!ctx.has_message());
+        assert_eq!(ctx.safe_offset(), 11);
+    }
+}
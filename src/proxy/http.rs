@@ -1,28 +1,166 @@
 use crate::common::common_net::common_get_orig_dst;
-use crate::protocol::http::ProtoHttpCtx;
-use crate::protocol::icap::ProtoIcapCtx;
+use crate::config::local_json::{
+    LocalConfigHttp, LocalConfigHttpOversize, LocalConfigHttpStrict, LocalConfigIcapFailPolicy, LocalConfigIcapMultipart,
+    LocalConfigIcapRemote, LocalConfigTls,
+};
+use crate::protocol::http::{ProtoHttpAnomaly, ProtoHttpCtx, ProtoHttpFraming, ProtoHttpPart};
+use crate::protocol::http_decode::{ProtoHttpDecodeLimit, ProtoHttpDecoder};
+use crate::protocol::icap::{IcapMode, IcapTransfer, ProtoIcapCtx, ProtoIcapOptions};
+use crate::protocol::tls::ProtoTlsHello;
+use crate::protocol::websocket::{ProtoWsCtx, ProtoWsMessage, WS_CLOSE_POLICY};
+use crate::proxy::http2::Http2;
+use crate::proxy::icap::{IcapClient, IcapConn};
+use crate::proxy::tls::{TlsMitm, TlsMitmOutcome};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    time::Instant,
 };
 
+// 附带multipart文件信息的icap头部
+const ICAP_UPLOAD_FILES_HEADER: &str = "X-Upload-Files";
+const ICAP_UPLOAD_FILE_HEADER: &str = "X-Upload-File";
+
+/*
+* 按文件扫描multipart请求的状态
+* 每个文件part依次发送一个REQMOD，被修改的part替换后重新构造请求体
+*/
+pub struct HttpMultipartScan {
+    parts: Vec<ProtoHttpPart>,
+    index: usize,
+    // 重新构造的请求体，以及已经复制到的原始请求体位置
+    body: Vec<u8>,
+    cursor: usize,
+    modified: bool,
+}
+
+/*
+* 协议切换为websocket之后，单个方向的消息扫描状态
+* 原始数据扣留到所在的消息扫描通过为止，控制帧与不扫描的消息直接转发
+*/
+pub struct HttpWsStream {
+    ctx: ProtoWsCtx,
+    // 扣留的原始数据，以及其第一个字节在数据流中的偏移
+    held: Vec<u8>,
+    offset: u64,
+    // 正在扫描的消息的起始偏移
+    scanning: Option<u64>,
+    // 已转发的数据结束在帧的边界，可以插入关闭帧
+    aligned: bool,
+}
+
+impl HttpWsStream {
+    fn new(ctx: ProtoWsCtx) -> Self {
+        Self {
+            ctx,
+            held: Vec::new(),
+            offset: 0,
+            scanning: None,
+            aligned: true,
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub enum WriteBuffer {
     UP(Vec<u8>),
     DOWN(Vec<u8>),
-    _ICAP(Vec<u8>)
+    ICAP(Vec<u8>),
+}
+
+// 从连接池借出icap连接，以及借到连接后发送的第一个消息
+type IcapConnect<'a> = (Pin<Box<dyn Future<Output = Result<IcapConn, std::io::Error>> + Send + 'a>>, WriteBuffer);
+
+/*
+* 请求的 Expect: 100-continue 状态
+* WAITING: http client端在等待100，icap server尚未决定
+* CONTINUE: 需要接收请求体，100在之前的响应发送完成后发送
+* SENT: 已代替http server发送100，转发的请求删除Expect
+*/
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum HttpExpect {
+    NONE,
+    WAITING,
+    CONTINUE,
+    SENT,
 }
 
 pub struct Http {
+    // 被扣留的消息: 消息头、原始的消息体、去掉chunked编码之后的消息体(发送给icap)
     pub head_down_buffer: Vec<u8>,
     pub body_down_buffer: Vec<u8>,
+    pub body_down_decoded: Vec<u8>,
 
     pub head_up_buffer: Vec<u8>,
     pub body_up_buffer: Vec<u8>,
+    pub body_up_decoded: Vec<u8>,
+
+    // 被扣留的消息已完整，之后收到的属于下一个消息的数据
+    pub down_stash: Vec<u8>,
+    pub up_stash: Vec<u8>,
 
-    pub _icap_buffer: Vec<u8>,
+    // http server端已关闭，响应以连接关闭结束
+    pub up_closed: bool,
+    // 消息已放行但body尚未结束，剩余的body直接透传; 消息被icap修改时丢弃
+    pub down_released: bool,
+    pub up_released: bool,
+    pub down_discard: bool,
+    pub up_discard: bool,
+
+    // 连接的五元组，用于告警日志
+    pub conn_tuple: String,
+    // 消息头超过限制时的处理策略
+    pub oversize_policy: LocalConfigHttpOversize,
+    // 消息头分帧信息有歧义时的处理策略
+    pub strict_policy: LocalConfigHttpStrict,
+    // 请求已被拒绝: 之后的请求数据丢弃，本地响应发送后关闭连接
+    pub down_rejected: bool,
+    // 响应已被拒绝: 502发送后关闭连接
+    pub up_rejected: bool,
+
+    // 解压限制，None表示不解压
+    pub decode_limit: Option<ProtoHttpDecodeLimit>,
+    // 等待icap扫描而扣留的消息体的最大字节数
+    pub held_max: usize,
+    // 被扣留消息的解压器，解压后的内容发送给icap server
+    pub down_decoder: Option<ProtoHttpDecoder>,
+    pub up_decoder: Option<ProtoHttpDecoder>,
+
+    pub icap_buffer: Vec<u8>,
+    // 上一个icap事务正常结束，连接可以归还到连接池
+    pub icap_reusable: bool,
     pub http_ctx: ProtoHttpCtx,
     pub icap_ctx: ProtoIcapCtx,
+
+    // icap service通告的能力, None表示该方向不扫描
+    pub reqmod_options: Option<ProtoIcapOptions>,
+    pub respmod_options: Option<ProtoIcapOptions>,
+    // 没有可用的icap server
+    pub icap_unavailable: bool,
+    pub reqmod_fail_policy: LocalConfigIcapFailPolicy,
+    pub respmod_fail_policy: LocalConfigIcapFailPolicy,
+    // 每个icap请求附带的客户端身份头部
+    pub icap_identity: Vec<(String, String)>,
+    pub multipart_mode: LocalConfigIcapMultipart,
+    pub multipart_scan: Option<HttpMultipartScan>,
+    pub down_expect: HttpExpect,
+
+    // 协议切换(101 / CONNECT 2xx)之后双向透传
+    pub tunnel: bool,
+    // 已放行的请求要求切换协议，收到其响应之前http client端的数据暂存
+    pub down_upgrade: bool,
+    // websocket消息扫描配置，以及两个方向的扫描状态(None表示该方向不扫描)
+    pub ws_inspect: bool,
+    pub ws_max_message: usize,
+    pub ws_down: Option<HttpWsStream>,
+    pub ws_up: Option<HttpWsStream>,
 }
 
 impl Http {
@@ -30,186 +168,1713 @@ impl Http {
         Self {
             head_down_buffer: Vec::new(),
             body_down_buffer: Vec::new(),
+            body_down_decoded: Vec::new(),
 
             head_up_buffer: Vec::new(),
             body_up_buffer: Vec::new(),
+            body_up_decoded: Vec::new(),
+
+            down_stash: Vec::new(),
+            up_stash: Vec::new(),
+
+            up_closed: false,
+            down_released: false,
+            up_released: false,
+            down_discard: false,
+            up_discard: false,
 
-            _icap_buffer: Vec::new(),
+            conn_tuple: String::new(),
+            oversize_policy: LocalConfigHttpOversize::Reject,
+            strict_policy: LocalConfigHttpStrict::Off,
+            down_rejected: false,
+            up_rejected: false,
+
+            decode_limit: None,
+            held_max: usize::MAX,
+            down_decoder: None,
+            up_decoder: None,
+
+            icap_buffer: Vec::new(),
+            icap_reusable: false,
 
             http_ctx: ProtoHttpCtx::new(),
             icap_ctx: ProtoIcapCtx::new(),
+
+            reqmod_options: None,
+            respmod_options: None,
+            icap_unavailable: false,
+            reqmod_fail_policy: LocalConfigIcapFailPolicy::FailOpen,
+            respmod_fail_policy: LocalConfigIcapFailPolicy::FailOpen,
+            icap_identity: Vec::new(),
+            multipart_mode: LocalConfigIcapMultipart::Whole,
+            multipart_scan: None,
+            down_expect: HttpExpect::NONE,
+
+            tunnel: false,
+            down_upgrade: false,
+            ws_inspect: false,
+            ws_max_message: 0,
+            ws_down: None,
+            ws_up: None,
         }
     }
 
     /* 
-    * 从http client端读取数据
-    * 1. 如果数据不合法，则将数据发送给http client端; 返回非None
-    * 2. 如果已经解析了请求头，则将数据推入到body_up_buffer中, 延迟发送给icap server端
-    * 3. 如果数据长度不够，则继续收包
+    * 从http server端读取数据
+    * 1. 如果数据不合法，或者属于已放行响应的剩余body，则将数据发送给http client端; 返回非None
+    * 2. 如果已经解析了响应头，则将属于当前响应的body推入到body_up_buffer中, 延迟发送给icap server端
+    * 3. 当前响应已完整但仍被扣留(或者需要先发送阻断页面)，之后的数据暂存，等待放行后再处理
+    * 4. 如果数据长度不够，则继续收包
     */
-    fn read_service_up(&mut self, buffer: &mut [u8], size: usize) -> Option<Vec<u8>> {
+    fn read_service_up(&mut self, buffer: &[u8], size: usize) -> Option<Vec<u8>> {
+        // 响应已被拒绝，连接即将关闭
+        if self.up_rejected {
+            return None;
+        }
+
+        // 协议已切换，不再是http
+        if self.tunnel {
+            return self.tunnel_data(IcapMode::RESPMOD, &buffer[0..size]);
+        }
+
         // 如果不符合不合法，则将数据发生给http client端
         if !self.http_ctx.is_valid() {
             return Some(buffer[0..size].to_vec());
         }
 
-        // 如果已经解析了请求头，则将数据推入到head_up_buffer中, 延迟发送给icap server端
+        // 当前响应已完整但仍被扣留，或者需要先发送之前被阻断请求的阻断页面
+        if (self.http_ctx.resp_seen_head() && self.http_ctx.resp_body_done()) || self.http_ctx.txn_front_local() {
+            self.up_stash.extend_from_slice(&buffer[0..size]);
+            return None;
+        }
+
+        // 属于当前响应的body: 已放行的直接发送给http client端(或丢弃)，否则扣留
         if self.http_ctx.resp_seen_head() {
             self.http_ctx.resp_seen_bytes_inc(size as u64);
-            self.body_up_buffer.extend_from_slice(buffer);
-            return None;
+            let plain_start = self.body_up_decoded.len();
+            let decoded = match self.http_ctx.resp_body().framing() {
+                ProtoHttpFraming::CHUNKED if !self.up_released => Some(&mut self.body_up_decoded),
+                _ => None,
+            };
+            let n = match self.http_ctx.resp_body().feed(&buffer[0..size], decoded) {
+                Ok(n) => n,
+                Err(e) => {
+                    // body格式错误，之后的数据不再解析
+                    println!("Invalid response body: {}", e);
+                    self.http_ctx.set_valid(false);
+                    return self.flush_invalid_up(&buffer[0..size]);
+                }
+            };
+            let mut data = Vec::new();
+            if !self.up_released {
+                self.body_up_buffer.extend_from_slice(&buffer[0..n]);
+                if let Some(decoder) = self.up_decoder.as_mut() {
+                    match self.http_ctx.resp_framing() {
+                        ProtoHttpFraming::CHUNKED => decoder.feed(&self.body_up_decoded[plain_start..]),
+                        _ => decoder.feed(&buffer[0..n]),
+                    }
+                    if self.http_ctx.resp_body_done() {
+                        decoder.finish();
+                    }
+                }
+            } else if !self.up_discard {
+                data.extend_from_slice(&buffer[0..n]);
+            }
+            if self.up_released && self.http_ctx.resp_body_done() {
+                self.finish_message(IcapMode::RESPMOD);
+            }
+            if n < size {
+                if let Some(rest) = self.read_service_up(&buffer[n..size], size - n) {
+                    data.extend(rest);
+                }
+            }
+            if data.is_empty() {
+                return None;
+            }
+            return Some(data);
         }
 
         // 后续数据不能使用buffer；而要使用head_up_buffer
-        self.head_up_buffer.extend_from_slice(buffer);
+        self.head_up_buffer.extend_from_slice(&buffer[0..size]);
 
-        let head_size = self.http_ctx.parse_http_resp_header(&self.head_up_buffer);
+        let mut head_size = self.http_ctx.parse_http_resp_header(&self.head_up_buffer);
+        if self.http_ctx.is_oversize() {
+            return self.oversize_up();
+        }
+        let anomalies = self.http_ctx.take_anomalies();
+        if !anomalies.is_empty() {
+            match self.framing_anomaly(IcapMode::RESPMOD, &anomalies) {
+                true => head_size = self.http_ctx.parse_http_resp_header(&self.head_up_buffer),
+                false => return self.reject_up("The response framing is ambiguous."),
+            }
+        }
+        // Content-Length无法解析时消息边界不确定，不论严格模式如何设置都拒绝
+        if self.http_ctx.is_bad_length() {
+            println!("ALERT: HTTP response Content-Length invalid [{}] action reject", self.conn_tuple);
+            return self.reject_up("The response Content-Length is invalid.");
+        }
         // 如果不合法，则将数据发生给http client端
         if !self.http_ctx.is_valid() {
             return Some(self.head_up_buffer.drain(..).collect());
         }
 
-        // 如果已经解析了请求头，则将数据推入到body_up_buffer中, 延迟发生给icap server端
-        if self.http_ctx.resp_seen_head() {
-            self.body_up_buffer.extend(self.head_up_buffer.drain(head_size..));
+        // 长度不够，继续收包
+        if !self.http_ctx.resp_seen_head() {
+            assert!(head_size == 0, "head_size is 0");
             return None;
         }
 
-        // 长度不够，继续收包
-        assert!(head_size == 0, "head_size is 0");
-        return None;
+        // 剩余的数据属于响应body或者下一个响应
+        let rest = self.head_up_buffer.split_off(head_size);
+        let mut data = Vec::new();
+        if self.http_ctx.resp_interim() {
+            // 1xx中间响应没有body，直接发送给http client端，之后是最终响应
+            data.append(&mut self.head_up_buffer);
+            self.http_ctx.resp_seen_head_set(false);
+        } else {
+            self.up_decoder = self.new_decoder(IcapMode::RESPMOD);
+        }
+        if !rest.is_empty() {
+            if let Some(rest) = self.read_service_up(&rest, rest.len()) {
+                data.extend(rest);
+            }
+        }
+        if data.is_empty() {
+            return None;
+        }
+        return Some(data);
     }
 
     /* 
-    * 从http server端读取数据
-    * 1. 如果数据不合法，则将数据发送给http server端; 返回非None
-    * 2. 如果已经解析了请求头，则将数据推入到body_down_buffer中, 延迟发送给icap server端
-    * 3. 如果数据长度不够，则继续收包
+    * 从http client端读取数据
+    * 1. 如果数据不合法，或者属于已放行请求的剩余body，则将数据发送给http server端; 返回非None
+    * 2. 如果已经解析了请求头，则将属于当前请求的body推入到body_down_buffer中, 延迟发送给icap server端
+    * 3. 当前请求已完整但仍被扣留，之后的数据暂存，等待放行后再处理
+    * 4. 如果数据长度不够，则继续收包
     */
-    fn read_service_down(&mut self, buffer: &mut [u8], size: usize) -> Option<Vec<u8>> {
+    fn read_service_down(&mut self, buffer: &[u8], size: usize) -> Option<Vec<u8>> {
+        // 请求已被拒绝，之后的数据丢弃
+        if self.down_rejected {
+            return None;
+        }
+
+        // 协议已切换，不再是http
+        if self.tunnel {
+            return self.tunnel_data(IcapMode::REQMOD, &buffer[0..size]);
+        }
+
         // 如果不符合不合法，则将数据发生给http server端
         if !self.http_ctx.is_valid() {
             return Some(buffer[0..size].to_vec());
         }
 
-        // 如果已经解析了请求头，则将数据推入到body_down_buffer中, 延迟发送给icap server端
+        // 等待切换协议请求的响应，之后的数据可能不再是http
+        if self.down_upgrade && !self.http_ctx.req_seen_head() {
+            self.down_stash.extend_from_slice(&buffer[0..size]);
+            return None;
+        }
+
+        // 当前请求已完整但仍被扣留
+        if self.http_ctx.req_seen_head() && self.http_ctx.req_body_done() {
+            self.down_stash.extend_from_slice(&buffer[0..size]);
+            return None;
+        }
+
+        // 属于当前请求的body: 已放行的直接发送给http server端(或丢弃)，否则扣留
         if self.http_ctx.req_seen_head() {
             self.http_ctx.req_seen_bytes_inc(size as u64);
-            self.body_down_buffer.extend_from_slice(buffer);
-            return None;
+            let plain_start = self.body_down_decoded.len();
+            let decoded = match self.http_ctx.req_body().framing() {
+                ProtoHttpFraming::CHUNKED if !self.down_released => Some(&mut self.body_down_decoded),
+                _ => None,
+            };
+            let n = match self.http_ctx.req_body().feed(&buffer[0..size], decoded) {
+                Ok(n) => n,
+                Err(e) => {
+                    // body格式错误，之后的数据不再解析
+                    println!("Invalid request body: {}", e);
+                    self.http_ctx.set_valid(false);
+                    return self.flush_invalid_down(&buffer[0..size]);
+                }
+            };
+            // http client端没有等待100就开始发送请求体
+            if n > 0 && matches!(self.down_expect, HttpExpect::WAITING | HttpExpect::CONTINUE) {
+                self.down_expect = HttpExpect::NONE;
+            }
+            let mut data = Vec::new();
+            if !self.down_released {
+                self.body_down_buffer.extend_from_slice(&buffer[0..n]);
+                if let Some(decoder) = self.down_decoder.as_mut() {
+                    match self.http_ctx.req_framing() {
+                        ProtoHttpFraming::CHUNKED => decoder.feed(&self.body_down_decoded[plain_start..]),
+                        _ => decoder.feed(&buffer[0..n]),
+                    }
+                    if self.http_ctx.req_body_done() {
+                        decoder.finish();
+                    }
+                }
+            } else if !self.down_discard {
+                data.extend_from_slice(&buffer[0..n]);
+            }
+            if self.down_released && self.http_ctx.req_body_done() {
+                self.finish_message(IcapMode::REQMOD);
+            }
+            if n < size {
+                if let Some(rest) = self.read_service_down(&buffer[n..size], size - n) {
+                    data.extend(rest);
+                }
+            }
+            if data.is_empty() {
+                return None;
+            }
+            return Some(data);
         }
 
         // 后续数据不能使用buffer；而要使用head_down_buffer
-        self.head_down_buffer.extend_from_slice(buffer);
+        self.head_down_buffer.extend_from_slice(&buffer[0..size]);
 
-        let head_size = self.http_ctx.parse_http_req_header(&self.head_down_buffer);
+        let mut head_size = self.http_ctx.parse_http_req_header(&self.head_down_buffer);
+        if self.http_ctx.is_oversize() {
+            return self.oversize_down();
+        }
+        let anomalies = self.http_ctx.take_anomalies();
+        if !anomalies.is_empty() {
+            match self.framing_anomaly(IcapMode::REQMOD, &anomalies) {
+                true => head_size = self.http_ctx.parse_http_req_header(&self.head_down_buffer),
+                false => return self.reject_down(ProtoHttpCtx::build_bad_request()),
+            }
+        }
+        // Content-Length无法解析时消息边界不确定，不论严格模式如何设置都拒绝
+        if self.http_ctx.is_bad_length() {
+            println!("ALERT: HTTP request Content-Length invalid [{}] action reject", self.conn_tuple);
+            return self.reject_down(ProtoHttpCtx::build_bad_request());
+        }
+        // Transfer-Encoding最后一个编码不是chunked时请求体无法分帧，同样拒绝
+        if self.http_ctx.is_bad_transfer() {
+            println!("ALERT: HTTP request Transfer-Encoding not chunked [{}] action reject", self.conn_tuple);
+            return self.reject_down(ProtoHttpCtx::build_bad_request());
+        }
         // 如果不合法，则将数据发生给http server端
         if !self.http_ctx.is_valid() {
             return Some(self.head_down_buffer.drain(..).collect());
         }
 
-        // 如果已经解析了请求头，则将数据推入到body_down_buffer中, 延迟发送给icap server端
-        if self.http_ctx.req_seen_head() {
-            self.body_down_buffer
-                .extend(self.head_down_buffer.drain(head_size..));
+        // 长度不够，继续收包
+        if !self.http_ctx.req_seen_head() {
+            assert!(head_size == 0, "head_size is 0");
             return None;
         }
 
-        // 长度不够，继续收包
-        assert!(head_size == 0, "head_size is 0");
+        // 剩余的数据属于请求body或者下一个请求
+        self.down_expect = match self.http_ctx.req_expect_continue() {
+            true => HttpExpect::WAITING,
+            false => HttpExpect::NONE,
+        };
+        self.down_decoder = self.new_decoder(IcapMode::REQMOD);
+        let rest = self.head_down_buffer.split_off(head_size);
+        if !rest.is_empty() {
+            return self.read_service_down(&rest, rest.len());
+        }
+        return None;
+    }
+
+    /*
+    * 响应头超过限制
+    * 1. reject: 返回502给http client端，之后关闭连接
+    * 2. alert: 记录告警，不再解析http，原样发送
+    */
+    fn oversize_up(&mut self) -> Option<Vec<u8>> {
+        if self.oversize_policy == LocalConfigHttpOversize::Alert {
+            println!("ALERT: response header over limit, forward unparsed: {}", self.http_ctx.resp_path());
+            self.http_ctx.set_valid(false);
+            return Some(self.head_up_buffer.drain(..).collect());
+        }
+        println!("Response header over limit, reject: {}", self.http_ctx.resp_path());
+        return self.reject_up("The response header exceeds the proxy limit.");
+    }
+
+    /* 拒绝当前响应: 返回502给http client端，之后关闭连接 */
+    fn reject_up(&mut self, reason: &str) -> Option<Vec<u8>> {
+        self.head_up_buffer.clear();
+        self.up_stash.clear();
+        self.up_rejected = true;
+        return Some(ProtoHttpCtx::build_bad_gateway(reason));
+    }
+
+    /*
+    * 请求头超过限制
+    * 1. reject: 之前的响应发送完成后返回431给http client端，之后关闭连接
+    * 2. alert: 记录告警，不再解析http，原样发送
+    */
+    fn oversize_down(&mut self) -> Option<Vec<u8>> {
+        if self.oversize_policy == LocalConfigHttpOversize::Alert {
+            println!("ALERT: request header over limit, forward unparsed");
+            self.http_ctx.set_valid(false);
+            return Some(self.head_down_buffer.drain(..).collect());
+        }
+        println!("Request header over limit, reject");
+        return self.reject_down(ProtoHttpCtx::build_header_too_large());
+    }
+
+    /* 拒绝当前请求: 之前的响应发送完成后返回本地响应，之后关闭连接 */
+    fn reject_down(&mut self, page: Vec<u8>) -> Option<Vec<u8>> {
+        self.head_down_buffer.clear();
+        self.down_stash.clear();
+        self.down_rejected = true;
+        self.http_ctx.req_seen_head_set(false);
+        self.http_ctx.set_valid(true);
+        self.http_ctx.txn_push(Vec::new());
+        self.http_ctx.txn_set_local(page);
         return None;
     }
 
+    /*
+    * 消息头的分帧信息有歧义(请求走私)，记录告警并按策略处理
+    * 返回true表示继续处理: alert保持原样，normalize已重写消息头需要重新解析
+    * 返回false表示需要拒绝
+    */
+    fn framing_anomaly(&mut self, mode: IcapMode, anomalies: &[ProtoHttpAnomaly]) -> bool {
+        let direction = match mode {
+            IcapMode::REQMOD => "request",
+            IcapMode::RESPMOD => "response",
+        };
+        let action = self.strict_policy;
+        let action_name = match action {
+            LocalConfigHttpStrict::Reject => "reject",
+            LocalConfigHttpStrict::Normalize => "normalize",
+            _ => "alert",
+        };
+        println!(
+            "ALERT: HTTP {} framing anomaly {:?} [{}] action {}",
+            direction, anomalies, self.conn_tuple, action_name
+        );
+        match action {
+            LocalConfigHttpStrict::Reject => return false,
+            LocalConfigHttpStrict::Normalize => {}
+            _ => return true,
+        }
+
+        let buffer = match mode {
+            IcapMode::REQMOD => &mut self.head_down_buffer,
+            IcapMode::RESPMOD => &mut self.head_up_buffer,
+        };
+        let Some(end) = ProtoHttpCtx::head_end(buffer) else {
+            return false;
+        };
+        match ProtoHttpCtx::normalize_header(&buffer[..end], mode == IcapMode::REQMOD) {
+            Ok(head) => {
+                buffer.splice(..end, head);
+            }
+            Err(e) => {
+                println!("HTTP {} framing can not be normalized [{}]: {}", direction, self.conn_tuple, e);
+                return false;
+            }
+        }
+        // 使用重写后的消息头重新解析
+        self.http_ctx.set_valid(true);
+        match mode {
+            IcapMode::REQMOD => self.http_ctx.req_seen_head_set(false),
+            IcapMode::RESPMOD => self.http_ctx.resp_seen_head_set(false),
+        }
+        return true;
+    }
+
+    /*
+    * 请求/响应被拒绝，本地响应已发送，可以关闭连接
+    */
+    fn rejected(&self) -> bool {
+        return self.up_rejected || (self.down_rejected && self.http_ctx.txn_empty());
+    }
+
+    /*
+    * body格式错误，不再解析http
+    * 被扣留的消息与当前数据原样发送
+    */
+    fn flush_invalid_up(&mut self, buffer: &[u8]) -> Option<Vec<u8>> {
+        let mut data: Vec<u8> = self.head_up_buffer.drain(..).collect();
+        data.append(&mut self.body_up_buffer);
+        self.body_up_decoded.clear();
+        if !self.up_released || !self.up_discard {
+            data.extend_from_slice(buffer);
+        }
+        data.append(&mut self.up_stash);
+        return Some(data);
+    }
+
+    fn flush_invalid_down(&mut self, buffer: &[u8]) -> Option<Vec<u8>> {
+        let mut data: Vec<u8> = self.head_down_buffer.drain(..).collect();
+        data.append(&mut self.body_down_buffer);
+        self.body_down_decoded.clear();
+        if !self.down_released || !self.down_discard {
+            data.extend_from_slice(buffer);
+        }
+        data.append(&mut self.down_stash);
+        return Some(data);
+    }
+
     /* 
     * 从icap server端读取数据
-    * 1. 如果数据不合法，则将数据发送给up/down stream端; 返回非None
-    * 2. 如果已经解析了icap响应头, 判断code是204、还是200
+    * 1. 如果数据不合法，则放行被扣留的请求/响应; 返回非None
+    * 2. 如果已经解析了icap响应头, 判断code是204、还是200; 200需要等待封装的http消息完整
     * 3. 如果数据长度不够，则继续收包
     */
-    fn read_service_icap(&mut self, _buffer: &mut [u8], _size: usize) -> Option<WriteBuffer> {
-
-        self._icap_buffer.extend_from_slice(_buffer);
+    fn read_service_icap(&mut self, buffer: &[u8], size: usize) -> Option<WriteBuffer> {
+        self.icap_buffer.extend_from_slice(&buffer[0..size]);
+        let mode = self.icap_ctx.get_mode();
+        self.icap_reusable = false;
         // 解析icap响应头
-        _ = self.icap_ctx.parse_icap_resp(&self._icap_buffer);
+        let head_size = self.icap_ctx.parse_icap_resp(&self.icap_buffer);
         if !self.icap_ctx.get_vaild() {
+            self.icap_buffer.clear();
+            self.icap_ctx.reset();
+            if self.tunnel {
+                return self.ws_allow(mode);
+            }
             self.http_ctx.set_valid(false);
-            // 这里需要判断，发送给上行还是下行
-            return Some(WriteBuffer::DOWN(self._icap_buffer.drain(..).collect()));
+            return Some(self.release_message(mode));
         }
         // 如果未解析icap响应头，则继续收包
         if !self.icap_ctx.get_seen_head() {
             return None;
         }
 
-        // 如果已经解析了icap响应头, 判断code是204、还是200
+        // 100 Continue: preview之后继续发送剩余的body, 事务仍在进行中
         let code = self.icap_ctx.get_code();
-        let body = self.icap_ctx.get_body();
+        if code == 100 {
+            self.icap_buffer.drain(0..head_size);
+            self.icap_ctx.reset_head();
+            self.icap_ctx.set_continued(true);
+            // 请求的preview没有body: icap server需要请求体，允许http client端发送
+            return self.continue_down(mode);
+        }
+
+        // 200: 等待封装的http消息接收完整
+        if code == 200 {
+            match self.icap_ctx.parse_icap_encapsulated(&self.icap_buffer[head_size..]) {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => {
+                    println!("{}", e);
+                    self.icap_buffer.clear();
+                    self.icap_ctx.reset();
+                    if self.tunnel {
+                        return self.ws_allow(mode);
+                    }
+                    self.http_ctx.set_valid(false);
+                    return Some(self.release_message(mode));
+                }
+            }
+        }
+
+        // 如果已经解析了icap响应头, 判断code是204、还是200
+        self.icap_reusable = self.icap_ctx.get_reusable();
+        if self.tunnel {
+            self.icap_buffer.clear();
+            self.icap_ctx.reset();
+            return self.ws_verdict(mode, code);
+        }
+        let modified = self.icap_ctx.get_http_request();
+        let method = match mode {
+            IcapMode::REQMOD => self.http_ctx.req_method(),
+            IcapMode::RESPMOD => self.http_ctx.resp_method(),
+        };
+        // http client端仍在等待100，请求体不会发送: 修改后的请求不再携带Expect，响应后关闭连接
+        let waiting = self.expect_waiting(mode);
+        let header = match waiting {
+            true => ProtoHttpCtx::remove_http_header(self.icap_ctx.get_http_header(), "Expect"),
+            false => self.icap_ctx.get_http_header().to_vec(),
+        };
+        let message = self.http_ctx.build_modified_message(
+            &header,
+            modified,
+            method,
+            self.icap_ctx.get_http_has_body(),
+            &self.icap_ctx.get_body(),
+        );
+        // 按文件扫描时只使用修改后的part内容
+        let part_body = match (&self.multipart_scan, mode) {
+            (Some(_), IcapMode::REQMOD) if self.icap_ctx.get_http_has_body() => Some(self.icap_ctx.get_body()),
+            (Some(_), IcapMode::REQMOD) => Some(Vec::new()),
+            _ => None,
+        };
+        self.icap_buffer.clear();
         self.icap_ctx.reset();
+        if let Some(part_body) = part_body {
+            return self.multipart_verdict(code, modified, part_body, message);
+        }
+        // 解压后超过大小限制，扫描的内容不完整: icap没有发现问题时按失败策略处理，否则使用icap的结果
+        if self.decode_oversize(mode) && code == 204 {
+            return self.oversize_message(mode, "decoded body");
+        }
+        // 解压失败或者超过压缩率限制，扫描的内容不完整，不论icap的结果都阻断
+        if let Some(reason) = self.decode_error(mode) {
+            return self.block_message(mode, &reason);
+        }
         match code {
             204 => {
-                // 这里需要判断，发送给上行还是下行
-                return Some(WriteBuffer::DOWN(self._icap_buffer.drain(..).collect()));
+                // 不需要修改，放行原始请求/响应(包括preview之后的204)
+                return Some(self.release_message(mode));
             }
             200 => {
-                // 这里需要判断，发送给上行还是下行
-                return Some(WriteBuffer::UP(body));
+                // 使用icap修改后的消息，原始消息剩余的body丢弃
+                // 修改后的请求发送给http server端；修改后的响应发送给http client端
+                _ = self.release_message(mode);
+                match mode {
+                    IcapMode::REQMOD => self.down_discard = true,
+                    IcapMode::RESPMOD => self.up_discard = true,
+                }
+                if waiting {
+                    self.down_rejected = true;
+                }
+                if mode == IcapMode::RESPMOD {
+                    return Some(WriteBuffer::DOWN(message));
+                }
+                if modified {
+                    return Some(WriteBuffer::UP(message));
+                }
+                // 请求被阻断，阻断页面在之前的响应发送完成后发送给http client端
+                self.http_ctx.txn_set_local(message);
+                return None;
             }
             _ => {
-                // 100-continue
+                // icap server出错，放行原始请求/响应
+                println!("ICAP server returned {}, bypass message", code);
+                return Some(self.release_message(mode));
+            }
+        }
+    }
+
+    /*
+    * 取出被扣留的请求或响应
+    * 1. REQMOD: 取出请求头 + 请求体发送给http server端，并加入等待响应的事务队列
+    * 2. RESPMOD: 取出响应头 + 响应体发送给http client端
+    * 如果body尚未接收完整，剩余的body直接透传
+    */
+    fn release_message(&mut self, mode: IcapMode) -> WriteBuffer {
+        match mode {
+            IcapMode::REQMOD => {
+                self.down_discard = false;
+                self.down_decoder = None;
+                self.multipart_scan = None;
+                // 已经代替http server发送100，http server不需要再处理Expect
+                if matches!(self.down_expect, HttpExpect::CONTINUE | HttpExpect::SENT) {
+                    self.head_down_buffer = ProtoHttpCtx::remove_http_header(&self.head_down_buffer, "Expect");
+                }
+                self.down_expect = HttpExpect::NONE;
+                self.down_upgrade = self.http_ctx.req_meta().upgrade().is_some();
+                self.http_ctx.txn_push(self.head_down_buffer.clone());
+                let mut data: Vec<u8> = self.head_down_buffer.drain(..).collect();
+                data.append(&mut self.body_down_buffer);
+                self.body_down_decoded.clear();
+                self.down_released = true;
+                if self.http_ctx.req_body_done() {
+                    self.finish_message(mode);
+                }
+                return WriteBuffer::UP(data);
+            }
+            IcapMode::RESPMOD => {
+                self.up_discard = false;
+                self.up_decoder = None;
+                let mut data: Vec<u8> = self.head_up_buffer.drain(..).collect();
+                data.append(&mut self.body_up_buffer);
+                self.body_up_decoded.clear();
+                self.up_released = true;
+                // 101 / CONNECT 2xx 之后的数据不再是http，双向透传
+                if self.http_ctx.resp_upgraded() {
+                    self.start_tunnel();
+                } else if self.http_ctx.resp_tunnel() {
+                    self.http_ctx.set_valid(false);
+                } else if self.http_ctx.resp_req_meta().is_some_and(|req| req.upgrade().is_some()) {
+                    // 没有切换协议，暂存的数据按http处理
+                    self.down_upgrade = false;
+                }
+                if self.http_ctx.resp_body_done() {
+                    self.finish_message(mode);
+                }
+                return WriteBuffer::DOWN(data);
+            }
+        }
+    }
+
+    /* 当前消息已经全部发送(或丢弃)，开始解析下一个消息 */
+    fn finish_message(&mut self, mode: IcapMode) {
+        match mode {
+            IcapMode::REQMOD => {
+                self.down_released = false;
+                self.http_ctx.req_seen_head_set(false);
+            }
+            IcapMode::RESPMOD => {
+                self.up_released = false;
+                self.http_ctx.resp_seen_head_set(false);
+                self.access_log();
+                self.http_ctx.txn_finish();
+            }
+        }
+    }
+
+    /* 一个http事务结束，记录请求与响应的摘要 */
+    fn access_log(&self) {
+        let Some(req) = self.http_ctx.resp_req_meta() else {
+            return;
+        };
+        let resp = self.http_ctx.resp_meta();
+        println!(
+            "HTTP {} {}{} HTTP/1.{} \"{}\" {} -> {} HTTP/1.{} {} {}",
+            req.method,
+            req.host,
+            req.target,
+            req.version,
+            req.user_agent,
+            if req.content_type.is_empty() { "-" } else { &req.content_type },
+            resp.code,
+            resp.version,
+            if resp.content_type.is_empty() { "-" } else { &resp.content_type },
+            resp.content_length.map_or("-".to_string(), |len| len.to_string()),
+        );
+    }
+
+    /*
+    * 被扣留消息的body(去掉chunked编码，需要解压时为解压后的内容)，以及body是否已经完整
+    * 解压失败时视为完整，已解压的部分发送给icap server后阻断
+    */
+    fn held_body(&self, mode: IcapMode) -> (&[u8], bool) {
+        let decoder = match mode {
+            IcapMode::REQMOD => self.down_decoder.as_ref(),
+            IcapMode::RESPMOD => self.up_decoder.as_ref(),
+        };
+        if let Some(decoder) = decoder {
+            return (decoder.output(), decoder.is_done() || decoder.error().is_some());
+        }
+        match mode {
+            IcapMode::REQMOD => {
+                let body = match self.http_ctx.req_framing() {
+                    ProtoHttpFraming::CHUNKED => &self.body_down_decoded,
+                    _ => &self.body_down_buffer,
+                };
+                return (body, self.http_ctx.req_body_done());
+            }
+            IcapMode::RESPMOD => {
+                let body = match self.http_ctx.resp_framing() {
+                    ProtoHttpFraming::CHUNKED => &self.body_up_decoded,
+                    _ => &self.body_up_buffer,
+                };
+                return (body, self.http_ctx.resp_body_done());
+            }
+        }
+    }
+
+    /*
+    * 需要交给icap server扫描且带有Content-Encoding的消息，创建解压器
+    * 没有启用解压、没有body或者不支持的编码返回None，body原样扫描
+    */
+    fn new_decoder(&self, mode: IcapMode) -> Option<ProtoHttpDecoder> {
+        let limit = self.decode_limit?;
+        self.icap_options(mode)?;
+        let (encoding, framing) = match mode {
+            IcapMode::REQMOD => (self.http_ctx.req_meta().headers.get("Content-Encoding"), self.http_ctx.req_framing()),
+            IcapMode::RESPMOD => (self.http_ctx.resp_meta().headers.get("Content-Encoding"), self.http_ctx.resp_framing()),
+        };
+        if framing == ProtoHttpFraming::NONE {
+            return None;
+        }
+        return ProtoHttpDecoder::new(encoding?, limit);
+    }
+
+    /* 被扣留消息解压后超过大小限制 */
+    fn decode_oversize(&self, mode: IcapMode) -> bool {
+        let decoder = match mode {
+            IcapMode::REQMOD => self.down_decoder.as_ref(),
+            IcapMode::RESPMOD => self.up_decoder.as_ref(),
+        };
+        return decoder.is_some_and(|d| d.is_oversize());
+    }
+
+    /* 被扣留消息解压失败(格式错误、数据被截断)或者超过压缩率限制的原因 */
+    fn decode_error(&self, mode: IcapMode) -> Option<String> {
+        let decoder = match mode {
+            IcapMode::REQMOD => self.down_decoder.as_ref()?,
+            IcapMode::RESPMOD => self.up_decoder.as_ref()?,
+        };
+        if decoder.is_oversize() {
+            return None;
+        }
+        let reason = decoder.error()?;
+        println!("ALERT: block {}: {}", self.message_url(mode), reason);
+        return Some(format!("The compressed content could not be scanned: {}.", reason));
+    }
+
+    /*
+    * 发送给icap server的http头
+    * 消息体已解压时删除Content-Encoding以及原来的长度信息
+    */
+    fn icap_http_header(header: &[u8], decoded: bool) -> std::borrow::Cow<'_, [u8]> {
+        if !decoded {
+            return std::borrow::Cow::Borrowed(header);
+        }
+        let header = ProtoHttpCtx::rebuild_http_header(header, None);
+        return std::borrow::Cow::Owned(ProtoHttpCtx::remove_http_header(&header, "Content-Encoding"));
+    }
+
+    /*
+    * 判断该方向是否需要交给icap server扫描
+    * 1. icap service不可用或者不支持该方法，则不扫描
+    * 2. url后缀在Transfer-Ignore中，则不扫描
+    */
+    fn icap_options(&self, mode: IcapMode) -> Option<&ProtoIcapOptions> {
+        let (options, method) = match mode {
+            IcapMode::REQMOD => (self.reqmod_options.as_ref()?, "REQMOD"),
+            IcapMode::RESPMOD => (self.respmod_options.as_ref()?, "RESPMOD"),
+        };
+        if !options.support_method(method) {
+            return None;
+        }
+        if options.transfer_type(self.message_path(mode)) == IcapTransfer::IGNORE {
+            return None;
+        }
+        return Some(options);
+    }
+
+    /*
+    * 处理缓存中待发送的数据
+    * 1. 请求已完整或者已达到preview大小，则构造REQMOD发送给icap server，请求被扣留直到收到icap响应
+    * 2. 响应已完整或者已达到preview大小，则构造RESPMOD发送给icap server，响应被扣留直到收到icap响应
+    * 3. 收到100 Continue之后，继续发送剩余的body
+    */
+    fn pending_service(&mut self) -> Option<WriteBuffer> {
+        // 上一个消息已经结束，处理暂存的下一个消息
+        if !self.down_stash.is_empty() && !self.http_ctx.req_seen_head() {
+            let stash = std::mem::take(&mut self.down_stash);
+            if let Some(data) = self.read_service_down(&stash, stash.len()) {
+                return Some(WriteBuffer::UP(data));
+            }
+        }
+        if !self.up_stash.is_empty() && !self.http_ctx.resp_seen_head() {
+            let stash = std::mem::take(&mut self.up_stash);
+            if let Some(data) = self.read_service_up(&stash, stash.len()) {
+                return Some(WriteBuffer::DOWN(data));
+            }
+        }
+        if self.tunnel {
+            return self.pending_tunnel();
+        }
+        // http server端已关闭: 当前响应的body结束，不完整的响应头原样发送
+        if self.up_closed && self.up_stash.is_empty() {
+            if self.http_ctx.resp_seen_head() && !self.http_ctx.resp_body_done() {
+                self.http_ctx.resp_body().close();
+                if let Some(decoder) = self.up_decoder.as_mut() {
+                    decoder.finish();
+                }
+                if self.up_released {
+                    self.finish_message(IcapMode::RESPMOD);
+                }
+            } else if !self.http_ctx.resp_seen_head() && !self.head_up_buffer.is_empty() {
+                return Some(WriteBuffer::DOWN(self.head_up_buffer.drain(..).collect()));
+            }
+        }
+        // 之前的响应都已发送完成，发送被阻断请求的阻断页面
+        if let Some(resp) = self.http_ctx.txn_pop_local() {
+            return Some(WriteBuffer::DOWN(resp));
+        }
+        if let Some(data) = self.expect_continue() {
+            return Some(data);
+        }
+        if self.icap_ctx.get_pending() {
+            return self.pending_icap_body();
+        }
+        if !self.head_down_buffer.is_empty() && self.http_ctx.req_seen_head() {
+            if let Some(msg) = self.pending_icap(IcapMode::REQMOD) {
+                return Some(msg);
+            }
+        }
+        if !self.head_up_buffer.is_empty() && self.http_ctx.resp_seen_head() {
+            if let Some(msg) = self.pending_icap(IcapMode::RESPMOD) {
+                return Some(msg);
+            }
+        }
+        return self.http_ctx.txn_pop_local().map(WriteBuffer::DOWN);
+    }
+
+    /*
+    * 开始一个icap事务
+    * 1. 该方向不需要扫描，则解析消息头后立即放行，body直接透传(SSE、长轮询等流式响应不被扣留)
+    * 2. icap service支持preview，则body达到preview大小(或者完整)后发送preview
+    * 3. 否则等待body完整后一次性发送
+    * 请求带有 Expect: 100-continue 时，不等待body: 发送没有body的preview，或者先发送100
+    */
+    fn pending_icap(&mut self, mode: IcapMode) -> Option<WriteBuffer> {
+        // 切换协议的响应没有需要扫描的内容
+        if mode == IcapMode::RESPMOD && self.http_ctx.resp_upgraded() {
+            return Some(self.release_message(mode));
+        }
+        if self.icap_options(mode).is_some() {
+            if self.decode_oversize(mode) {
+                return self.oversize_message(mode, "decoded body");
+            }
+            if let Some(reason) = self.decode_error(mode) {
+                return self.block_message(mode, &reason);
+            }
+            // 扣留的消息体超过限制，无法完整扫描
+            if self.held_size(mode) > self.held_max {
+                return self.oversize_message(mode, "held body");
+            }
+            // multipart请求等待请求体完整后解析，不使用preview
+            if mode == IcapMode::REQMOD && self.multipart_scan.is_some() {
+                return self.pending_multipart();
+            }
+            if mode == IcapMode::REQMOD && self.multipart_mode != LocalConfigIcapMultipart::Whole && self.multipart_boundary().is_some() {
+                if !self.held_body(mode).1 {
+                    return self.continue_down(mode);
+                }
+                if self.multipart_mode == LocalConfigIcapMultipart::PerFile && self.multipart_start() {
+                    return self.pending_multipart();
+                }
+            }
+        }
+        let extra = self.icap_extra_headers(mode);
+        let waiting = self.expect_waiting(mode);
+        let (body, complete) = self.held_body(mode);
+        let options = match self.icap_options(mode) {
+            Some(options) => options,
+            None if self.icap_unavailable => return self.bypass_message(mode),
+            // 不扫描的消息立即放行; 请求带有Expect时由http server决定是否发送100
+            None => return Some(self.release_message(mode)),
+        };
+        let preview = match options.preview {
+            Some(size) if options.transfer_type(self.message_path(mode)) != IcapTransfer::COMPLETE => Some(size),
+            _ => None,
+        };
+
+        // http client端等待100时，发送没有body的preview，由icap server决定是否需要请求体
+        let (send_len, ieof) = match preview {
+            None if !complete => return self.continue_down(mode),
+            None => (body.len(), None),
+            Some(size) if !complete && body.len() < size && !waiting => return None,
+            Some(size) => {
+                let len = std::cmp::min(size, body.len());
+                (len, Some(complete && body.len() <= size))
+            }
+        };
+        let data = match mode {
+            IcapMode::REQMOD => {
+                let header = Self::icap_http_header(&self.head_down_buffer, self.down_decoder.is_some());
+                ProtoIcapCtx::build_reqmod(options, &extra, &header, &body[0..send_len], ieof)
+            }
+            IcapMode::RESPMOD => ProtoIcapCtx::build_respmod(
+                options,
+                &extra,
+                self.http_ctx.resp_req_hdr(),
+                &Self::icap_http_header(&self.head_up_buffer, self.up_decoder.is_some()),
+                &body[0..send_len],
+                ieof,
+            ),
+        };
+        self.icap_ctx.set_mode(mode);
+        self.icap_ctx.set_pending(true);
+        self.icap_ctx.set_body_sent(send_len, ieof != Some(false));
+        return Some(WriteBuffer::ICAP(data));
+    }
+
+    /* multipart请求的boundary，不是multipart请求返回None */
+    fn multipart_boundary(&self) -> Option<String> {
+        return ProtoHttpPart::multipart_boundary(&self.http_ctx.req_meta().content_type);
+    }
+
+    /* 解析完整的multipart请求体中的文件part，格式错误返回None */
+    fn multipart_files(&self) -> Option<Vec<ProtoHttpPart>> {
+        let boundary = self.multipart_boundary()?;
+        let (body, _) = self.held_body(IcapMode::REQMOD);
+        match ProtoHttpPart::parse_multipart(body, &boundary) {
+            Ok(parts) => return Some(parts.into_iter().filter(|p| p.filename.is_some()).collect()),
+            Err(e) => {
+                println!("Invalid multipart body {}: {}", self.message_url(IcapMode::REQMOD), e);
                 return None;
             }
         }
     }
 
-    /* 
-    * 从up/down stream端读取数据
-    * 1. 如果数据不合法，则将数据发送给up/down stream端; 返回非None
-    * 2. 如果已经解析了icap响应头, 判断code是204、还是200
-    * 3. 如果数据长度不够，则继续收包
+    /*
+    * 附加的icap头部: 客户端身份
+    * multipart请求按headers方式扫描时，附带文件数量以及每个文件的信息
+    */
+    fn icap_extra_headers(&self, mode: IcapMode) -> Vec<(String, String)> {
+        let mut extra = self.icap_identity.clone();
+        if mode != IcapMode::REQMOD || self.multipart_mode != LocalConfigIcapMultipart::Headers || !self.held_body(mode).1 {
+            return extra;
+        }
+        if let Some(files) = self.multipart_files() {
+            extra.push((ICAP_UPLOAD_FILES_HEADER.to_string(), files.len().to_string()));
+            for part in &files {
+                extra.push((ICAP_UPLOAD_FILE_HEADER.to_string(), part.describe()));
+            }
+        }
+        return extra;
+    }
+
+    /* 开始按文件扫描multipart请求; 没有文件或者格式错误时返回false，整个请求体一起扫描 */
+    fn multipart_start(&mut self) -> bool {
+        let parts = match self.multipart_files() {
+            Some(parts) if !parts.is_empty() => parts,
+            _ => return false,
+        };
+        self.multipart_scan = Some(HttpMultipartScan {
+            parts,
+            index: 0,
+            body: Vec::new(),
+            cursor: 0,
+            modified: false,
+        });
+        return true;
+    }
+
+    /*
+    * 将当前文件part作为单独的REQMOD发送
+    * http请求头使用该part的Content-Type与文件名，body为part的内容
+    */
+    fn pending_multipart(&mut self) -> Option<WriteBuffer> {
+        let scan = self.multipart_scan.as_ref()?;
+        let part = &scan.parts[scan.index];
+        let options = self.icap_options(IcapMode::REQMOD)?;
+        let (body, _) = self.held_body(IcapMode::REQMOD);
+        let mut extra = self.icap_identity.clone();
+        extra.push((ICAP_UPLOAD_FILE_HEADER.to_string(), part.describe()));
+        let header = part.build_part_header(&self.head_down_buffer);
+        let data = ProtoIcapCtx::build_reqmod(options, &extra, &header, &body[part.offset..part.offset + part.size], None);
+        let size = part.size;
+        self.icap_ctx.set_mode(IcapMode::REQMOD);
+        self.icap_ctx.set_pending(true);
+        self.icap_ctx.set_body_sent(size, true);
+        return Some(WriteBuffer::ICAP(data));
+    }
+
+    /*
+    * 处理一个文件part的icap结果
+    * 1. 204: part不变，继续扫描下一个文件
+    * 2. 200 修改后的请求: 使用修改后的body替换该part，继续扫描下一个文件
+    * 3. 200 响应: 阻断整个请求
+    * 所有文件扫描完成后，有part被修改则发送重新构造的请求，否则放行原始请求
+    */
+    fn multipart_verdict(&mut self, code: u16, modified: bool, part_body: Vec<u8>, message: Vec<u8>) -> Option<WriteBuffer> {
+        let mut scan = self.multipart_scan.take()?;
+        let part = scan.parts[scan.index].clone();
+        let url = self.message_url(IcapMode::REQMOD);
+        let content = match code {
+            204 => None,
+            200 if modified => Some(part_body),
+            200 => {
+                println!("ICAP REQMOD block upload {}: {}", url, part.describe());
+                _ = self.release_message(IcapMode::REQMOD);
+                self.down_discard = true;
+                self.http_ctx.txn_set_local(message);
+                return None;
+            }
+            _ => {
+                println!("ICAP server returned {}, bypass message", code);
+                return Some(self.release_message(IcapMode::REQMOD));
+            }
+        };
+        let verdict = if content.is_some() { "modified" } else { "clean" };
+        println!("ICAP REQMOD upload {} {}: {}", url, verdict, part.describe());
+
+        let (body, _) = self.held_body(IcapMode::REQMOD);
+        scan.body.extend_from_slice(&body[scan.cursor..part.offset]);
+        match content {
+            Some(content) => {
+                scan.body.extend_from_slice(&content);
+                scan.modified = true;
+            }
+            None => scan.body.extend_from_slice(&body[part.offset..part.offset + part.size]),
+        }
+        scan.cursor = part.offset + part.size;
+        scan.index += 1;
+        if scan.index < scan.parts.len() {
+            self.multipart_scan = Some(scan);
+            return None;
+        }
+        if !scan.modified {
+            return Some(self.release_message(IcapMode::REQMOD));
+        }
+        scan.body.extend_from_slice(&body[scan.cursor..]);
+        let header = Self::icap_http_header(&self.head_down_buffer, self.down_decoder.is_some());
+        let message = self.http_ctx.build_modified_message(&header, true, self.http_ctx.req_method(), true, &scan.body);
+        _ = self.release_message(IcapMode::REQMOD);
+        self.down_discard = true;
+        return Some(WriteBuffer::UP(message));
+    }
+
+    /*
+    * 收到100 Continue之后，将新收到的body发送给icap server
+    * body完整后发送结束块
+    */
+    fn pending_icap_body(&mut self) -> Option<WriteBuffer> {
+        if !self.icap_ctx.get_continued() || self.icap_ctx.get_body_done() {
+            return None;
+        }
+        let (body, complete) = self.held_body(self.icap_ctx.get_mode());
+        let sent = self.icap_ctx.get_body_sent();
+        if body.len() == sent && !complete {
+            return None;
+        }
+
+        let mut data = Vec::new();
+        if body.len() > sent {
+            ProtoIcapCtx::build_chunk(&mut data, &body[sent..]);
+        }
+        if complete {
+            ProtoIcapCtx::build_chunk(&mut data, &[]);
+        }
+        self.icap_ctx.set_body_sent(body.len(), complete);
+        return Some(WriteBuffer::ICAP(data));
+    }
+
+    fn options_expired(&self) -> bool {
+        let expired = |options: &Option<ProtoIcapOptions>| match options {
+            Some(options) => options.is_expired(),
+            None => false,
+        };
+        return expired(&self.reqmod_options) || expired(&self.respmod_options);
+    }
+
+    /* icap server通告的最大连接数 */
+    fn icap_max_connections(&self) -> Option<u32> {
+        let reqmod = self.reqmod_options.as_ref().and_then(|o| o.max_connections);
+        let respmod = self.respmod_options.as_ref().and_then(|o| o.max_connections);
+        return match (reqmod, respmod) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    /*
+    * 获取icap server上reqmod/respmod service的能力
+    * OPTIONS失败的方向不扫描; 两个方向都失败返回false
+    */
+    async fn update_options(&mut self, icap_remote: &LocalConfigIcapRemote, icap: &IcapClient, server: &str) -> bool {
+        self.reqmod_options = match icap.options.fetch(&icap.pool, icap_remote, server, &icap_remote.reqmod_service).await {
+            Ok(options) => Some(options),
+            Err(e) => {
+                println!("ICAP OPTIONS {}/{} failed: {}", server, icap_remote.reqmod_service, e);
+                None
+            }
+        };
+        self.respmod_options = match icap.options.fetch(&icap.pool, icap_remote, server, &icap_remote.respmod_service).await {
+            Ok(options) => Some(options),
+            Err(e) => {
+                println!("ICAP OPTIONS {}/{} failed: {}", server, icap_remote.respmod_service, e);
+                None
+            }
+        };
+        return self.reqmod_options.is_some() || self.respmod_options.is_some();
+    }
+
+    /*
+    * 通过负载均衡选择icap server，并获取其能力
+    * OPTIONS失败的server记录失败后选择下一个; 全部不可用时返回None，按失败策略处理
+    */
+    async fn select_icap(
+        &mut self,
+        icap_remote: &LocalConfigIcapRemote,
+        icap: &IcapClient,
+        client_ip: IpAddr,
+    ) -> Option<String> {
+        for _ in 0..icap_remote.servers.len() {
+            let server = match icap.balancer.select(icap_remote, &icap.pool, client_ip) {
+                Some(server) => server,
+                None => break,
+            };
+            if self.update_options(icap_remote, icap, &server).await {
+                icap.balancer.report(icap_remote, &server, true);
+                self.icap_unavailable = false;
+                return Some(server);
+            }
+            icap.balancer.report(icap_remote, &server, false);
+        }
+        self.icap_down();
+        return None;
+    }
+
+    /* 当前icap server不可用，在重新选择之前按失败策略处理新的请求/响应 */
+    fn icap_down(&mut self) {
+        self.reqmod_options = None;
+        self.respmod_options = None;
+        self.icap_unavailable = true;
+    }
+
+    /*
+    * icap连接失败、被关闭或者响应超时
+    * 按失败策略处理被扣留的请求/响应，返回需要发送的数据
+    */
+    fn icap_failed(&mut self) -> Option<WriteBuffer> {
+        if !self.icap_ctx.get_pending() {
+            return None;
+        }
+        let mode = self.icap_ctx.get_mode();
+        self.icap_buffer.clear();
+        self.icap_ctx.reset();
+        if self.tunnel {
+            return self.ws_bypass(mode);
+        }
+        return self.bypass_message(mode);
+    }
+
+    /*
+    * 无法通过icap server扫描的请求/响应
+    * 1. fail-open: 不扫描直接放行，并记录日志
+    * 2. fail-closed: 返回阻断页面给http client端，原始消息剩余的body丢弃
+    */
+    fn bypass_message(&mut self, mode: IcapMode) -> Option<WriteBuffer> {
+        let policy = match mode {
+            IcapMode::REQMOD => self.reqmod_fail_policy,
+            IcapMode::RESPMOD => self.respmod_fail_policy,
+        };
+        let direction = match mode {
+            IcapMode::REQMOD => "request",
+            IcapMode::RESPMOD => "response",
+        };
+        if policy == LocalConfigIcapFailPolicy::FailOpen {
+            println!("ICAP unavailable, forward {} unscanned: {}", direction, self.message_url(mode));
+            return Some(self.release_message(mode));
+        }
+        println!("ICAP unavailable, block {}: {}", direction, self.message_url(mode));
+        return self.block_message(mode, "Content scanning service is unavailable.");
+    }
+
+    /*
+    * 解压后或者扣留的消息体超过大小限制的请求/响应，无法完整扫描
+    * fail-open时不扫描直接放行，fail-closed时阻断
+    */
+    fn oversize_message(&mut self, mode: IcapMode, what: &str) -> Option<WriteBuffer> {
+        let policy = match mode {
+            IcapMode::REQMOD => self.reqmod_fail_policy,
+            IcapMode::RESPMOD => self.respmod_fail_policy,
+        };
+        if policy == LocalConfigIcapFailPolicy::FailOpen {
+            println!("ALERT: {} over limit, forward unscanned: {}", what, self.message_url(mode));
+            return Some(self.release_message(mode));
+        }
+        println!("ALERT: {} over limit, block: {}", what, self.message_url(mode));
+        return self.block_message(mode, "The content exceeds the scanning size limit.");
+    }
+
+    /* 被扣留消息已经接收的原始消息体字节数 */
+    fn held_size(&self, mode: IcapMode) -> usize {
+        match mode {
+            IcapMode::REQMOD => return self.body_down_buffer.len(),
+            IcapMode::RESPMOD => return self.body_up_buffer.len(),
+        }
+    }
+
+    /*
+    * 阻断请求/响应: 返回阻断页面给http client端，原始消息剩余的body丢弃
+    */
+    fn block_message(&mut self, mode: IcapMode, reason: &str) -> Option<WriteBuffer> {
+        // http client端仍在等待100: 返回阻断页面代替100，请求体不会发送，之后关闭连接
+        let waiting = self.expect_waiting(mode);
+        _ = self.release_message(mode);
+        let page = ProtoHttpCtx::build_block_page(reason, waiting);
+        match mode {
+            IcapMode::REQMOD => {
+                // 阻断页面在之前的响应发送完成后发送给http client端
+                self.down_discard = true;
+                if waiting {
+                    self.down_rejected = true;
+                }
+                self.http_ctx.txn_set_local(page);
+                return None;
+            }
+            IcapMode::RESPMOD => {
+                self.up_discard = true;
+                return Some(WriteBuffer::DOWN(page));
+            }
+        }
+    }
+
+    /* 被扣留的请求带有 Expect: 100-continue，且http client端仍在等待100 */
+    fn expect_waiting(&self, mode: IcapMode) -> bool {
+        return mode == IcapMode::REQMOD && self.down_expect == HttpExpect::WAITING;
+    }
+
+    /*
+    * 需要接收请求体才能扫描: http client端在等待100时，允许其发送请求体
+    * 100在之前的响应都发送完成后发送，避免插入到其他响应中间
+    */
+    fn continue_down(&mut self, mode: IcapMode) -> Option<WriteBuffer> {
+        if !self.expect_waiting(mode) {
+            return None;
+        }
+        self.down_expect = HttpExpect::CONTINUE;
+        return self.expect_continue();
+    }
+
+    fn expect_continue(&mut self) -> Option<WriteBuffer> {
+        if self.down_expect != HttpExpect::CONTINUE || !self.http_ctx.txn_empty() || self.http_ctx.resp_seen_head() {
+            return None;
+        }
+        println!("Send 100 Continue: {}", self.message_url(IcapMode::REQMOD));
+        self.down_expect = HttpExpect::SENT;
+        return Some(WriteBuffer::DOWN(ProtoHttpCtx::build_continue()));
+    }
+
+    /* 请求/响应对应的url，响应使用其对应请求的url */
+    fn message_path(&self, mode: IcapMode) -> &str {
+        match mode {
+            IcapMode::REQMOD => return self.http_ctx.req_path(),
+            IcapMode::RESPMOD => return self.http_ctx.resp_path(),
+        }
+    }
+
+    /* 用于日志的请求地址: Host + request-target */
+    fn message_url(&self, mode: IcapMode) -> String {
+        let req = match mode {
+            IcapMode::REQMOD => Some(self.http_ctx.req_meta()),
+            IcapMode::RESPMOD => self.http_ctx.resp_req_meta(),
+        };
+        match req {
+            Some(req) if !req.target.starts_with('/') => return req.target.clone(),
+            Some(req) => return format!("{}{}", req.host, req.target),
+            None => return String::new(),
+        }
+    }
+
+    /*
+    * 正在等待icap server的响应
+    * 收到100 Continue之后、body发送完成之前，icap server不需要响应
+    */
+    fn icap_waiting(&self) -> bool {
+        return self.icap_ctx.get_pending() && (!self.icap_ctx.get_continued() || self.icap_ctx.get_body_done());
+    }
+
+    /*
+    * http server端关闭连接
+    * 如果响应仍未交给icap server处理完成，则返回true，等待处理完成后再关闭
+    */
+    fn close_service_up(&mut self) -> bool {
+        self.up_closed = true;
+        // websocket: 已完整的消息扫描完成后再关闭，不完整的消息丢弃
+        let ws_pending = self.ws_up.as_ref().is_some_and(|s| s.scanning.is_some() || s.ctx.has_message());
+        return self.icap_ctx.get_pending() || !self.head_up_buffer.is_empty() || !self.up_stash.is_empty() || ws_pending;
+    }
+
+    /*
+    * 切换协议，之后双向透传
+    * 切换为websocket且启用了消息扫描时，解析两个方向的帧
+    */
+    fn start_tunnel(&mut self) {
+        self.tunnel = true;
+        self.down_upgrade = false;
+        let upgrade = self.http_ctx.resp_req_meta().and_then(|req| req.upgrade()).unwrap_or("");
+        println!("HTTP upgrade to {} [{}]", upgrade, self.conn_tuple);
+        if !self.ws_inspect || !self.http_ctx.resp_websocket() {
+            return;
+        }
+        let extensions = self.http_ctx.resp_meta().headers.get("Sec-WebSocket-Extensions");
+        let (client, server) = (ProtoWsCtx::deflate_params(extensions, true), ProtoWsCtx::deflate_params(extensions, false));
+        if self.icap_options(IcapMode::REQMOD).is_some() {
+            self.ws_down = Some(HttpWsStream::new(ProtoWsCtx::new(client, self.ws_max_message)));
+        }
+        if self.icap_options(IcapMode::RESPMOD).is_some() {
+            self.ws_up = Some(HttpWsStream::new(ProtoWsCtx::new(server, self.ws_max_message)));
+        }
+    }
+
+    fn ws_stream(&mut self, mode: IcapMode) -> Option<&mut HttpWsStream> {
+        match mode {
+            IcapMode::REQMOD => return self.ws_down.as_mut(),
+            IcapMode::RESPMOD => return self.ws_up.as_mut(),
+        }
+    }
+
+    /*
+    * 协议切换之后收到的数据
+    * 不扫描的方向直接转发; 否则扣留，由pending_tunnel转发或者发送给icap server
     */
-    async fn pending_service(&mut self) -> Option<WriteBuffer> {
-        if self.head_down_buffer.len() > 0 && self.http_ctx.req_seen_head() {
-            return Some(WriteBuffer::DOWN(self.head_down_buffer.drain(..).collect()));
+    fn tunnel_data(&mut self, mode: IcapMode, data: &[u8]) -> Option<Vec<u8>> {
+        let conn_tuple = self.conn_tuple.clone();
+        let Some(stream) = self.ws_stream(mode) else {
+            return Some(data.to_vec());
+        };
+        stream.held.extend_from_slice(data);
+        let Err(e) = stream.ctx.feed(data) else {
+            return None;
+        };
+        // 帧格式错误，不再扫描该方向
+        println!("ALERT: {} [{}], forward uninspected", e, conn_tuple);
+        let held = std::mem::take(&mut stream.held);
+        match mode {
+            IcapMode::REQMOD => self.ws_down = None,
+            IcapMode::RESPMOD => self.ws_up = None,
         }
-        if self.head_up_buffer.len() > 0 && self.http_ctx.resp_seen_head() {
-            return Some(WriteBuffer::UP(self.head_up_buffer.drain(..).collect()));
+        return Some(held);
+    }
+
+    /*
+    * 协议切换之后待发送的数据
+    * 1. 转发扣留的数据，直到第一个尚未扫描通过的消息
+    * 2. 没有正在进行的icap事务时，发送下一个完整的消息给icap server
+    */
+    fn pending_tunnel(&mut self) -> Option<WriteBuffer> {
+        // 超过大小限制的消息在转发之前按失败策略处理
+        for mode in [IcapMode::REQMOD, IcapMode::RESPMOD] {
+            if self.ws_oversize(mode) {
+                return self.ws_block(mode, "Message exceeds the scanning size limit");
+            }
+        }
+        if let Some(data) = self.ws_release(IcapMode::REQMOD) {
+            return Some(WriteBuffer::UP(data));
+        }
+        if let Some(data) = self.ws_release(IcapMode::RESPMOD) {
+            return Some(WriteBuffer::DOWN(data));
+        }
+        if self.icap_ctx.get_pending() {
+            return None;
+        }
+        if let Some(msg) = self.ws_scan(IcapMode::REQMOD) {
+            return Some(msg);
+        }
+        return self.ws_scan(IcapMode::RESPMOD);
+    }
+
+    fn ws_release(&mut self, mode: IcapMode) -> Option<Vec<u8>> {
+        let stream = self.ws_stream(mode)?;
+        let mut limit = stream.ctx.safe_offset();
+        if let Some(start) = stream.scanning {
+            limit = std::cmp::min(limit, start);
+        }
+        let n = limit.saturating_sub(stream.offset) as usize;
+        if n == 0 {
+            return None;
+        }
+        stream.offset += n as u64;
+        stream.aligned = limit <= stream.ctx.boundary();
+        return Some(stream.held.drain(..n).collect());
+    }
+
+    /*
+    * 有消息超过大小限制无法扫描
+    * fail-open时记录告警并转发，返回true表示需要阻断
+    */
+    fn ws_oversize(&mut self, mode: IcapMode) -> bool {
+        let Some(stream) = self.ws_stream(mode) else {
+            return false;
+        };
+        if !stream.ctx.take_oversize() {
+            return false;
+        }
+        let policy = match mode {
+            IcapMode::REQMOD => self.reqmod_fail_policy,
+            IcapMode::RESPMOD => self.respmod_fail_policy,
+        };
+        if policy == LocalConfigIcapFailPolicy::FailOpen {
+            println!("ALERT: websocket message over limit, forward unscanned [{}]", self.conn_tuple);
+            return false;
+        }
+        return true;
+    }
+
+    /* 发送下一个完整的websocket消息给icap server */
+    fn ws_scan(&mut self, mode: IcapMode) -> Option<WriteBuffer> {
+        let stream = self.ws_stream(mode)?;
+        if stream.scanning.is_some() {
+            return None;
+        }
+        let message = stream.ctx.next_message()?;
+        stream.scanning = Some(message.start);
+        let Some(options) = self.icap_options(mode) else {
+            return self.ws_bypass(mode);
+        };
+        let extra = self.icap_identity.clone();
+        let (req_hdr, res_hdr) = self.ws_http_header(mode, &message);
+        let data = match mode {
+            IcapMode::REQMOD => ProtoIcapCtx::build_reqmod(options, &extra, &req_hdr, &message.payload, None),
+            IcapMode::RESPMOD => ProtoIcapCtx::build_respmod(options, &extra, &req_hdr, &res_hdr, &message.payload, None),
+        };
+        self.icap_ctx.set_mode(mode);
+        self.icap_ctx.set_pending(true);
+        self.icap_ctx.set_body_sent(message.payload.len(), true);
+        return Some(WriteBuffer::ICAP(data));
+    }
+
+    /*
+    * 封装websocket消息的http头
+    * http client端发送的消息作为POST请求的body; http server端发送的消息作为响应的body
+    */
+    fn ws_http_header(&self, mode: IcapMode, message: &ProtoWsMessage) -> (Vec<u8>, Vec<u8>) {
+        let req = self.http_ctx.req_meta();
+        let content_type = match message.text {
+            true => "text/plain; charset=utf-8",
+            false => "application/octet-stream",
+        };
+        let fields = format!(
+            "Content-Type: {}\r\nContent-Length: {}\r\nX-WebSocket-Message: {}\r\n\r\n",
+            content_type,
+            message.payload.len(),
+            if message.text { "text" } else { "binary" }
+        );
+        match mode {
+            IcapMode::REQMOD => {
+                let req_hdr = format!("POST {} HTTP/1.1\r\nHost: {}\r\n{}", req.target, req.host, fields);
+                return (req_hdr.into_bytes(), Vec::new());
+            }
+            IcapMode::RESPMOD => {
+                let req_hdr = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", req.target, req.host);
+                return (req_hdr.into_bytes(), format!("HTTP/1.1 200 OK\r\n{}", fields).into_bytes());
+            }
+        }
+    }
+
+    /*
+    * websocket消息的扫描结果
+    * 204放行; 200表示消息被阻断(不支持修改websocket消息); 其他错误放行
+    */
+    fn ws_verdict(&mut self, mode: IcapMode, code: u16) -> Option<WriteBuffer> {
+        match code {
+            204 => return self.ws_allow(mode),
+            200 => return self.ws_block(mode, "Message blocked by content scanning"),
+            _ => {
+                println!("ICAP server returned {}, bypass websocket message", code);
+                return self.ws_allow(mode);
+            }
+        }
+    }
+
+    /* 当前消息扫描通过，扣留的数据由pending_tunnel转发 */
+    fn ws_allow(&mut self, mode: IcapMode) -> Option<WriteBuffer> {
+        if let Some(stream) = self.ws_stream(mode) {
+            stream.scanning = None;
         }
         return None;
     }
 
+    /* 无法通过icap server扫描的websocket消息，按失败策略处理 */
+    fn ws_bypass(&mut self, mode: IcapMode) -> Option<WriteBuffer> {
+        let policy = match mode {
+            IcapMode::REQMOD => self.reqmod_fail_policy,
+            IcapMode::RESPMOD => self.respmod_fail_policy,
+        };
+        if policy == LocalConfigIcapFailPolicy::FailOpen {
+            println!("ICAP unavailable, forward websocket message unscanned [{}]", self.conn_tuple);
+            return self.ws_allow(mode);
+        }
+        return self.ws_block(mode, "Content scanning service is unavailable");
+    }
+
+    /*
+    * 阻断websocket消息: 扣留的数据全部丢弃，关闭连接
+    * http client端收到的数据在帧的边界时，先发送关闭帧
+    */
+    fn ws_block(&mut self, mode: IcapMode, reason: &str) -> Option<WriteBuffer> {
+        let direction = match mode {
+            IcapMode::REQMOD => "client",
+            IcapMode::RESPMOD => "server",
+        };
+        println!("ALERT: block websocket {} message {} [{}]", direction, self.message_url(IcapMode::REQMOD), self.conn_tuple);
+        let aligned = self.ws_up.as_ref().is_some_and(|s| s.aligned);
+        self.ws_down = None;
+        self.ws_up = None;
+        self.up_rejected = true;
+        if !aligned {
+            return None;
+        }
+        return Some(WriteBuffer::DOWN(ProtoWsCtx::build_close(WS_CLOSE_POLICY, reason)));
+    }
+
+    /* 对端没有发送close_notify就关闭了TLS连接，按连接关闭处理 */
+    fn read_result(result: Result<usize, std::io::Error>) -> Result<usize, std::io::Error> {
+        match result {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(0),
+            result => return result,
+        }
+    }
+
+    /* 没有借出icap连接时永远不返回 */
+    async fn read_icap(icap_conn: &mut Option<IcapConn>, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        match icap_conn {
+            Some(icap_conn) => icap_conn.socket().read(buffer).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /* 没有正在借出的icap连接时永远不返回 */
+    async fn connect_icap(icap_connect: &mut Option<IcapConnect<'_>>) -> Result<IcapConn, std::io::Error> {
+        match icap_connect {
+            Some((connect, _)) => connect.await,
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn write_service<D: AsyncWrite + Unpin, U: AsyncWrite + Unpin>(
+        msg: WriteBuffer,
+        up_socket: &mut U,
+        down_socket: &mut D,
+        icap_conn: &mut Option<IcapConn>,
+    ) -> Result<(), std::io::Error> {
+        match msg {
+            WriteBuffer::UP(msg) => up_socket.write_all(&msg).await,
+            WriteBuffer::DOWN(msg) => down_socket.write_all(&msg).await,
+            WriteBuffer::ICAP(msg) => match icap_conn {
+                Some(icap_conn) => {
+                    // ICAPS的数据在flush之后才真正发送
+                    icap_conn.socket().write_all(&msg).await?;
+                    icap_conn.socket().flush().await
+                }
+                None => Err(std::io::Error::other("ICAP 连接为空")),
+            },
+        }
+    }
+
     pub async fn accept_service(
         http_listen: &Option<TcpListener>,
     ) -> Result<TcpStream, std::io::Error> {
         if http_listen.is_none() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "HTTP 监听器为空",
-            ));
+            return Err(std::io::Error::other("HTTP 监听器为空"));
         }
         let listener = http_listen.as_ref().unwrap();
         let (socket, _) = listener.accept().await?;
         Ok(socket)
     }
 
-    pub async fn process_service(mut down_socket: TcpStream) -> Result<(), std::io::Error> {
-        let mut icap_socket = TcpStream::connect("127.0.0.1:1344").await?;
-
+    /*
+    * 处理一个http连接
+    * icap_remote为None时不进行icap扫描，直接转发
+    * 每个icap事务从连接池借出一个连接，事务结束后归还
+    * icap server失败时放行当前消息，后续事务重新选择icap server
+    */
+    pub async fn process_service(
+        down_socket: TcpStream,
+        icap_remote: Option<LocalConfigIcapRemote>,
+        http_config: LocalConfigHttp,
+        tls_config: Option<LocalConfigTls>,
+        tls: Arc<TlsMitm>,
+        icap: Arc<IcapClient>,
+    ) -> Result<(), std::io::Error> {
+        let client_addr = down_socket.peer_addr()?;
         let orig_dst = common_get_orig_dst(&down_socket)?;
+        // 需要解密的端口: 解密之后的明文走同样的http处理
+        if let Some(tls_config) = tls_config.filter(|c| c.ports.contains(&orig_dst.port())) {
+            // 不解密名单: 根据ClientHello的SNI或者目的ip判断，命中时原样转发
+            let hello = TlsMitm::peek_hello(&down_socket).await;
+            if let Some(rule) = TlsMitm::bypass(&tls_config, hello.as_ref().ok(), orig_dst.ip()) {
+                return Self::process_passthrough(down_socket, client_addr, orig_dst, hello.ok(), format!("bypass {}", rule)).await;
+            }
+            let hello = match hello {
+                Ok(hello) => Some(hello),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Err(e),
+                Err(e) => {
+                    // 超时或者以TLS握手记录开始但无法解析，原样转发; 其他数据按明文http处理，仍然进行icap扫描
+                    let mut first = [0u8; 1];
+                    let plain = e.kind() == std::io::ErrorKind::InvalidData
+                        && matches!(down_socket.peek(&mut first).await, Ok(1))
+                        && !ProtoTlsHello::is_handshake(&first);
+                    if !plain {
+                        return Self::process_passthrough(down_socket, client_addr, orig_dst, None, format!("ClientHello unusable: {}", e)).await;
+                    }
+                    println!("ALERT: non-TLS traffic on intercept port [tcp {} -> {}]: {}, handle as plain http", client_addr, orig_dst, e);
+                    None
+                }
+            };
+            if let Some(hello) = hello {
+                match tls.intercept(&tls_config, down_socket, orig_dst, &hello).await? {
+                    TlsMitmOutcome::INTERCEPT(down_socket, up_socket, host) => {
+                        let conn_tuple = format!("tls {} -> {} {}", client_addr, orig_dst, host);
+                        // 两端都协商了h2，按流拆分后每个流走同样的http处理
+                        if down_socket.get_ref().1.alpn_protocol() == Some(b"h2") {
+                            return Http2::process_connection(*down_socket, *up_socket, client_addr, orig_dst, conn_tuple, icap_remote, http_config, icap).await;
+                        }
+                        return Self::process_stream(down_socket, up_socket, client_addr, orig_dst, conn_tuple, icap_remote, http_config, icap).await;
+                    }
+                    TlsMitmOutcome::PASSTHROUGH(down_socket, reason) => {
+                        return Self::process_passthrough(down_socket, client_addr, orig_dst, Some(hello), reason).await;
+                    }
+                }
+            }
+        }
+        let up_socket = TcpStream::connect(orig_dst).await?;
+        let conn_tuple = format!("tcp {} -> {}", client_addr, orig_dst);
+        return Self::process_stream(down_socket, up_socket, client_addr, orig_dst, conn_tuple, icap_remote, http_config, icap).await;
+    }
+
+    /* 不解密的TLS连接，原样转发到原始目的地址，reason为不解密的原因 */
+    async fn process_passthrough(
+        mut down_socket: TcpStream,
+        client_addr: SocketAddr,
+        orig_dst: SocketAddr,
+        hello: Option<ProtoTlsHello>,
+        reason: String,
+    ) -> Result<(), std::io::Error> {
+        let (sni, alpn) = match &hello {
+            Some(hello) => (hello.sni.clone().unwrap_or_else(|| "-".to_string()), hello.alpn.join(",")),
+            None => ("-".to_string(), String::new()),
+        };
+        let conn_tuple = format!("tls {} -> {} {}", client_addr, orig_dst, sni);
+        println!("TLS passthrough [{}] alpn {}: {}", conn_tuple, alpn, reason);
         let mut up_socket = TcpStream::connect(orig_dst).await?;
+        // 一端复位连接时仍然记录连接结束
+        match tokio::io::copy_bidirectional(&mut down_socket, &mut up_socket).await {
+            Ok((sent, received)) => println!("TLS passthrough closed [{}] sent {} received {}", conn_tuple, sent, received),
+            Err(e) => println!("TLS passthrough closed [{}]: {}", conn_tuple, e),
+        }
+        return Ok(());
+    }
 
+    #[allow(clippy::too_many_arguments)]
+    pub async fn process_stream<D: AsyncRead + AsyncWrite + Unpin, U: AsyncRead + AsyncWrite + Unpin>(
+        mut down_socket: D,
+        mut up_socket: U,
+        client_addr: SocketAddr,
+        orig_dst: SocketAddr,
+        conn_tuple: String,
+        icap_remote: Option<LocalConfigIcapRemote>,
+        http_config: LocalConfigHttp,
+        icap: Arc<IcapClient>,
+    ) -> Result<(), std::io::Error> {
+        let client_ip = client_addr.ip();
         let mut http = Http::new();
+        http.http_ctx.set_header_limits(http_config.max_headers, http_config.max_header_size);
+        http.icap_ctx.set_max_headers(http_config.max_headers);
+        http.oversize_policy = http_config.oversize_policy;
+        http.strict_policy = http_config.strict_policy;
+        http.held_max = http_config.max_held_body;
+        http.http_ctx.set_strict(http_config.strict_policy != LocalConfigHttpStrict::Off);
+        http.conn_tuple = conn_tuple;
+        if http_config.decode_enable {
+            http.decode_limit = Some(ProtoHttpDecodeLimit {
+                max_size: http_config.decode_max_size,
+                max_ratio: http_config.decode_max_ratio,
+            });
+        }
+        let mut icap_server: Option<String> = None;
+        if let Some(icap_remote) = &icap_remote {
+            http.reqmod_fail_policy = icap_remote.reqmod_fail_policy;
+            http.respmod_fail_policy = icap_remote.respmod_fail_policy;
+            http.icap_ctx.set_max_body(icap_remote.max_body_size);
+            http.icap_identity = IcapClient::identity_headers(&icap_remote.identity, client_ip, orig_dst.ip());
+            http.multipart_mode = icap_remote.multipart;
+            http.ws_inspect = http_config.websocket_inspect;
+            http.ws_max_message = http_config.websocket_max_message;
+            icap_server = http.select_icap(icap_remote, &icap, client_ip).await;
+        }
+        let mut icap_conn: Option<IcapConn> = None;
+        // 连接池中没有空闲连接时需要等待，期间两个方向继续转发，新的icap消息等连接借出后再发送
+        let mut icap_connect: Option<IcapConnect> = None;
+        // icap server在responseTimeout内没有响应，视为失败
+        let response_timeout = Duration::from_secs(icap_remote.as_ref().map_or(0, |r| r.response_timeout));
+        let mut icap_deadline = Instant::now();
+
         let mut buffer_down = [0u8; 8192];
         let mut buffer_up = [0u8; 8192];
         let mut buffer_icap = [0u8; 8192];
         loop {
+            // icap连接失败、被关闭或者响应超时
+            let mut icap_failed = false;
+            // 连接池等待超时说明icap server繁忙，不计入健康检查的失败
+            let mut icap_busy = false;
             tokio::select! {
-                msg = up_socket.read(&mut buffer_up) => {
-                    match msg {
+                msg = up_socket.read(&mut buffer_up), if !http.up_closed => {
+                    match Self::read_result(msg) {
                         Ok(n) => {
-                            if n == 0 { break; }
-                            let msg = http.read_service_up(&mut buffer_up, n);
-                            if msg.is_some() {
-                                let msg = msg.unwrap();
+                            if n == 0 {
+                                // 响应仍在等待icap处理，延迟关闭
+                                if !http.close_service_up() { break; }
+                            } else if let Some(msg) = http.read_service_up(&buffer_up, n) {
                                 down_socket.write_all(&msg).await?;
                             }
                         }
@@ -218,12 +1883,10 @@ impl Http {
                 }
 
                 msg = down_socket.read(&mut buffer_down) => {
-                    match msg {
+                    match Self::read_result(msg) {
                         Ok(n) => {
                             if n == 0 { break; }
-                            let msg = http.read_service_down(&mut buffer_down, n);
-                            if msg.is_some() {
-                                let msg = msg.unwrap();
+                            if let Some(msg) = http.read_service_down(&buffer_down, n) {
                                 up_socket.write_all(&msg).await?;
                             }
                         }
@@ -231,36 +1894,120 @@ impl Http {
                     }
                 }
 
-                msg = icap_socket.read(&mut buffer_icap) => {
+                msg = Self::read_icap(&mut icap_conn, &mut buffer_icap) => {
                     match msg {
-                        Ok(n) => {
-                            if n == 0 { break; }
-                            let msg = http.read_service_icap(&mut buffer_icap, n);
-                            if msg.is_some() {
-                                let msg = msg.unwrap();
-                                match msg {
-                                    WriteBuffer::UP(msg) => { up_socket.write_all(&msg).await?; }
-                                    WriteBuffer::DOWN(msg) => { down_socket.write_all(&msg).await?; }
-                                    WriteBuffer::_ICAP(msg) => { icap_socket.write_all(&msg).await?; }
+                        Ok(n) if n > 0 => {
+                            icap_deadline = Instant::now() + response_timeout;
+                            if let Some(msg) = http.read_service_icap(&buffer_icap, n) {
+                                Self::write_service(msg, &mut up_socket, &mut down_socket, &mut icap_conn).await?;
+                            }
+                            // icap事务结束，归还连接
+                            if !http.icap_ctx.get_pending() {
+                                if let Some(mut conn) = icap_conn.take() {
+                                    conn.set_reusable(http.icap_reusable);
                                 }
                             }
                         }
-                        Err(e) => { return Err(e); }
+                        Ok(_) => { icap_failed = true; }
+                        Err(e) => {
+                            println!("ICAP connection error: {}", e);
+                            icap_failed = true;
+                        }
+                    }
+                }
+
+                conn = Self::connect_icap(&mut icap_connect) => {
+                    let (_, msg) = icap_connect.take().unwrap();
+                    match conn {
+                        Ok(conn) => {
+                            icap_conn = Some(conn);
+                            if Self::write_service(msg, &mut up_socket, &mut down_socket, &mut icap_conn).await.is_ok() {
+                                icap_deadline = Instant::now() + response_timeout;
+                            } else {
+                                icap_failed = true;
+                            }
+                        }
+                        Err(e) => {
+                            println!("ICAP connect {} failed: {}", icap_server.as_deref().unwrap_or("-"), e);
+                            icap_failed = true;
+                            icap_busy = e.kind() == std::io::ErrorKind::ResourceBusy;
+                        }
                     }
                 }
 
-                msg = http.pending_service() => {
-                    if let Some(msg) = msg {
-                        match msg {
-                            WriteBuffer::UP(msg) => { up_socket.write_all(&msg).await?; }
-                            WriteBuffer::DOWN(msg) => { down_socket.write_all(&msg).await?; }
-                            WriteBuffer::_ICAP(msg) => { icap_socket.write_all(&msg).await?; }
+                _ = tokio::time::sleep_until(icap_deadline), if icap_conn.is_some() && http.icap_waiting() => {
+                    println!("ICAP response timed out after {}s", response_timeout.as_secs());
+                    icap_failed = true;
+                }
+            }
+
+            if let Some(icap_remote) = &icap_remote {
+                if icap_failed {
+                    if let Some(server) = icap_server.take() {
+                        if !icap_busy {
+                            icap.balancer.report(icap_remote, &server, false);
+                        }
+                    }
+                    icap_conn = None;
+                    http.icap_down();
+                    if let Some(msg) = http.icap_failed() {
+                        Self::write_service(msg, &mut up_socket, &mut down_socket, &mut icap_conn).await?;
+                    }
+                }
+                if !http.icap_ctx.get_pending() {
+                    // Options-TTL超时，在下一个事务开始前重新获取
+                    if let Some(server) = &icap_server {
+                        if http.options_expired() && !http.update_options(icap_remote, &icap, server).await {
+                            icap.balancer.report(icap_remote, server, false);
+                            icap_server = None;
                         }
                     }
+                    // 之前的icap server失败，或者没有可用的icap server，重新选择
+                    if icap_server.is_none() {
+                        icap_server = http.select_icap(icap_remote, &icap, client_ip).await;
+                    }
                 }
+            }
 
+            // 发送缓存中待发送的数据，新的icap事务从连接池借出连接; 借出连接之前的数据保留在缓存中
+            while icap_connect.is_none() {
+                let Some(msg) = http.pending_service() else {
+                    break;
+                };
+                let (Some(icap_remote), Some(server), WriteBuffer::ICAP(_)) = (&icap_remote, &icap_server, &msg) else {
+                    Self::write_service(msg, &mut up_socket, &mut down_socket, &mut icap_conn).await?;
+                    continue;
+                };
+                if icap_conn.is_none() {
+                    let (pool, server, max_connections) = (&icap.pool, server.clone(), http.icap_max_connections());
+                    let connect = Box::pin(async move { pool.get(icap_remote, &server, max_connections).await });
+                    icap_connect = Some((connect, msg));
+                    break;
+                }
+                if Self::write_service(msg, &mut up_socket, &mut down_socket, &mut icap_conn).await.is_ok() {
+                    icap_deadline = Instant::now() + response_timeout;
+                    continue;
+                }
+                // icap server不可用，按失败策略处理当前消息
+                icap.balancer.report(icap_remote, server, false);
+                icap_server = None;
+                icap_conn = None;
+                http.icap_down();
+                if let Some(msg) = http.icap_failed() {
+                    Self::write_service(msg, &mut up_socket, &mut down_socket, &mut icap_conn).await?;
+                }
+            }
+            if http.up_closed && !http.close_service_up() {
+                break;
+            }
+            // 消息头超过限制，本地响应发送后关闭连接
+            if http.rejected() {
+                break;
             }
         }
+        // TLS连接需要发送close_notify，http client端才能区分正常结束与截断
+        _ = down_socket.shutdown().await;
+        _ = up_socket.shutdown().await;
         Ok(())
     }
 }
@@ -0,0 +1,1165 @@
+use crate::config::local_json::{LocalConfigHttp, LocalConfigIcapRemote};
+use crate::protocol::hpack::{ProtoHpackDecoder, ProtoHpackEncoder, ProtoHpackField};
+use crate::protocol::http::{ProtoHttpBody, ProtoHttpFraming};
+use crate::protocol::http2::{
+    ProtoH2Ctx, ProtoH2Frame, ProtoH2Section, H2_CANCEL, H2_COMPRESSION_ERROR, H2_DEFAULT_FRAME_SIZE, H2_DEFAULT_TABLE_SIZE, H2_DEFAULT_WINDOW,
+    H2_ENHANCE_YOUR_CALM, H2_FLAG_ACK, H2_FLAG_END_STREAM, H2_FLOW_CONTROL_ERROR, H2_FRAME_DATA, H2_FRAME_GOAWAY, H2_FRAME_HEADERS, H2_FRAME_PING,
+    H2_FRAME_PUSH_PROMISE, H2_FRAME_RST_STREAM, H2_FRAME_SETTINGS, H2_FRAME_SIZE_ERROR, H2_FRAME_WINDOW_UPDATE,
+    H2_INTERNAL_ERROR, H2_NO_ERROR, H2_PREFACE, H2_PROTOCOL_ERROR, H2_REFUSED_STREAM, H2_SETTINGS_ENABLE_PUSH,
+    H2_SETTINGS_INITIAL_WINDOW_SIZE, H2_SETTINGS_MAX_CONCURRENT_STREAMS, H2_SETTINGS_MAX_FRAME_SIZE,
+};
+use crate::proxy::http::Http;
+use crate::proxy::icap::IcapClient;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+// http client端可以同时打开的流数
+const H2_MAX_STREAMS: u32 = 100;
+// 每个流与http处理之间的管道大小
+const H2_PIPE_SIZE: usize = 64 * 1024;
+// 一个流等待发送的数据超过该字节数时，暂停读取http处理的输出
+const H2_STREAM_BUFFER: usize = 256 * 1024;
+// 流控窗口的最大值
+const H2_MAX_WINDOW: i64 = 0x7fff_ffff;
+// 一端等待写出的数据超过该字节数时，暂停读取该端以及流任务的输出
+const H2_WRITE_BUFFER: usize = 1024 * 1024;
+// 连接结束时写出GOAWAY的超时
+const H2_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+// http client端在统计周期内可以复位的流数，超过时以ENHANCE_YOUR_CALM关闭连接
+const H2_MAX_RESETS: u32 = 200;
+const H2_RESET_PERIOD: Duration = Duration::from_secs(10);
+
+/* http/2连接的两端 */
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+enum Http2Side {
+    // http client端
+    DOWN = 0,
+    // http server端
+    UP = 1,
+}
+
+/* 连接任务发送给流任务的消息，side为帧的来源 */
+#[allow(clippy::upper_case_acronyms)]
+enum Http2ToStream {
+    HEADERS(Http2Side, Vec<ProtoHpackField>, bool),
+    // 数据、占用的接收窗口(包含填充)、是否结束流
+    DATA(Http2Side, Vec<u8>, usize, bool),
+    // 已经发送出去的数据字节数
+    FLUSHED(Http2Side, usize),
+}
+
+/* 流任务发送给连接任务的消息 */
+#[allow(clippy::upper_case_acronyms)]
+enum Http2FromStream {
+    // 发送到side的头部与数据
+    HEADERS(Http2Side, Vec<ProtoHpackField>, bool),
+    DATA(Http2Side, Vec<u8>, bool),
+    // 发送到side的trailers，在数据之后结束流
+    TRAILERS(Http2Side, Vec<ProtoHpackField>),
+    // 来自side的数据已经被http处理读取，可以归还接收窗口
+    CONSUMED(Http2Side, usize),
+    // 流任务结束，H2_NO_ERROR表示响应已经完整输出
+    DONE(u32),
+}
+
+/* http/2连接一端的状态 */
+struct Http2Leg {
+    ctx: ProtoH2Ctx,
+    decoder: ProtoHpackDecoder,
+    // 等待写出的帧
+    out: Vec<u8>,
+    // 对端的连接级发送窗口、流的初始发送窗口、最大帧大小、最大并发流数
+    window: i64,
+    initial_window: i64,
+    max_frame: usize,
+    max_streams: u32,
+    // 对端发送了GOAWAY，不再打开新的流
+    goaway: bool,
+}
+
+/* 一个方向等待发送的数据 */
+struct Http2Send {
+    window: i64,
+    data: Vec<u8>,
+    // 数据之后结束流，以及结束流的trailers
+    end: bool,
+    trailers: Option<Vec<ProtoHpackField>>,
+    // 已经发送END_STREAM
+    closed: bool,
+}
+
+/*
+* http client端的一个流，以及对应的http server端的流
+* 被复位的流在流任务结束之前仍然计入并发流数
+*/
+struct Http2Stream {
+    // 复位之后为None，流任务因此结束
+    tx: Option<UnboundedSender<Http2ToStream>>,
+    // 流的http处理任务，复位时取消
+    engine: JoinHandle<()>,
+    // http server端的流id，发送请求头时分配
+    up_id: Option<u32>,
+    // 等待分配http server端流id的请求头
+    up_headers: Option<(Vec<ProtoHpackField>, bool)>,
+    // 按Http2Side索引: 发送状态、接收窗口、对端已经结束流、已经收到最终的消息头(之后的HEADERS为trailers)
+    send: [Http2Send; 2],
+    recv_window: [i64; 2],
+    recv_closed: [bool; 2],
+    recv_head: [bool; 2],
+    // 流任务已经结束
+    done: Option<u32>,
+}
+
+/* 写入http处理的一个方向 */
+struct Http2Pipe {
+    // 待写入的数据，以及写完之后归还的接收窗口
+    pending: VecDeque<(Vec<u8>, usize)>,
+    offset: usize,
+    // 已经写入最终的消息头，消息体使用chunked编码
+    head: bool,
+    chunked: bool,
+    // 消息已经结束，以及已经关闭写方向
+    ended: bool,
+    shutdown: bool,
+}
+
+/* 读取http处理输出的一个方向，转换为http/2的头部与数据 */
+struct Http2Reader {
+    buffer: Vec<u8>,
+    body: Option<ProtoHttpBody>,
+    done: bool,
+    // 已经交给连接任务、尚未发送出去的字节数
+    outstanding: usize,
+}
+
+/*
+* 解密后两端都协商了h2的连接
+* 1. 每个http client端的流对应一个http处理(与http/1.1连接相同的ICAP REQMOD/RESPMOD流程)
+*    流的HEADERS与DATA转换为http/1.1请求写入http处理，http处理输出的请求再转换为http server端的流
+*    http server端的响应按同样方式经过http处理返回给http client端
+* 2. 连接级接收窗口在收到数据时立即归还，流的接收窗口在http处理读取数据之后归还
+*    等待ICAP结果的流不影响其它流
+* 3. trailers作为chunked消息体的trailer字段经过http处理，之后转换为结束流的HEADERS
+*/
+pub struct Http2 {
+    // 按Http2Side索引
+    legs: [Http2Leg; 2],
+    streams: HashMap<u32, Http2Stream>,
+    // http server端流id到http client端流id
+    up_ids: HashMap<u32, u32>,
+    last_down: u32,
+    next_up: u32,
+    // 等待http server端并发流数的流
+    waiting: VecDeque<u32>,
+    // 统计周期的开始时间，以及周期内http client端复位的流数
+    reset_start: Instant,
+    resets: u32,
+    encoder: ProtoHpackEncoder,
+    tx: UnboundedSender<(u32, Http2FromStream)>,
+    // 每个流的http处理使用的参数
+    client_addr: SocketAddr,
+    orig_dst: SocketAddr,
+    conn_tuple: String,
+    icap_remote: Option<LocalConfigIcapRemote>,
+    http_config: LocalConfigHttp,
+    icap: Arc<IcapClient>,
+}
+
+impl Http2Side {
+    fn name(self) -> &'static str {
+        match self {
+            Http2Side::DOWN => return "client",
+            Http2Side::UP => return "server",
+        }
+    }
+
+    fn other(self) -> Http2Side {
+        match self {
+            Http2Side::DOWN => return Http2Side::UP,
+            Http2Side::UP => return Http2Side::DOWN,
+        }
+    }
+}
+
+impl Http2Leg {
+    /* 解码后的头部与http/1.1消息头使用相同的数量与大小限制 */
+    fn new(server: bool, http_config: &LocalConfigHttp) -> Self {
+        Self {
+            ctx: ProtoH2Ctx::new(server),
+            decoder: ProtoHpackDecoder::new(H2_DEFAULT_TABLE_SIZE, http_config.max_headers, http_config.max_header_size),
+            out: Vec::new(),
+            window: H2_DEFAULT_WINDOW as i64,
+            initial_window: H2_DEFAULT_WINDOW as i64,
+            max_frame: H2_DEFAULT_FRAME_SIZE,
+            max_streams: u32::MAX,
+            goaway: false,
+        }
+    }
+}
+
+impl Http2Send {
+    fn new(window: i64) -> Self {
+        Self {
+            window,
+            data: Vec::new(),
+            end: false,
+            trailers: None,
+            closed: false,
+        }
+    }
+}
+
+impl Http2Stream {
+    /* 转发给流任务，已经复位的流忽略 */
+    fn notify(&self, msg: Http2ToStream) {
+        if let Some(tx) = self.tx.as_ref() {
+            _ = tx.send(msg);
+        }
+    }
+
+    fn is_reset(&self) -> bool {
+        self.tx.is_none()
+    }
+
+    /* side一端的流id */
+    fn wire_id(&self, id: u32, side: Http2Side) -> Option<u32> {
+        match side {
+            Http2Side::DOWN => return Some(id),
+            Http2Side::UP => return self.up_id,
+        }
+    }
+}
+
+impl Http2Pipe {
+    fn new() -> Self {
+        Self {
+            pending: VecDeque::new(),
+            offset: 0,
+            head: false,
+            chunked: false,
+            ended: false,
+            shutdown: false,
+        }
+    }
+
+    fn push(&mut self, data: Vec<u8>, credit: usize) {
+        if !data.is_empty() || credit > 0 {
+            self.pending.push_back((data, credit));
+        }
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn front(&self) -> &[u8] {
+        return self.pending.front().map_or(&[], |(data, _)| &data[self.offset..]);
+    }
+
+    /* 写入了n字节，返回写完的数据归还的接收窗口 */
+    fn advance(&mut self, n: usize) -> usize {
+        self.offset += n;
+        let mut credit = 0;
+        while let Some((data, _)) = self.pending.front() {
+            if self.offset < data.len() {
+                break;
+            }
+            credit += self.pending.pop_front().map_or(0, |(_, c)| c);
+            self.offset = 0;
+        }
+        return credit;
+    }
+
+    /* 消息结束，chunked编码时写入最后一个chunk */
+    fn finish(&mut self) {
+        if !self.ended && self.chunked {
+            self.push(ProtoH2Ctx::build_chunk(&[]), 0);
+        }
+        self.ended = true;
+    }
+
+    fn data(&mut self, data: Vec<u8>, credit: usize, end: bool) {
+        if self.ended {
+            self.push(Vec::new(), credit);
+            return;
+        }
+        let data = match self.chunked && !data.is_empty() {
+            true => ProtoH2Ctx::build_chunk(&data),
+            false => data,
+        };
+        self.push(data, credit);
+        if end {
+            self.finish();
+        }
+    }
+}
+
+impl Http2Reader {
+    fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            body: None,
+            done: false,
+            outstanding: 0,
+        }
+    }
+
+    /*
+    * 输入http处理输出的数据
+    * side为DOWN时是返回给http client端的响应，UP时是发送给http server端的请求
+    * 1xx响应作为不结束流的HEADERS输出，之后继续解析最终响应
+    */
+    fn feed(&mut self, side: Http2Side, data: &[u8], method: &str, max_headers: usize) -> Result<Vec<Http2FromStream>, String> {
+        let mut out = Vec::new();
+        if self.done {
+            return Ok(out);
+        }
+        self.buffer.extend_from_slice(data);
+        loop {
+            let Some(body) = self.body.as_mut() else {
+                let parsed = match side {
+                    Http2Side::DOWN => ProtoH2Ctx::parse_h1_response(&self.buffer, max_headers, method)?,
+                    Http2Side::UP => ProtoH2Ctx::parse_h1_request(&self.buffer, max_headers)?,
+                };
+                let Some(message) = parsed else {
+                    break;
+                };
+                self.buffer.drain(..message.size);
+                if (100..200).contains(&message.code) {
+                    out.push(Http2FromStream::HEADERS(side, message.fields, false));
+                    continue;
+                }
+                let empty = message.framing == ProtoHttpFraming::NONE;
+                out.push(Http2FromStream::HEADERS(side, message.fields, empty));
+                if empty {
+                    self.done = true;
+                    break;
+                }
+                let mut body = ProtoHttpBody::new(message.framing);
+                body.keep_trailers();
+                self.body = Some(body);
+                continue;
+            };
+            if self.buffer.is_empty() {
+                break;
+            }
+            let mut decoded = Vec::new();
+            let n = body.feed(&self.buffer, Some(&mut decoded))?;
+            self.buffer.drain(..n);
+            self.done = body.is_done();
+            let trailers = match self.done {
+                true => ProtoH2Ctx::h2_trailers(&body.take_trailers()),
+                false => Vec::new(),
+            };
+            let end = self.done && trailers.is_empty();
+            if !decoded.is_empty() || end {
+                self.outstanding += decoded.len();
+                out.push(Http2FromStream::DATA(side, decoded, end));
+            }
+            if !trailers.is_empty() {
+                out.push(Http2FromStream::TRAILERS(side, trailers));
+            }
+            break;
+        }
+        return Ok(out);
+    }
+
+    /* http处理关闭了该方向，读取到连接关闭为止的消息体正常结束 */
+    fn close(&mut self) -> Result<Option<Http2FromStream>, String> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        match self.body.as_ref().map(|b| b.framing()) {
+            Some(ProtoHttpFraming::CLOSE) => return Ok(Some(Http2FromStream::DATA(Http2Side::DOWN, Vec::new(), true))),
+            _ => return Err("HTTP message truncated".to_string()),
+        }
+    }
+}
+
+impl Http2 {
+    /*
+    * 处理一个http/2连接，down_socket与up_socket都已经协商了h2
+    * 任意一端关闭或者出错时结束连接，向两端发送GOAWAY
+    */
+    #[allow(clippy::too_many_arguments)]
+    pub async fn process_connection<D: AsyncRead + AsyncWrite + Unpin, U: AsyncRead + AsyncWrite + Unpin>(
+        down_socket: D,
+        up_socket: U,
+        client_addr: SocketAddr,
+        orig_dst: SocketAddr,
+        conn_tuple: String,
+        icap_remote: Option<LocalConfigIcapRemote>,
+        http_config: LocalConfigHttp,
+        icap: Arc<IcapClient>,
+    ) -> Result<(), std::io::Error> {
+        println!("HTTP/2 [{}] start", conn_tuple);
+        let (tx, mut rx) = unbounded_channel();
+        let mut h2 = Http2 {
+            legs: [Http2Leg::new(true, &http_config), Http2Leg::new(false, &http_config)],
+            streams: HashMap::new(),
+            up_ids: HashMap::new(),
+            last_down: 0,
+            next_up: 1,
+            waiting: VecDeque::new(),
+            reset_start: Instant::now(),
+            resets: 0,
+            encoder: ProtoHpackEncoder::new(),
+            tx,
+            client_addr,
+            orig_dst,
+            conn_tuple,
+            icap_remote,
+            http_config,
+            icap,
+        };
+        h2.legs[Http2Side::DOWN as usize].out = ProtoH2Ctx::build_settings(&[(H2_SETTINGS_MAX_CONCURRENT_STREAMS, H2_MAX_STREAMS)]);
+        h2.legs[Http2Side::UP as usize].out = H2_PREFACE.to_vec();
+        h2.legs[Http2Side::UP as usize].out.extend(ProtoH2Ctx::build_settings(&[(H2_SETTINGS_ENABLE_PUSH, 0)]));
+
+        let (mut down_read, mut down_write) = tokio::io::split(down_socket);
+        let (mut up_read, mut up_write) = tokio::io::split(up_socket);
+        let mut buffer_down = [0u8; 16384];
+        let mut buffer_up = [0u8; 16384];
+        let (down, up) = (Http2Side::DOWN as usize, Http2Side::UP as usize);
+        // 写入之后尚未flush(TLS记录可能还在缓冲中)
+        let mut dirty = [false, false];
+        let reason = loop {
+            // 写出与读取都在select中进行，一端不读取时不影响另一端
+            let readable = [h2.legs[down].out.len() < H2_WRITE_BUFFER, h2.legs[up].out.len() < H2_WRITE_BUFFER];
+            let result = tokio::select! {
+                msg = down_read.read(&mut buffer_down), if readable[down] => match msg {
+                    Ok(n) if n > 0 => h2.read_frames(Http2Side::DOWN, &buffer_down[..n]),
+                    // 没有close_notify的关闭同样视为正常关闭
+                    Ok(_) => break "client closed".to_string(),
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break "client closed".to_string(),
+                    Err(e) => break format!("client error: {}", e),
+                },
+                msg = up_read.read(&mut buffer_up), if readable[up] => match msg {
+                    Ok(n) if n > 0 => h2.read_frames(Http2Side::UP, &buffer_up[..n]),
+                    Ok(_) => break "server closed".to_string(),
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break "server closed".to_string(),
+                    Err(e) => break format!("server error: {}", e),
+                },
+                Some((id, msg)) = rx.recv(), if readable[down] && readable[up] => {
+                    h2.stream_message(id, msg);
+                    Ok(())
+                }
+                n = Self::write_some(&mut down_write, &h2.legs[down].out), if dirty[down] || !h2.legs[down].out.is_empty() => match n {
+                    Ok(n) => {
+                        h2.legs[down].out.drain(..n);
+                        dirty[down] = n > 0;
+                        Ok(())
+                    }
+                    Err(e) => break format!("client error: {}", e),
+                },
+                n = Self::write_some(&mut up_write, &h2.legs[up].out), if dirty[up] || !h2.legs[up].out.is_empty() => match n {
+                    Ok(n) => {
+                        h2.legs[up].out.drain(..n);
+                        dirty[up] = n > 0;
+                        Ok(())
+                    }
+                    Err(e) => break format!("server error: {}", e),
+                },
+            };
+            if let Err((side, code, e)) = result {
+                h2.goaway(side, code);
+                break format!("{} protocol error: {}", side.name(), e);
+            }
+            if h2.legs.iter().any(|leg| leg.goaway) && h2.streams.is_empty() {
+                break "GOAWAY received".to_string();
+            }
+        };
+        // 通知两端连接结束，出错的一端已经有带错误码的GOAWAY; 对端不读取时不等待
+        for side in [Http2Side::DOWN, Http2Side::UP] {
+            if h2.legs[side as usize].out.is_empty() {
+                h2.goaway(side, H2_NO_ERROR);
+            }
+        }
+        _ = tokio::time::timeout(H2_CLOSE_TIMEOUT, Self::write_leg(&mut h2.legs[down], &mut down_write)).await;
+        _ = tokio::time::timeout(H2_CLOSE_TIMEOUT, Self::write_leg(&mut h2.legs[up], &mut up_write)).await;
+        _ = tokio::time::timeout(H2_CLOSE_TIMEOUT, down_write.shutdown()).await;
+        _ = tokio::time::timeout(H2_CLOSE_TIMEOUT, up_write.shutdown()).await;
+        for stream in h2.streams.values() {
+            stream.engine.abort();
+        }
+        println!("HTTP/2 [{}] closed: {}, {} streams aborted", h2.conn_tuple, reason, h2.streams.len());
+        Ok(())
+    }
+
+    /* 写出一部分等待的帧，返回写出的字节数; 已经全部写出时flush，返回0 */
+    async fn write_some<W: AsyncWrite + Unpin>(socket: &mut W, out: &[u8]) -> Result<usize, std::io::Error> {
+        if out.is_empty() {
+            socket.flush().await?;
+            return Ok(0);
+        }
+        match socket.write(out).await? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            n => return Ok(n),
+        }
+    }
+
+    /* 连接结束时写出剩余的帧 */
+    async fn write_leg<W: AsyncWrite + Unpin>(leg: &mut Http2Leg, socket: &mut W) -> Result<(), std::io::Error> {
+        if !leg.out.is_empty() {
+            socket.write_all(&leg.out).await?;
+            leg.out.clear();
+        }
+        socket.flush().await
+    }
+
+    fn goaway(&mut self, side: Http2Side, code: u32) {
+        // 不接受http server端发起的流
+        let last = match side {
+            Http2Side::DOWN => self.last_down,
+            Http2Side::UP => 0,
+        };
+        self.legs[side as usize].out.extend(ProtoH2Ctx::build_goaway(last, code));
+    }
+
+    /* 输入一端读取的数据，处理其中完整的帧; 连接错误返回(端, 错误码, 原因) */
+    fn read_frames(&mut self, side: Http2Side, data: &[u8]) -> Result<(), (Http2Side, u32, String)> {
+        self.legs[side as usize].ctx.feed(data);
+        loop {
+            let frame = match self.legs[side as usize].ctx.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(e) => return Err((side, H2_PROTOCOL_ERROR, e)),
+            };
+            self.process_frame(side, frame).map_err(|(code, e)| (side, code, e))?;
+        }
+    }
+
+    /* side一端的流id对应的http client端流id */
+    fn lookup(&self, side: Http2Side, wire: u32) -> Option<u32> {
+        match side {
+            Http2Side::DOWN => return self.streams.get(&wire).is_some_and(|stream| !stream.is_reset()).then_some(wire),
+            Http2Side::UP => return self.up_ids.get(&wire).copied(),
+        }
+    }
+
+    fn process_frame(&mut self, side: Http2Side, frame: ProtoH2Frame) -> Result<(), (u32, String)> {
+        let protocol_error = |e: String| (H2_PROTOCOL_ERROR, e);
+        let s = side as usize;
+        match frame.kind {
+            H2_FRAME_SETTINGS => {
+                if frame.stream != 0 {
+                    return Err(protocol_error("SETTINGS on a stream".to_string()));
+                }
+                if frame.flags & H2_FLAG_ACK != 0 {
+                    return Ok(());
+                }
+                for (id, value) in ProtoH2Ctx::settings(&frame).map_err(|e| (H2_FRAME_SIZE_ERROR, e))? {
+                    match id {
+                        H2_SETTINGS_INITIAL_WINDOW_SIZE => {
+                            if value as i64 > H2_MAX_WINDOW {
+                                return Err((H2_FLOW_CONTROL_ERROR, format!("initial window {} too large", value)));
+                            }
+                            // 已经打开的流按差值调整发送窗口
+                            let delta = value as i64 - self.legs[s].initial_window;
+                            self.legs[s].initial_window = value as i64;
+                            for stream in self.streams.values_mut() {
+                                stream.send[s].window += delta;
+                                if stream.send[s].window > H2_MAX_WINDOW {
+                                    return Err((H2_FLOW_CONTROL_ERROR, "stream window overflow".to_string()));
+                                }
+                            }
+                        }
+                        H2_SETTINGS_MAX_FRAME_SIZE => {
+                            if !(H2_DEFAULT_FRAME_SIZE as u32..=0xff_ffff).contains(&value) {
+                                return Err(protocol_error(format!("invalid max frame size {}", value)));
+                            }
+                            self.legs[s].max_frame = value as usize;
+                        }
+                        H2_SETTINGS_MAX_CONCURRENT_STREAMS => self.legs[s].max_streams = value,
+                        _ => {}
+                    }
+                }
+                self.legs[s].out.extend(ProtoH2Ctx::build_frame(H2_FRAME_SETTINGS, H2_FLAG_ACK, 0, &[]));
+                self.flush_all(side);
+                self.open_waiting();
+            }
+            H2_FRAME_PING => {
+                if frame.payload.len() != 8 {
+                    return Err((H2_FRAME_SIZE_ERROR, "invalid PING length".to_string()));
+                }
+                if frame.flags & H2_FLAG_ACK == 0 {
+                    self.legs[s].out.extend(ProtoH2Ctx::build_frame(H2_FRAME_PING, H2_FLAG_ACK, 0, &frame.payload));
+                }
+            }
+            H2_FRAME_WINDOW_UPDATE => {
+                let increment = ProtoH2Ctx::payload_u32(&frame).map_err(|e| (H2_FRAME_SIZE_ERROR, e))? as i64;
+                if frame.stream == 0 {
+                    if increment == 0 {
+                        return Err(protocol_error("connection WINDOW_UPDATE with zero increment".to_string()));
+                    }
+                    self.legs[s].window += increment;
+                    if self.legs[s].window > H2_MAX_WINDOW {
+                        return Err((H2_FLOW_CONTROL_ERROR, "connection window overflow".to_string()));
+                    }
+                    self.flush_all(side);
+                } else if let Some(id) = self.lookup(side, frame.stream) {
+                    let Some(stream) = self.streams.get_mut(&id) else {
+                        return Ok(());
+                    };
+                    // 流级别的错误只复位该流
+                    if increment == 0 {
+                        self.stream_error(id, side, H2_PROTOCOL_ERROR);
+                        return Ok(());
+                    }
+                    stream.send[s].window += increment;
+                    if stream.send[s].window > H2_MAX_WINDOW {
+                        self.stream_error(id, side, H2_FLOW_CONTROL_ERROR);
+                        return Ok(());
+                    }
+                    self.flush(id, side);
+                }
+            }
+            H2_FRAME_RST_STREAM => {
+                let code = ProtoH2Ctx::payload_u32(&frame).map_err(|e| (H2_FRAME_SIZE_ERROR, e))?;
+                if side == Http2Side::DOWN {
+                    self.count_reset()?;
+                }
+                if let Some(id) = self.lookup(side, frame.stream) {
+                    self.stream_reset(id, side, code);
+                }
+            }
+            H2_FRAME_GOAWAY => {
+                let last = ProtoH2Ctx::payload_u32(&frame).map_err(|e| (H2_FRAME_SIZE_ERROR, e))?;
+                let code = frame.payload.get(4..8).map_or(0, |c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]));
+                println!("HTTP/2 [{}] {} GOAWAY last stream {} error {}", self.conn_tuple, side.name(), last, code);
+                self.legs[s].goaway = true;
+                if side == Http2Side::UP {
+                    // http server端没有处理的流，http client端可以重试
+                    let refused: Vec<u32> = self.up_ids.iter().filter(|(up, _)| **up > last).map(|(_, id)| *id).collect();
+                    for id in refused {
+                        self.stream_reset(id, side, H2_REFUSED_STREAM);
+                    }
+                    self.open_waiting();
+                }
+            }
+            H2_FRAME_HEADERS => {
+                // 头部块必须解码，否则动态表与对端不一致
+                let fields = self.legs[s].decoder.decode(&frame.payload).map_err(|e| (H2_COMPRESSION_ERROR, e))?;
+                let end = frame.flags & H2_FLAG_END_STREAM != 0;
+                match side {
+                    Http2Side::DOWN => self.request_headers(frame.stream, fields, end)?,
+                    Http2Side::UP => self.response_headers(frame.stream, fields, end),
+                }
+            }
+            H2_FRAME_PUSH_PROMISE => {
+                // 已经禁用服务端推送，拒绝推送的流
+                let block = frame.payload.get(4..).ok_or(protocol_error("PUSH_PROMISE truncated".to_string()))?;
+                self.legs[s].decoder.decode(block).map_err(|e| (H2_COMPRESSION_ERROR, e))?;
+                let promised = ProtoH2Ctx::payload_u32(&frame).map_err(protocol_error)?;
+                self.legs[s].out.extend(ProtoH2Ctx::build_rst_stream(promised, H2_CANCEL));
+            }
+            H2_FRAME_DATA => {
+                let credit = frame.payload.len();
+                // 连接级窗口立即归还
+                if credit > 0 {
+                    self.legs[s].out.extend(ProtoH2Ctx::build_window_update(0, credit as u32));
+                }
+                let Some(id) = self.lookup(side, frame.stream) else {
+                    return Ok(());
+                };
+                let data = ProtoH2Ctx::data_payload(&frame).map_err(protocol_error)?.to_vec();
+                let end = frame.flags & H2_FLAG_END_STREAM != 0;
+                let Some(stream) = self.streams.get_mut(&id) else {
+                    return Ok(());
+                };
+                if stream.recv_window[s] < credit as i64 {
+                    self.stream_error(id, side, H2_FLOW_CONTROL_ERROR);
+                    return Ok(());
+                }
+                stream.recv_window[s] -= credit as i64;
+                stream.recv_closed[s] |= end;
+                stream.notify(Http2ToStream::DATA(side, data, credit, end));
+            }
+            _ => {}
+        }
+        return Ok(());
+    }
+
+    /* http client端的HEADERS: 新的流启动一个http处理，已有的流为trailers */
+    fn request_headers(&mut self, id: u32, fields: Vec<ProtoHpackField>, end: bool) -> Result<(), (u32, String)> {
+        let down = Http2Side::DOWN as usize;
+        if let Some(stream) = self.streams.get_mut(&id) {
+            if let Err(e) = Self::check_headers(&fields, ProtoH2Section::TRAILERS, end) {
+                println!("HTTP/2 [{}] client stream {} malformed: {}", self.conn_tuple, id, e);
+                self.stream_error(id, Http2Side::DOWN, H2_PROTOCOL_ERROR);
+                return Ok(());
+            }
+            stream.recv_closed[down] |= end;
+            stream.notify(Http2ToStream::HEADERS(Http2Side::DOWN, fields, end));
+            return Ok(());
+        }
+        if id.is_multiple_of(2) {
+            return Err((H2_PROTOCOL_ERROR, format!("client opened even stream {}", id)));
+        }
+        // 已经结束的流
+        if id <= self.last_down {
+            return Ok(());
+        }
+        self.last_down = id;
+        if let Err(e) = Self::check_headers(&fields, ProtoH2Section::REQUEST, end) {
+            println!("HTTP/2 [{}] client stream {} malformed: {}", self.conn_tuple, id, e);
+            self.legs[down].out.extend(ProtoH2Ctx::build_rst_stream(id, H2_PROTOCOL_ERROR));
+            return Ok(());
+        }
+        let connect = fields.iter().any(|(n, v)| n == b":method" && v == b"CONNECT");
+        if connect || self.legs.iter().any(|leg| leg.goaway) || self.streams.len() >= H2_MAX_STREAMS as usize {
+            self.legs[down].out.extend(ProtoH2Ctx::build_rst_stream(id, H2_REFUSED_STREAM));
+            return Ok(());
+        }
+
+        let (engine_down, stream_down) = tokio::io::duplex(H2_PIPE_SIZE);
+        let (engine_up, stream_up) = tokio::io::duplex(H2_PIPE_SIZE);
+        let (stream_tx, stream_rx) = unbounded_channel();
+        let conn_tuple = format!("{} stream {}", self.conn_tuple, id);
+        let (client_addr, orig_dst) = (self.client_addr, self.orig_dst);
+        let (icap_remote, http_config, icap) = (self.icap_remote.clone(), self.http_config.clone(), self.icap.clone());
+        let engine = tokio::spawn(async move {
+            if let Err(e) = Http::process_stream(engine_down, engine_up, client_addr, orig_dst, conn_tuple, icap_remote, http_config, icap).await {
+                println!("failed to process HTTP/2 stream; error = {e}");
+            }
+        });
+        tokio::spawn(Self::process_stream(id, stream_rx, self.tx.clone(), stream_down, stream_up, self.http_config.max_headers));
+        _ = stream_tx.send(Http2ToStream::HEADERS(Http2Side::DOWN, fields, end));
+        let window = H2_DEFAULT_WINDOW as i64;
+        self.streams.insert(
+            id,
+            Http2Stream {
+                tx: Some(stream_tx),
+                engine,
+                up_id: None,
+                up_headers: None,
+                send: [Http2Send::new(self.legs[down].initial_window), Http2Send::new(0)],
+                recv_window: [window, window],
+                recv_closed: [end, false],
+                recv_head: [true, false],
+                done: None,
+            },
+        );
+        return Ok(());
+    }
+
+    /* http server端的HEADERS: 1xx与最终的响应头，之后为trailers */
+    fn response_headers(&mut self, wire: u32, fields: Vec<ProtoHpackField>, end: bool) {
+        let up = Http2Side::UP as usize;
+        let Some(id) = self.lookup(Http2Side::UP, wire) else {
+            return;
+        };
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return;
+        };
+        let section = match stream.recv_head[up] {
+            true => ProtoH2Section::TRAILERS,
+            false => ProtoH2Section::RESPONSE,
+        };
+        if let Err(e) = Self::check_headers(&fields, section, end) {
+            println!("HTTP/2 [{}] server stream {} malformed: {}", self.conn_tuple, wire, e);
+            self.stream_error(id, Http2Side::UP, H2_PROTOCOL_ERROR);
+            return;
+        }
+        let interim = fields.iter().any(|(n, v)| n == b":status" && v.first() == Some(&b'1'));
+        stream.recv_head[up] |= !interim;
+        stream.recv_closed[up] |= end;
+        stream.notify(Http2ToStream::HEADERS(Http2Side::UP, fields, end));
+    }
+
+    /* 检查头部块，trailers必须结束流 */
+    fn check_headers(fields: &[ProtoHpackField], section: ProtoH2Section, end: bool) -> Result<(), String> {
+        ProtoH2Ctx::check_fields(fields, section)?;
+        if section == ProtoH2Section::TRAILERS && !end {
+            return Err("trailers without END_STREAM".to_string());
+        }
+        return Ok(());
+    }
+
+    /* 处理流任务的消息 */
+    fn stream_message(&mut self, id: u32, msg: Http2FromStream) {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return;
+        };
+        // 已经复位的流只等待流任务结束
+        if stream.is_reset() && !matches!(msg, Http2FromStream::DONE(_)) {
+            return;
+        }
+        match msg {
+            Http2FromStream::HEADERS(Http2Side::DOWN, fields, end) => {
+                let down = Http2Side::DOWN as usize;
+                let block = self.encoder.encode(&fields);
+                self.legs[down].out.extend(ProtoH2Ctx::build_headers(id, &block, end, self.legs[down].max_frame));
+                stream.send[down].closed |= end;
+                self.reap(id);
+            }
+            Http2FromStream::HEADERS(Http2Side::UP, fields, end) => {
+                if stream.up_id.is_none() && stream.up_headers.is_none() {
+                    stream.up_headers = Some((fields, end));
+                    self.waiting.push_back(id);
+                    self.open_waiting();
+                }
+            }
+            Http2FromStream::DATA(side, data, end) => {
+                let send = &mut stream.send[side as usize];
+                send.data.extend_from_slice(&data);
+                send.end |= end;
+                self.flush(id, side);
+                self.reap(id);
+            }
+            Http2FromStream::TRAILERS(side, fields) => {
+                let send = &mut stream.send[side as usize];
+                send.trailers = Some(fields);
+                send.end = true;
+                self.flush(id, side);
+                self.reap(id);
+            }
+            Http2FromStream::CONSUMED(side, n) => {
+                let s = side as usize;
+                if n == 0 || stream.recv_closed[s] {
+                    return;
+                }
+                stream.recv_window[s] += n as i64;
+                if let Some(wire) = stream.wire_id(id, side) {
+                    self.legs[s].out.extend(ProtoH2Ctx::build_window_update(wire, n as u32));
+                }
+            }
+            Http2FromStream::DONE(code) => {
+                stream.done = Some(code);
+                self.reap(id);
+            }
+        }
+    }
+
+    /* 在http server端并发流数之内，为等待的流分配流id并发送请求头 */
+    fn open_waiting(&mut self) {
+        let up = Http2Side::UP as usize;
+        while let Some(&id) = self.waiting.front() {
+            if self.legs[up].goaway {
+                self.waiting.pop_front();
+                self.stream_reset(id, Http2Side::UP, H2_REFUSED_STREAM);
+                continue;
+            }
+            if self.up_ids.len() as u32 >= self.legs[up].max_streams {
+                break;
+            }
+            self.waiting.pop_front();
+            let Some((fields, end)) = self.streams.get_mut(&id).and_then(|stream| stream.up_headers.take()) else {
+                continue;
+            };
+            let up_id = self.next_up;
+            self.next_up += 2;
+            self.up_ids.insert(up_id, id);
+            if let Some(stream) = self.streams.get_mut(&id) {
+                stream.up_id = Some(up_id);
+                // 分配流id之前已经收到的数据保留在data中
+                stream.send[up].window = self.legs[up].initial_window;
+                stream.send[up].closed = end;
+            }
+            let block = self.encoder.encode(&fields);
+            self.legs[up].out.extend(ProtoH2Ctx::build_headers(up_id, &block, end, self.legs[up].max_frame));
+            self.flush(id, Http2Side::UP);
+        }
+    }
+
+    fn flush_all(&mut self, side: Http2Side) {
+        let ids: Vec<u32> = self.streams.keys().copied().collect();
+        for id in ids {
+            self.flush(id, side);
+            self.reap(id);
+        }
+    }
+
+    /* 在发送窗口之内发送流的数据，通知流任务已经发送的字节数 */
+    fn flush(&mut self, id: u32, side: Http2Side) {
+        let Some(stream) = self.streams.get_mut(&id).filter(|stream| !stream.is_reset()) else {
+            return;
+        };
+        let Some(wire) = stream.wire_id(id, side) else {
+            return;
+        };
+        let leg = &mut self.legs[side as usize];
+        let send = &mut stream.send[side as usize];
+        let mut flushed = 0;
+        while !send.closed {
+            let window = std::cmp::max(0, std::cmp::min(send.window, leg.window)) as usize;
+            let n = std::cmp::min(std::cmp::min(send.data.len(), leg.max_frame), window);
+            let end = send.end && n == send.data.len();
+            if n == 0 && !end {
+                break;
+            }
+            // 有trailers时由trailers结束流
+            let trailers = if end { send.trailers.take() } else { None };
+            if n > 0 || trailers.is_none() {
+                let flags = if end && trailers.is_none() { H2_FLAG_END_STREAM } else { 0 };
+                leg.out.extend(ProtoH2Ctx::build_frame(H2_FRAME_DATA, flags, wire, &send.data[..n]));
+            }
+            if let Some(fields) = trailers {
+                let block = self.encoder.encode(&fields);
+                leg.out.extend(ProtoH2Ctx::build_headers(wire, &block, true, leg.max_frame));
+            }
+            send.data.drain(..n);
+            send.window -= n as i64;
+            leg.window -= n as i64;
+            flushed += n;
+            send.closed = end;
+        }
+        if flushed > 0 {
+            stream.notify(Http2ToStream::FLUSHED(side, flushed));
+        }
+    }
+
+    /*
+    * 流任务结束之后删除流
+    * 正常结束时等待响应发送完; 之后http client端仍在发送请求体时通知其停止，http server端未结束的流取消
+    * 已经复位的流两端都已经发送过RST_STREAM，直接删除
+    */
+    fn reap(&mut self, id: u32) {
+        let down = Http2Side::DOWN as usize;
+        let up = Http2Side::UP as usize;
+        let Some(stream) = self.streams.get(&id) else {
+            return;
+        };
+        let Some(code) = stream.done else {
+            return;
+        };
+        if stream.is_reset() {
+            self.streams.remove(&id);
+            return;
+        }
+        if code == H2_NO_ERROR && self.streams.get(&id).is_some_and(|stream| !stream.send[down].closed) {
+            return;
+        }
+        let Some(stream) = self.streams.remove(&id) else {
+            return;
+        };
+        if !stream.send[down].closed || !stream.recv_closed[down] {
+            self.legs[down].out.extend(ProtoH2Ctx::build_rst_stream(id, code));
+        }
+        if let Some(up_id) = stream.up_id {
+            self.up_ids.remove(&up_id);
+            if !stream.send[up].closed || !stream.recv_closed[up] {
+                self.legs[up].out.extend(ProtoH2Ctx::build_rst_stream(up_id, H2_CANCEL));
+            }
+        }
+        self.waiting.retain(|w| *w != id);
+        self.open_waiting();
+    }
+
+    /*
+    * 一端复位了流，复位另一端的流并结束http处理
+    * http server端在完整的响应之后以NO_ERROR复位，表示不再需要请求体，响应仍然需要返回给http client端
+    */
+    fn stream_reset(&mut self, id: u32, side: Http2Side, code: u32) {
+        let (down, up) = (Http2Side::DOWN as usize, Http2Side::UP as usize);
+        let Some(stream) = self.streams.get_mut(&id).filter(|stream| !stream.is_reset()) else {
+            return;
+        };
+        if side == Http2Side::UP && code == H2_NO_ERROR && stream.recv_closed[up] {
+            stream.send[up].closed = true;
+            stream.send[up].data.clear();
+            return;
+        }
+        let reset_down = side == Http2Side::UP && !(stream.send[down].closed && stream.recv_closed[down]);
+        let reset_up = stream.up_id.filter(|_| side == Http2Side::DOWN && !(stream.send[up].closed && stream.recv_closed[up]));
+        if reset_down {
+            self.legs[down].out.extend(ProtoH2Ctx::build_rst_stream(id, code));
+        }
+        if let Some(up_id) = reset_up {
+            self.legs[up].out.extend(ProtoH2Ctx::build_rst_stream(up_id, H2_CANCEL));
+        }
+        self.cancel(id);
+    }
+
+    /*
+    * side一端的流出错，以code复位该端的流
+    * 另一端的流也被复位: http server端取消，http client端为内部错误
+    */
+    fn stream_error(&mut self, id: u32, side: Http2Side, code: u32) {
+        let Some(stream) = self.streams.get(&id).filter(|stream| !stream.is_reset()) else {
+            return;
+        };
+        let other = side.other();
+        let other_code = match other {
+            Http2Side::DOWN => H2_INTERNAL_ERROR,
+            Http2Side::UP => H2_CANCEL,
+        };
+        if let Some(wire) = stream.wire_id(id, side) {
+            self.legs[side as usize].out.extend(ProtoH2Ctx::build_rst_stream(wire, code));
+        }
+        if let Some(wire) = stream.wire_id(id, other) {
+            self.legs[other as usize].out.extend(ProtoH2Ctx::build_rst_stream(wire, other_code));
+        }
+        self.cancel(id);
+    }
+
+    /*
+    * 复位之后取消流的http处理并关闭流任务的输入
+    * 流在流任务发送DONE之后才删除，在此之前仍然计入并发流数
+    */
+    fn cancel(&mut self, id: u32) {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return;
+        };
+        stream.tx = None;
+        stream.engine.abort();
+        stream.up_headers = None;
+        for send in stream.send.iter_mut() {
+            send.data.clear();
+            send.closed = true;
+        }
+        if let Some(up_id) = stream.up_id.take() {
+            self.up_ids.remove(&up_id);
+        }
+        if stream.done.is_some() {
+            self.streams.remove(&id);
+        }
+        self.waiting.retain(|w| *w != id);
+        self.open_waiting();
+    }
+
+    /* 统计http client端复位的流数，短时间内大量复位(rapid reset)时关闭连接 */
+    fn count_reset(&mut self) -> Result<(), (u32, String)> {
+        if self.reset_start.elapsed() >= H2_RESET_PERIOD {
+            self.reset_start = Instant::now();
+            self.resets = 0;
+        }
+        self.resets += 1;
+        if self.resets > H2_MAX_RESETS {
+            return Err((H2_ENHANCE_YOUR_CALM, format!("more than {} streams reset in {:?}", H2_MAX_RESETS, H2_RESET_PERIOD)));
+        }
+        return Ok(());
+    }
+
+    /*
+    * 一个流的任务: 在http/2帧与http处理的两个管道之间转换
+    * down为http处理的http client端，up为http处理的http server端
+    * 管道满时暂停写入，流的接收窗口不归还，对端因此停止发送
+    * 响应完整输出之后结束，关闭管道使http处理结束
+    */
+    async fn process_stream(
+        id: u32,
+        mut rx: UnboundedReceiver<Http2ToStream>,
+        tx: UnboundedSender<(u32, Http2FromStream)>,
+        down: DuplexStream,
+        up: DuplexStream,
+        max_headers: usize,
+    ) {
+        let (mut down_read, mut down_write) = tokio::io::split(down);
+        let (mut up_read, mut up_write) = tokio::io::split(up);
+        let mut pipes = [Http2Pipe::new(), Http2Pipe::new()];
+        let mut readers = [Http2Reader::new(), Http2Reader::new()];
+        let mut method = String::new();
+        let mut buffer_down = [0u8; 8192];
+        let mut buffer_up = [0u8; 8192];
+        let (down, up) = (Http2Side::DOWN as usize, Http2Side::UP as usize);
+        let result: Result<(), String> = async {
+            loop {
+                let mut out = Vec::new();
+                tokio::select! {
+                    msg = rx.recv() => {
+                        let Some(msg) = msg else {
+                            // 流已经被复位
+                            return Ok(());
+                        };
+                        Self::stream_input(msg, &mut pipes, &mut readers, &mut method)?;
+                    }
+                    n = down_write.write(pipes[down].front()), if pipes[down].has_pending() => {
+                        let n = n.map_err(|e| e.to_string())?;
+                        out.push(Http2FromStream::CONSUMED(Http2Side::DOWN, pipes[down].advance(n)));
+                    }
+                    n = up_write.write(pipes[up].front()), if pipes[up].has_pending() => {
+                        let n = n.map_err(|e| e.to_string())?;
+                        out.push(Http2FromStream::CONSUMED(Http2Side::UP, pipes[up].advance(n)));
+                    }
+                    n = down_read.read(&mut buffer_down), if !readers[down].done && readers[down].outstanding < H2_STREAM_BUFFER => {
+                        match n.map_err(|e| e.to_string())? {
+                            0 => out.extend(readers[down].close()?),
+                            n => out.extend(readers[down].feed(Http2Side::DOWN, &buffer_down[..n], &method, max_headers)?),
+                        }
+                    }
+                    n = up_read.read(&mut buffer_up), if !readers[up].done && readers[up].outstanding < H2_STREAM_BUFFER => {
+                        match n.map_err(|e| e.to_string())? {
+                            // http处理没有发出完整的请求，http server端的流在结束时被取消
+                            0 => readers[up].done = true,
+                            n => out.extend(readers[up].feed(Http2Side::UP, &buffer_up[..n], &method, max_headers)?),
+                        }
+                    }
+                }
+                for msg in out {
+                    _ = tx.send((id, msg));
+                }
+                // http server端的响应已经完整写入，关闭写方向
+                if pipes[up].ended && !pipes[up].has_pending() && !pipes[up].shutdown {
+                    pipes[up].shutdown = true;
+                    _ = up_write.shutdown().await;
+                }
+                if readers[down].done {
+                    return Ok(());
+                }
+            }
+        }
+        .await;
+        let code = match result {
+            Ok(_) => H2_NO_ERROR,
+            Err(e) => {
+                println!("HTTP/2 stream {} error: {}", id, e);
+                H2_INTERNAL_ERROR
+            }
+        };
+        _ = tx.send((id, Http2FromStream::DONE(code)));
+    }
+
+    /* 连接任务转来的帧，转换为http/1.1消息写入管道 */
+    fn stream_input(msg: Http2ToStream, pipes: &mut [Http2Pipe; 2], readers: &mut [Http2Reader; 2], method: &mut String) -> Result<(), String> {
+        match msg {
+            Http2ToStream::HEADERS(side, fields, end) => {
+                let pipe = &mut pipes[side as usize];
+                // trailers写入chunked消息体的最后一个chunk; 使用Content-Length的消息无法携带trailers，只结束消息
+                if pipe.head {
+                    if pipe.chunked && !pipe.ended {
+                        pipe.push(ProtoH2Ctx::build_last_chunk(&fields), 0);
+                        pipe.ended = true;
+                    }
+                    pipe.finish();
+                    return Ok(());
+                }
+                let head = match side {
+                    Http2Side::DOWN => {
+                        let head = ProtoH2Ctx::build_h1_request(&fields, end)?;
+                        *method = fields.iter().find(|(n, _)| n == b":method").map(|(_, v)| String::from_utf8_lossy(v).to_string()).unwrap_or_default();
+                        head
+                    }
+                    Http2Side::UP => {
+                        let interim = fields.iter().any(|(n, v)| n == b":status" && v.first() == Some(&b'1'));
+                        let head = ProtoH2Ctx::build_h1_response(&fields, end, method)?;
+                        if interim {
+                            pipe.push(head.head, 0);
+                            return Ok(());
+                        }
+                        head
+                    }
+                };
+                pipe.push(head.head, 0);
+                pipe.head = true;
+                pipe.chunked = head.chunked;
+                if end {
+                    pipe.finish();
+                }
+            }
+            Http2ToStream::DATA(side, data, credit, end) => pipes[side as usize].data(data, credit, end),
+            Http2ToStream::FLUSHED(side, n) => {
+                let reader = &mut readers[side as usize];
+                reader.outstanding = reader.outstanding.saturating_sub(n);
+            }
+        }
+        return Ok(());
+    }
+}
@@ -0,0 +1,785 @@
+use crate::common::common_tls::common_tls_client_config;
+use crate::config::local_json::{
+    LocalConfigIcapBalance, LocalConfigIcapIdentity, LocalConfigIcapRemote, LocalConfigIcapServer, LocalConfigIcapTls,
+};
+use base64::Engine;
+use crate::protocol::icap::ProtoIcapOptions;
+use rustls::pki_types::ServerName;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+    sync::Notify,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+/* 到icap server的连接: 明文或者ICAPS(TLS) */
+pub enum IcapStream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/*
+* 单个icap server的连接: 空闲连接及连接总数(空闲 + 借出)
+* 每个icap server单独通知，归还连接只唤醒等待同一个server的事务
+*/
+struct IcapPoolServer {
+    idle: Vec<(IcapStream, Instant)>,
+    total: usize,
+    notify: Arc<Notify>,
+}
+
+/*
+* 每个runtime的icap连接池
+* 1. 按icap server保存空闲的keep-alive连接
+* 2. 连接总数不超过 poolSize 与 Max-Connections 中的较小值，超过时等待归还，最多等待connectTimeout
+* 3. 空闲超时的连接被淘汰，复用前检查连接是否已被对端关闭
+*/
+pub struct IcapPool {
+    servers: Mutex<HashMap<String, IcapPoolServer>>,
+    // 每个icap server的TLS客户端配置，TLS配置变化时重新加载
+    tls_configs: Mutex<HashMap<String, (LocalConfigIcapTls, TlsConnector)>>,
+}
+
+/* 单个icap server的健康状态与权重轮询状态 */
+struct IcapServerState {
+    failures: u32,
+    down: bool,
+    retry_time: Instant,
+    current_weight: i64,
+}
+
+/*
+* 一致性hash环，每个server按权重放置虚拟节点
+* 只在server列表或权重变化时重新构建
+*/
+struct IcapHashRing {
+    servers: Vec<(String, u32)>,
+    nodes: Vec<(u64, String)>,
+}
+
+/*
+* 多个icap server之间的负载均衡
+* 1. round-robin: 平滑加权轮询
+* 2. least-outstanding: 借出连接数/权重最小
+* 3. hash: 按客户端ip一致性hash
+* 连续失败failThreshold次标记为down，retryInterval之后由probe探测恢复
+*/
+pub struct IcapBalancer {
+    servers: Mutex<HashMap<String, IcapServerState>>,
+    ring: Mutex<IcapHashRing>,
+}
+
+/* 每个runtime的icap客户端: service能力缓存、连接池、负载均衡 */
+pub struct IcapClient {
+    pub options: IcapOptionsCache,
+    pub pool: Arc<IcapPool>,
+    pub balancer: IcapBalancer,
+}
+
+/* 从连接池借出的连接，drop时可复用的连接归还到连接池 */
+pub struct IcapConn {
+    socket: Option<IcapStream>,
+    server: String,
+    reusable: bool,
+    pool: Arc<IcapPool>,
+}
+
+impl IcapPool {
+    pub fn new() -> Self {
+        Self {
+            servers: Mutex::new(HashMap::new()),
+            tls_configs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /*
+    * 借出一个连接，用于一个icap事务
+    * max_connections 为icap server通告的Max-Connections
+    * 等待归还超过connectTimeout时返回ResourceBusy，由调用者按失败策略处理
+    */
+    pub async fn get(
+        self: &Arc<Self>,
+        icap_remote: &LocalConfigIcapRemote,
+        server: &str,
+        max_connections: Option<u32>,
+    ) -> Result<IcapConn, std::io::Error> {
+        let server = server.to_string();
+        let idle_timeout = Duration::from_secs(icap_remote.idle_timeout);
+        let mut limit = std::cmp::max(icap_remote.pool_size, 1);
+        if let Some(max) = max_connections {
+            limit = std::cmp::min(limit, std::cmp::max(max as usize, 1));
+        }
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(icap_remote.connect_timeout);
+        loop {
+            let notify = {
+                let mut servers = self.servers.lock().unwrap();
+                let entry = servers.entry(server.clone()).or_insert(IcapPoolServer {
+                    idle: Vec::new(),
+                    total: 0,
+                    notify: Arc::new(Notify::new()),
+                });
+                // 优先复用空闲连接
+                while let Some((socket, last_used)) = entry.idle.pop() {
+                    if last_used.elapsed() < idle_timeout && Self::is_alive(&socket) {
+                        return Ok(IcapConn::new(socket, &server, self.clone()));
+                    }
+                    entry.total -= 1;
+                }
+                if entry.total < limit {
+                    entry.total += 1;
+                    break;
+                }
+                entry.notify.clone()
+            };
+            // 连接数已达上限，等待归还; 检查之后到开始等待之间的归还保存为permit，不会丢失
+            if tokio::time::timeout_at(deadline, notify.notified()).await.is_err() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ResourceBusy,
+                    format!("ICAP pool {} exhausted", server),
+                ));
+            }
+        }
+
+        // 已计入连接总数，连接过程中被取消或者连接失败时由drop归还
+        let mut conn = IcapConn {
+            socket: None,
+            server,
+            reusable: false,
+            pool: self.clone(),
+        };
+        conn.socket = Some(self.connect(icap_remote, &conn.server).await?);
+        return Ok(conn);
+    }
+
+    /*
+    * 建立到icap server的连接，超过connectTimeout视为失败
+    * 配置了TLS的icap server在TCP连接之后进行TLS握手，校验证书与主机名
+    */
+    pub async fn connect(&self, icap_remote: &LocalConfigIcapRemote, server: &str) -> Result<IcapStream, std::io::Error> {
+        let connect_timeout = Duration::from_secs(icap_remote.connect_timeout);
+        let config = icap_remote.find_server(server);
+        let connect = async {
+            let socket = TcpStream::connect(server).await?;
+            let tls = match config.and_then(|c| c.tls.as_ref()) {
+                Some(tls) => tls,
+                None => return Ok(IcapStream::Tcp(socket)),
+            };
+            let connector = self.tls_connector(server, tls)?;
+            let server_name = Self::tls_server_name(config.unwrap(), tls)?;
+            let stream = connector.connect(server_name, socket).await?;
+            return Ok(IcapStream::Tls(Box::new(stream)));
+        };
+        match tokio::time::timeout(connect_timeout, connect).await {
+            Ok(result) => return result,
+            Err(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("ICAP connect {} timed out", server),
+                ))
+            }
+        }
+    }
+
+    /* 获取icap server的TLS客户端配置，首次使用或者配置变化时加载证书 */
+    fn tls_connector(&self, server: &str, tls: &LocalConfigIcapTls) -> Result<TlsConnector, std::io::Error> {
+        let mut tls_configs = self.tls_configs.lock().unwrap();
+        if let Some((cached, connector)) = tls_configs.get(server) {
+            if cached == tls {
+                return Ok(connector.clone());
+            }
+        }
+        let config = common_tls_client_config(&tls.ca_file, &tls.cert_file, &tls.key_file)?;
+        let connector = TlsConnector::from(Arc::new(config));
+        tls_configs.insert(server.to_string(), (tls.clone(), connector.clone()));
+        return Ok(connector);
+    }
+
+    /* SNI以及证书校验使用的主机名，未配置serverName时使用ip */
+    fn tls_server_name(config: &LocalConfigIcapServer, tls: &LocalConfigIcapTls) -> Result<ServerName<'static>, std::io::Error> {
+        let name = if tls.server_name.is_empty() { &config.ip } else { &tls.server_name };
+        return ServerName::try_from(name.clone()).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid ICAP server name {}: {}", name, e))
+        });
+    }
+
+    /* 空闲连接上不应该有数据，可读到数据或者EOF都说明连接不可用 */
+    fn is_alive(socket: &IcapStream) -> bool {
+        let mut buffer = [0u8; 1];
+        match socket.tcp().try_read(&mut buffer) {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return true,
+            _ => return false,
+        }
+    }
+
+    /* 归还连接；socket为None表示连接已关闭 */
+    fn release(&self, server: &str, socket: Option<IcapStream>) {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(entry) = servers.get_mut(server) {
+            match socket {
+                Some(socket) => entry.idle.push((socket, Instant::now())),
+                None => entry.total -= 1,
+            }
+            entry.notify.notify_one();
+        }
+    }
+
+    /* 借出的连接数，即正在进行的icap事务数 */
+    pub fn outstanding(&self, server: &str) -> usize {
+        let servers = self.servers.lock().unwrap();
+        match servers.get(server) {
+            Some(entry) => return entry.total - entry.idle.len(),
+            None => return 0,
+        }
+    }
+
+    /* 淘汰空闲超时的连接 */
+    pub fn evict_idle(&self, idle_timeout: Duration) {
+        let mut servers = self.servers.lock().unwrap();
+        for entry in servers.values_mut() {
+            let before = entry.idle.len();
+            entry.idle.retain(|(_, last_used)| last_used.elapsed() < idle_timeout);
+            entry.total -= before - entry.idle.len();
+            if entry.idle.len() < before {
+                entry.notify.notify_one();
+            }
+        }
+    }
+}
+
+impl IcapStream {
+    /* 底层的TCP连接 */
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            IcapStream::Tcp(socket) => return socket,
+            IcapStream::Tls(stream) => return stream.get_ref().0,
+        }
+    }
+}
+
+impl AsyncRead for IcapStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            IcapStream::Tcp(socket) => Pin::new(socket).poll_read(cx, buf),
+            IcapStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for IcapStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            IcapStream::Tcp(socket) => Pin::new(socket).poll_write(cx, buf),
+            IcapStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            IcapStream::Tcp(socket) => Pin::new(socket).poll_flush(cx),
+            IcapStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            IcapStream::Tcp(socket) => Pin::new(socket).poll_shutdown(cx),
+            IcapStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+impl IcapConn {
+    fn new(socket: IcapStream, server: &str, pool: Arc<IcapPool>) -> Self {
+        Self {
+            socket: Some(socket),
+            server: server.to_string(),
+            reusable: false,
+            pool,
+        }
+    }
+
+    pub fn socket(&mut self) -> &mut IcapStream {
+        self.socket.as_mut().unwrap()
+    }
+
+    /* icap事务正常结束后才能复用 */
+    pub fn set_reusable(&mut self, reusable: bool) {
+        self.reusable = reusable;
+    }
+}
+
+impl Drop for IcapConn {
+    fn drop(&mut self) {
+        let socket = self.socket.take();
+        if self.reusable {
+            self.pool.release(&self.server, socket);
+        } else {
+            self.pool.release(&self.server, None);
+        }
+    }
+}
+
+/* 每个icap service的OPTIONS缓存，key为 server/service */
+pub struct IcapOptionsCache {
+    services: Mutex<HashMap<String, ProtoIcapOptions>>,
+}
+
+impl IcapOptionsCache {
+    pub fn new() -> Self {
+        Self {
+            services: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /* 获取未超时的缓存 */
+    pub fn get(&self, server: &str, service: &str) -> Option<ProtoIcapOptions> {
+        let services = self.services.lock().unwrap();
+        let options = services.get(&format!("{}/{}", server, service))?;
+        if options.is_expired() {
+            return None;
+        }
+        return Some(options.clone());
+    }
+
+    pub fn insert(&self, options: ProtoIcapOptions) {
+        let key = format!("{}/{}", options.server, options.service);
+        self.services.lock().unwrap().insert(key, options);
+    }
+
+    /*
+    * 获取icap service的能力
+    * 缓存不存在或者Options-TTL已超时，则从连接池借出连接发送OPTIONS并更新缓存
+    */
+    pub async fn fetch(
+        &self,
+        pool: &Arc<IcapPool>,
+        icap_remote: &LocalConfigIcapRemote,
+        server: &str,
+        service: &str,
+    ) -> Result<ProtoIcapOptions, std::io::Error> {
+        if let Some(options) = self.get(server, service) {
+            return Ok(options);
+        }
+        let mut conn = pool.get(icap_remote, server, None).await?;
+        let response_timeout = Duration::from_secs(icap_remote.response_timeout);
+        let options = Self::request_options(conn.socket(), server, service, response_timeout).await?;
+        conn.set_reusable(true);
+        println!(
+            "ICAP OPTIONS {}/{}: methods {:?}, preview {:?}, istag {}",
+            server, service, options.methods, options.preview, options.istag
+        );
+        self.insert(options.clone());
+        return Ok(options);
+    }
+
+    /* 发送OPTIONS并等待响应，超过responseTimeout视为失败 */
+    pub async fn request_options(
+        icap_socket: &mut IcapStream,
+        server: &str,
+        service: &str,
+        response_timeout: Duration,
+    ) -> Result<ProtoIcapOptions, std::io::Error> {
+        let mut options = ProtoIcapOptions::new(server, service);
+        icap_socket.write_all(&options.build_options()).await?;
+        icap_socket.flush().await?;
+
+        let mut data = Vec::new();
+        let mut buffer = [0u8; 8192];
+        loop {
+            let n = match tokio::time::timeout(response_timeout, icap_socket.read(&mut buffer)).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("ICAP OPTIONS {}/{} timed out", server, service),
+                    ))
+                }
+            };
+            if n == 0 {
+                return Err(std::io::Error::other("ICAP server closed during OPTIONS"));
+            }
+            data.extend_from_slice(&buffer[0..n]);
+            match options.parse_options_resp(&data) {
+                Ok(0) => continue,
+                Ok(_) => return Ok(options),
+                Err(e) => return Err(std::io::Error::other(e)),
+            }
+        }
+    }
+}
+
+impl IcapBalancer {
+    pub fn new() -> Self {
+        Self {
+            servers: Mutex::new(HashMap::new()),
+            ring: Mutex::new(IcapHashRing {
+                servers: Vec::new(),
+                nodes: Vec::new(),
+            }),
+        }
+    }
+
+    /* server列表或权重变化时重新构建一致性hash环 */
+    fn update_ring(ring: &mut IcapHashRing, icap_remote: &LocalConfigIcapRemote) {
+        let servers: Vec<_> = icap_remote.servers.iter().map(|s| (s.server(), s.weight)).collect();
+        if ring.servers == servers {
+            return;
+        }
+        let mut nodes = Vec::new();
+        for (server, weight) in servers.iter() {
+            for i in 0..*weight * 40 {
+                nodes.push((Self::hash(&(server, i)), server.clone()));
+            }
+        }
+        nodes.sort();
+        ring.servers = servers;
+        ring.nodes = nodes;
+    }
+
+    /*
+    * 选择一个可用的icap server
+    * 所有server都不可用时返回None
+    */
+    pub fn select(&self, icap_remote: &LocalConfigIcapRemote, pool: &IcapPool, client_ip: IpAddr) -> Option<String> {
+        let mut states = self.servers.lock().unwrap();
+        for server in icap_remote.servers.iter() {
+            states.entry(server.server()).or_insert(IcapServerState {
+                failures: 0,
+                down: false,
+                retry_time: Instant::now(),
+                current_weight: 0,
+            });
+        }
+        let up: Vec<_> = icap_remote
+            .servers
+            .iter()
+            .filter(|s| !states[&s.server()].down)
+            .collect();
+        if up.is_empty() {
+            return None;
+        }
+
+        match icap_remote.balance {
+            LocalConfigIcapBalance::RoundRobin => {
+                // 平滑加权轮询
+                let total: i64 = up.iter().map(|s| s.weight as i64).sum();
+                let mut best: Option<String> = None;
+                let mut best_weight = i64::MIN;
+                for server in up.iter() {
+                    let state = states.get_mut(&server.server()).unwrap();
+                    state.current_weight += server.weight as i64;
+                    if state.current_weight > best_weight {
+                        best_weight = state.current_weight;
+                        best = Some(server.server());
+                    }
+                }
+                let best = best?;
+                states.get_mut(&best).unwrap().current_weight -= total;
+                return Some(best);
+            }
+            LocalConfigIcapBalance::LeastOutstanding => {
+                let best = up.iter().min_by(|a, b| {
+                    let a_load = pool.outstanding(&a.server()) as u64 * b.weight as u64;
+                    let b_load = pool.outstanding(&b.server()) as u64 * a.weight as u64;
+                    a_load.cmp(&b_load)
+                })?;
+                return Some(best.server());
+            }
+            LocalConfigIcapBalance::ClientHash => {
+                // 一致性hash环上顺时针找到第一个可用的server
+                let mut ring = self.ring.lock().unwrap();
+                Self::update_ring(&mut ring, icap_remote);
+                let ring = &ring.nodes;
+                let key = Self::hash(&client_ip);
+                let start = ring.partition_point(|(h, _)| *h < key);
+                for i in 0..ring.len() {
+                    let (_, server) = &ring[(start + i) % ring.len()];
+                    if !states[server].down {
+                        return Some(server.clone());
+                    }
+                }
+                return None;
+            }
+        }
+    }
+
+    fn hash<T: Hash>(value: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        return hasher.finish();
+    }
+
+    /* 记录一次连接/事务的结果，连续失败达到阈值则标记为down */
+    pub fn report(&self, icap_remote: &LocalConfigIcapRemote, server: &str, ok: bool) {
+        let mut states = self.servers.lock().unwrap();
+        let state = match states.get_mut(server) {
+            Some(state) => state,
+            None => return,
+        };
+        if ok {
+            state.failures = 0;
+            return;
+        }
+        state.failures += 1;
+        if !state.down && state.failures >= std::cmp::max(icap_remote.fail_threshold, 1) {
+            println!("ICAP server {} is down after {} failures", server, state.failures);
+            state.down = true;
+            state.retry_time = Instant::now() + Duration::from_secs(icap_remote.retry_interval);
+        }
+    }
+
+    /*
+    * 探测down状态的icap server
+    * 到达retryInterval后发送OPTIONS，成功则恢复为可用
+    */
+    pub async fn probe(&self, icap_remote: &LocalConfigIcapRemote, pool: &IcapPool) {
+        let due: Vec<String> = {
+            let mut states = self.servers.lock().unwrap();
+            let now = Instant::now();
+            let mut due = Vec::new();
+            for (server, state) in states.iter_mut() {
+                if state.down && state.retry_time <= now {
+                    state.retry_time = now + Duration::from_secs(icap_remote.retry_interval);
+                    due.push(server.clone());
+                }
+            }
+            due
+        };
+        let response_timeout = Duration::from_secs(icap_remote.response_timeout);
+        for server in due {
+            let probe = async {
+                let mut socket = pool.connect(icap_remote, &server).await?;
+                IcapOptionsCache::request_options(&mut socket, &server, &icap_remote.reqmod_service, response_timeout).await
+            };
+            let ok = probe.await.is_ok();
+            if ok {
+                println!("ICAP server {} is up", server);
+                let mut states = self.servers.lock().unwrap();
+                if let Some(state) = states.get_mut(&server) {
+                    state.down = false;
+                    state.failures = 0;
+                }
+            }
+        }
+    }
+}
+
+impl IcapClient {
+    pub fn new() -> Self {
+        Self {
+            options: IcapOptionsCache::new(),
+            pool: Arc::new(IcapPool::new()),
+            balancer: IcapBalancer::new(),
+        }
+    }
+
+    /*
+    * 构造发送给icap server的客户端身份头部
+    * 1. X-Client-IP / X-Server-IP: 客户端地址与原始目的地址
+    * 2. X-Authenticated-User / X-Authenticated-Groups: 从ip到用户的映射中查找，未找到则不发送
+    */
+    pub fn identity_headers(identity: &LocalConfigIcapIdentity, client_ip: IpAddr, server_ip: IpAddr) -> Vec<(String, String)> {
+        let mut headers = Vec::new();
+        if !identity.client_ip_header.is_empty() {
+            headers.push((identity.client_ip_header.clone(), client_ip.to_string()));
+        }
+        if !identity.server_ip_header.is_empty() {
+            headers.push((identity.server_ip_header.clone(), server_ip.to_string()));
+        }
+        let user = match identity.users.get(&client_ip) {
+            Some(user) => user,
+            None => return headers,
+        };
+        let encode = |value: &str| {
+            if identity.base64 {
+                return base64::engine::general_purpose::STANDARD.encode(value);
+            }
+            // 不编码时去掉控制字符，避免破坏icap头部
+            return value.chars().filter(|c| !c.is_control()).collect();
+        };
+        if !identity.user_header.is_empty() && !user.user.is_empty() {
+            headers.push((identity.user_header.clone(), encode(&user.user)));
+        }
+        if !identity.groups_header.is_empty() && !user.groups.is_empty() {
+            headers.push((identity.groups_header.clone(), encode(&user.groups.join(","))));
+        }
+        return headers;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::local_json::{LocalConfigIcapFailPolicy, LocalConfigIcapMultipart};
+    use tokio::net::TcpListener;
+
+    fn remote(balance: LocalConfigIcapBalance, servers: &[(&str, u16, u32)]) -> LocalConfigIcapRemote {
+        return LocalConfigIcapRemote {
+            enable: true,
+            servers: servers
+                .iter()
+                .map(|(ip, port, weight)| LocalConfigIcapServer {
+                    ip: ip.to_string(),
+                    port: *port,
+                    weight: *weight,
+                    tls: None,
+                })
+                .collect(),
+            balance,
+            fail_threshold: 2,
+            retry_interval: 10,
+            reqmod_service: "reqmod".to_string(),
+            respmod_service: "respmod".to_string(),
+            pool_size: 16,
+            idle_timeout: 60,
+            connect_timeout: 1,
+            response_timeout: 1,
+            max_body_size: 1024,
+            reqmod_fail_policy: LocalConfigIcapFailPolicy::FailOpen,
+            respmod_fail_policy: LocalConfigIcapFailPolicy::FailOpen,
+            identity: LocalConfigIcapIdentity {
+                client_ip_header: String::new(),
+                server_ip_header: String::new(),
+                user_header: String::new(),
+                groups_header: String::new(),
+                base64: false,
+                users: Arc::new(HashMap::new()),
+            },
+            multipart: LocalConfigIcapMultipart::Whole,
+        };
+    }
+
+    fn client(i: u8) -> IpAddr {
+        return IpAddr::from([10, 0, 0, i]);
+    }
+
+    #[test]
+    fn select_round_robin_weighted() {
+        let icap_remote = remote(LocalConfigIcapBalance::RoundRobin, &[("10.1.1.1", 1344, 3), ("10.1.1.2", 1344, 1)]);
+        let balancer = IcapBalancer::new();
+        let pool = IcapPool::new();
+        let picks: Vec<_> = (0..8).map(|_| balancer.select(&icap_remote, &pool, client(1)).unwrap()).collect();
+        assert_eq!(picks.iter().filter(|s| *s == "10.1.1.1:1344").count(), 6);
+        // 平滑加权轮询，不会连续选择同一个server超过权重
+        assert_eq!(picks[..4], ["10.1.1.1:1344", "10.1.1.1:1344", "10.1.1.2:1344", "10.1.1.1:1344"]);
+
+        // 连续失败达到阈值后不再选择，全部down时返回None
+        balancer.report(&icap_remote, "10.1.1.1:1344", false);
+        assert!(balancer.select(&icap_remote, &pool, client(1)).is_some());
+        balancer.report(&icap_remote, "10.1.1.1:1344", false);
+        for _ in 0..4 {
+            assert_eq!(balancer.select(&icap_remote, &pool, client(1)).unwrap(), "10.1.1.2:1344");
+        }
+        balancer.report(&icap_remote, "10.1.1.2:1344", false);
+        balancer.report(&icap_remote, "10.1.1.2:1344", false);
+        assert!(balancer.select(&icap_remote, &pool, client(1)).is_none());
+    }
+
+    #[test]
+    fn select_client_hash() {
+        let servers = [("10.1.1.1", 1344, 1), ("10.1.1.2", 1344, 1), ("10.1.1.3", 1344, 1)];
+        let icap_remote = remote(LocalConfigIcapBalance::ClientHash, &servers);
+        let balancer = IcapBalancer::new();
+        let pool = IcapPool::new();
+        let picks: Vec<_> = (0..32).map(|i| balancer.select(&icap_remote, &pool, client(i)).unwrap()).collect();
+        // 同一个客户端总是选择同一个server，不同客户端分布到多个server
+        for i in 0..32 {
+            assert_eq!(balancer.select(&icap_remote, &pool, client(i)).unwrap(), picks[i as usize]);
+        }
+        assert!(servers.iter().all(|(ip, _, _)| picks.iter().any(|s| s.starts_with(ip))));
+
+        // server down时只有映射到该server的客户端改变
+        let down = picks[0].clone();
+        balancer.report(&icap_remote, &down, false);
+        balancer.report(&icap_remote, &down, false);
+        for i in 0..32 {
+            let pick = balancer.select(&icap_remote, &pool, client(i)).unwrap();
+            assert_ne!(pick, down);
+            if picks[i as usize] != down {
+                assert_eq!(pick, picks[i as usize]);
+            }
+        }
+
+        // server列表变化时重新构建hash环
+        let icap_remote = remote(LocalConfigIcapBalance::ClientHash, &servers[1..]);
+        let balancer = IcapBalancer::new();
+        for i in 0..32 {
+            let pick = balancer.select(&icap_remote, &pool, client(i)).unwrap();
+            assert!(pick != "10.1.1.1:1344");
+            if !picks[i as usize].starts_with("10.1.1.1") {
+                assert_eq!(pick, picks[i as usize]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn select_least_outstanding() {
+        let a = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let b = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (a_port, b_port) = (a.local_addr().unwrap().port(), b.local_addr().unwrap().port());
+        let icap_remote = remote(
+            LocalConfigIcapBalance::LeastOutstanding,
+            &[("127.0.0.1", a_port, 2), ("127.0.0.1", b_port, 1)],
+        );
+        let (a_server, b_server) = (format!("127.0.0.1:{}", a_port), format!("127.0.0.1:{}", b_port));
+        let balancer = IcapBalancer::new();
+        let pool = Arc::new(IcapPool::new());
+
+        // 借出连接数/权重最小的server
+        let first = pool.get(&icap_remote, &a_server, None).await.unwrap();
+        assert_eq!(balancer.select(&icap_remote, &pool, client(1)).unwrap(), b_server);
+        let second = pool.get(&icap_remote, &b_server, None).await.unwrap();
+        assert_eq!(balancer.select(&icap_remote, &pool, client(1)).unwrap(), a_server);
+        let third = pool.get(&icap_remote, &a_server, None).await.unwrap();
+        assert_eq!(pool.outstanding(&a_server), 2);
+        // 负载相同时选择配置中靠前的server
+        assert_eq!(balancer.select(&icap_remote, &pool, client(1)).unwrap(), a_server);
+        drop((first, second, third));
+        assert_eq!(pool.outstanding(&a_server), 0);
+    }
+
+    #[tokio::test]
+    async fn pool_limits_and_reuse() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut icap_remote = remote(LocalConfigIcapBalance::RoundRobin, &[("127.0.0.1", port, 1)]);
+        icap_remote.pool_size = 2;
+        let server = format!("127.0.0.1:{}", port);
+        let pool = Arc::new(IcapPool::new());
+
+        // 可复用的连接归还后被下一个事务复用
+        let mut conn = pool.get(&icap_remote, &server, None).await.unwrap();
+        conn.set_reusable(true);
+        let local = conn.socket().tcp().local_addr().unwrap();
+        drop(conn);
+        assert_eq!(pool.outstanding(&server), 0);
+        let conn = pool.get(&icap_remote, &server, None).await.unwrap();
+        assert_eq!(conn.socket.as_ref().unwrap().tcp().local_addr().unwrap(), local);
+
+        // 连接总数达到poolSize，等待超过connectTimeout返回ResourceBusy
+        let second = pool.get(&icap_remote, &server, None).await.unwrap();
+        let err = pool.get(&icap_remote, &server, None).await.err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::ResourceBusy);
+
+        // Max-Connections小于poolSize时使用较小值；归还连接唤醒等待的事务
+        drop(second);
+        let waiter = {
+            let (pool, icap_remote, server) = (pool.clone(), icap_remote.clone(), server.clone());
+            tokio::spawn(async move { pool.get(&icap_remote, &server, Some(1)).await.map(|_| ()) })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiter.is_finished());
+        drop(conn);
+        assert!(waiter.await.unwrap().is_ok());
+        assert_eq!(pool.outstanding(&server), 0);
+    }
+}
@@ -1 +1,5 @@
 pub mod http;
+pub mod http2;
+pub mod icap;
+pub mod tls;
+pub mod tls_cache;
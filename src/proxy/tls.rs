@@ -0,0 +1,716 @@
+use crate::common::common_net::common_try_peek;
+use crate::common::common_tls::{common_tls_load_certs, common_tls_load_key, common_tls_root_store};
+use crate::config::local_json::{LocalConfigTls, LocalConfigTlsPolicy};
+use crate::protocol::tls::{ProtoTlsHello, TLS_HELLO_MAX_SIZE};
+use crate::proxy::tls_cache::{TlsCertCache, TlsCertEntry, TlsCertStored};
+use rcgen::{CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, KeyPair, KeyUsagePurpose};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::server::Acceptor;
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use tokio_rustls::{client, server, LazyConfigAcceptor, TlsConnector};
+
+// TLS握手超时
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// 签发的证书有效期，起始时间提前一天以容忍客户端的时钟偏差
+const TLS_LEAF_VALID_DAYS: i64 = 30;
+// 缓存的证书距离过期不足该时间时重新签发
+const TLS_LEAF_RENEW_SECS: u64 = 24 * 3600;
+
+/* 签发证书使用的企业CA */
+struct TlsMitmCa {
+    // 发送给http client端的CA证书链
+    chain: Vec<CertificateDer<'static>>,
+    // 从CA证书解析的签发者信息，以及CA私钥
+    issuer: rcgen::Certificate,
+    key: KeyPair,
+}
+
+/* 一份TLS配置对应的CA以及连接http server端的TLS客户端配置 */
+struct TlsMitmState {
+    ca: TlsMitmCa,
+    // CA证书的指纹，用于识别CA的更换
+    ca_fingerprint: String,
+    // 连接http server端的TLS客户端配置，每个连接按http client端的ALPN复制一份
+    client_config: Arc<ClientConfig>,
+    // 使用配置的信任CA校验http server证书
+    verifier: Arc<WebPkiServerVerifier>,
+}
+
+/*
+* 连接http server端使用的证书校验
+* 握手时只校验签名，握手完成之后再校验证书链，按错误类型决定如何处理
+*/
+#[derive(Debug)]
+struct TlsUpstreamVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+}
+
+/* http server证书的错误类型 */
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+enum TlsUpstreamError {
+    // 过期或者尚未生效
+    EXPIRED,
+    // 自签名证书
+    SELFSIGNED,
+    // 证书不包含SNI
+    MISMATCH,
+    // 其它错误: 未知CA、吊销、签名错误等
+    INVALID,
+}
+
+/* 解密的结果 */
+#[allow(clippy::upper_case_acronyms)]
+pub enum TlsMitmOutcome {
+    // 与http client端、http server端的TLS连接，以及证书使用的主机名
+    INTERCEPT(Box<server::TlsStream<TcpStream>>, Box<client::TlsStream<TcpStream>>, String),
+    // 不解密，原样转发，以及原因
+    PASSTHROUGH(TcpStream, String),
+}
+
+/* 加载CA使用的文件及其修改时间，任何一个变化时重新加载 */
+#[derive(PartialEq)]
+struct TlsMitmSource {
+    files: Vec<(String, Option<SystemTime>)>,
+}
+
+/*
+* TLS解密(中间人)
+* 1. 预读http client端的ClientHello，取得SNI
+* 2. 使用SNI连接原始目的地址，并按策略校验http server端的证书
+* 3. 使用企业CA为SNI签发证书(优先使用缓存)，完成与http client端的握手
+* 所有runtime共享一个实例，CA配置或者文件变化时重新加载CA
+*/
+pub struct TlsMitm {
+    state: Mutex<Option<(TlsMitmSource, Arc<TlsMitmState>)>>,
+    cache: TlsCertCache,
+}
+
+impl ServerCertVerifier for TlsUpstreamVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        return Ok(ServerCertVerified::assertion());
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        return self.inner.verify_tls12_signature(message, cert, dss);
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        return self.inner.verify_tls13_signature(message, cert, dss);
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        return self.inner.supported_verify_schemes();
+    }
+}
+
+impl TlsUpstreamError {
+    /*
+    * 根据证书链的校验错误分类，chain为http server发送的证书链
+    * 自签名证书通常带有CA标志，错误可能是未知CA或者CA证书用作服务端证书，按证书本身判断
+    */
+    fn classify(error: &rustls::Error, chain: &[CertificateDer<'_>]) -> Self {
+        match error {
+            rustls::Error::InvalidCertificate(
+                CertificateError::Expired
+                | CertificateError::ExpiredContext { .. }
+                | CertificateError::NotValidYet
+                | CertificateError::NotValidYetContext { .. },
+            ) => return Self::EXPIRED,
+            rustls::Error::InvalidCertificate(CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. }) => {
+                return Self::MISMATCH;
+            }
+            _ if Self::self_issued(&chain[0]) => return Self::SELFSIGNED,
+            _ => return Self::INVALID,
+        }
+    }
+
+    fn self_issued(cert: &CertificateDer<'_>) -> bool {
+        match x509_parser::parse_x509_certificate(cert) {
+            Ok((_, cert)) => return cert.issuer() == cert.subject(),
+            Err(_) => return false,
+        }
+    }
+
+    fn policy(&self, config: &LocalConfigTls) -> LocalConfigTlsPolicy {
+        match self {
+            Self::EXPIRED => return config.upstream_policy.expired,
+            Self::SELFSIGNED => return config.upstream_policy.self_signed,
+            Self::MISMATCH => return config.upstream_policy.hostname_mismatch,
+            Self::INVALID => return config.upstream_policy.other,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::EXPIRED => return "expired",
+            Self::SELFSIGNED => return "self-signed",
+            Self::MISMATCH => return "hostname mismatch",
+            Self::INVALID => return "invalid",
+        }
+    }
+}
+
+impl TlsMitmCa {
+    fn load(config: &LocalConfigTls) -> Result<Self, std::io::Error> {
+        let chain = common_tls_load_certs(&config.ca_cert)?;
+        let key = match common_tls_load_key(&config.ca_key)? {
+            PrivateKeyDer::Pkcs8(key) => KeyPair::try_from(&key),
+            _ => return Err(std::io::Error::other(format!("{} 不是PKCS#8格式的私钥", config.ca_key))),
+        }
+        .map_err(std::io::Error::other)?;
+        // 证书链中的第一个证书为签发证书的CA
+        let issuer = CertificateParams::from_ca_cert_der(&chain[0])
+            .and_then(|params| params.self_signed(&key))
+            .map_err(std::io::Error::other)?;
+        return Ok(Self { chain, issuer, key });
+    }
+
+    /* 服务端证书的参数，CN为第一个名字 */
+    fn leaf_params(names: Vec<String>) -> Result<CertificateParams, rcgen::Error> {
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CommonName, names[0].as_str());
+        let mut params = CertificateParams::new(names)?;
+        params.distinguished_name = name;
+        let now = time::OffsetDateTime::now_utc();
+        params.not_before = now - time::Duration::days(1);
+        params.not_after = now + time::Duration::days(TLS_LEAF_VALID_DAYS);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature, KeyUsagePurpose::KeyEncipherment];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        return Ok(params);
+    }
+
+    /*
+    * 为主机名或者ip签发证书
+    * 每个证书使用新生成的密钥，序列号由公钥计算
+    */
+    fn mint(&self, host: &str) -> Result<TlsCertStored, rcgen::Error> {
+        let key = KeyPair::generate()?;
+        let params = Self::leaf_params(vec![host.to_string()])?;
+        let created = (params.not_before + time::Duration::days(1)).unix_timestamp() as u64;
+        let not_after = params.not_after.unix_timestamp() as u64;
+        let cert = params.signed_by(&key, &self.issuer, &self.key)?;
+
+        return Ok(TlsCertStored {
+            leaf: cert.der().clone(),
+            key: PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+            created,
+            not_after,
+        });
+    }
+
+    /*
+    * 签发http client端不会接受的证书，重现http server证书的错误
+    * EXPIRED: 企业CA签发，有效期与http server证书相同
+    * MISMATCH: 企业CA签发，名字与http server证书相同
+    * 其它: 自签名证书
+    * 返回证书链与私钥
+    */
+    fn mint_untrusted(
+        &self,
+        host: &str,
+        origin: &CertificateDer<'_>,
+        error: TlsUpstreamError,
+    ) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), rcgen::Error> {
+        let key = KeyPair::generate()?;
+        let parsed = x509_parser::parse_x509_certificate(origin).ok().map(|(_, cert)| cert);
+        let mut names = vec![host.to_string()];
+        if let (TlsUpstreamError::MISMATCH, Some(origin)) = (error, parsed.as_ref()) {
+            names = Self::origin_names(origin);
+            // 没有可用的名字时使用一个不会匹配的名字
+            if names.is_empty() {
+                names.push("invalid.invalid".to_string());
+            }
+        }
+        let mut params = Self::leaf_params(names)?;
+        if let (TlsUpstreamError::EXPIRED, Some(origin)) = (error, parsed.as_ref()) {
+            params.not_before = origin.validity().not_before.to_datetime();
+            params.not_after = origin.validity().not_after.to_datetime();
+        }
+        let chain = match error {
+            TlsUpstreamError::EXPIRED | TlsUpstreamError::MISMATCH => {
+                let cert = params.signed_by(&key, &self.issuer, &self.key)?;
+                let mut chain = vec![cert.der().clone()];
+                chain.extend(self.chain.iter().cloned());
+                chain
+            }
+            _ => {
+                params.use_authority_key_identifier_extension = false;
+                vec![params.self_signed(&key)?.der().clone()]
+            }
+        };
+        return Ok((chain, PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()))));
+    }
+
+    /* http server证书中的主机名与ip: SAN，没有SAN时使用CN */
+    fn origin_names(origin: &x509_parser::certificate::X509Certificate<'_>) -> Vec<String> {
+        let mut names = Vec::new();
+        if let Ok(Some(san)) = origin.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    x509_parser::extensions::GeneralName::DNSName(dns) => names.push(dns.to_string()),
+                    x509_parser::extensions::GeneralName::IPAddress(ip) => match ip.len() {
+                        4 => names.push(IpAddr::from(<[u8; 4]>::try_from(*ip).unwrap_or_default()).to_string()),
+                        16 => names.push(IpAddr::from(<[u8; 16]>::try_from(*ip).unwrap_or_default()).to_string()),
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+        if names.is_empty() {
+            if let Some(cn) = origin.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()) {
+                names.push(cn.to_string());
+            }
+        }
+        return names;
+    }
+}
+
+impl TlsMitm {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(None),
+            cache: TlsCertCache::new(),
+        }
+    }
+
+    fn now() -> u64 {
+        return SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    }
+
+    /*
+    * 当前配置对应的CA与TLS客户端配置，配置或者文件变化时重新加载
+    * CA更换(指纹变化)时清空内存中的证书，并删除磁盘上旧CA签发的证书
+    */
+    fn state(&self, config: &LocalConfigTls) -> Result<Arc<TlsMitmState>, std::io::Error> {
+        let source = TlsMitmSource {
+            files: [&config.ca_cert, &config.ca_key, &config.upstream_ca]
+                .iter()
+                .map(|f| (f.to_string(), std::fs::metadata(f).and_then(|m| m.modified()).ok()))
+                .collect(),
+        };
+        let mut state = self.state.lock().unwrap();
+        if let Some((cached, loaded)) = state.as_ref() {
+            if *cached == source {
+                return Ok(loaded.clone());
+            }
+        }
+        let ca = TlsMitmCa::load(config)?;
+        let ca_fingerprint = TlsCertCache::fingerprint(&ca.chain[0]);
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let roots = Arc::new(common_tls_root_store(&config.upstream_ca)?);
+        let verifier = WebPkiServerVerifier::builder_with_provider(roots, provider.clone())
+            .build()
+            .map_err(std::io::Error::other)?;
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(std::io::Error::other)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(TlsUpstreamVerifier { inner: verifier.clone() }))
+            .with_no_client_auth();
+        if let Some((_, previous)) = state.as_ref() {
+            if previous.ca_fingerprint != ca_fingerprint {
+                println!("TLS interception CA rotated, generated certificates discarded");
+                self.cache.clear();
+            }
+        }
+        if !config.cache_dir.is_empty() {
+            let removed = TlsCertCache::prune(&config.cache_dir, &ca_fingerprint, Self::now());
+            if removed > 0 {
+                println!("TLS certificate cache {}: {} stale certificates removed", config.cache_dir, removed);
+            }
+        }
+        let loaded = Arc::new(TlsMitmState {
+            ca,
+            ca_fingerprint,
+            client_config: Arc::new(client_config),
+            verifier,
+        });
+        *state = Some((source, loaded.clone()));
+        println!("TLS interception CA loaded from {}", config.ca_cert);
+        return Ok(loaded);
+    }
+
+    /* 证书未到密钥轮换时间，且距离过期还有足够的时间 */
+    fn fresh(config: &LocalConfigTls, created: u64, not_after: u64, now: u64) -> bool {
+        return now < created.saturating_add(config.key_rotation) && now.saturating_add(TLS_LEAF_RENEW_SECS) < not_after;
+    }
+
+    /*
+    * 取得主机名对应的TLS服务端配置
+    * 依次查找内存缓存、磁盘缓存，都没有可用的证书时签发新证书
+    * upstream: http server端证书的指纹，http server更换证书时重新签发
+    */
+    fn server_config(
+        &self,
+        config: &LocalConfigTls,
+        state: &TlsMitmState,
+        host: &str,
+        upstream: &str,
+    ) -> Result<Arc<ServerConfig>, std::io::Error> {
+        let key = format!("{}|{}", host, upstream);
+        let now = Self::now();
+        if let Some(entry) = self.cache.get(&key) {
+            if Self::fresh(config, entry.created, entry.not_after, now) {
+                return Ok(entry.server_config.clone());
+            }
+            self.cache.remove(&key);
+        }
+
+        let stored = match config.cache_dir.is_empty() {
+            true => None,
+            false => TlsCertCache::load(&config.cache_dir, &key, &state.ca_fingerprint),
+        };
+        let stored = match stored {
+            Some(stored) if Self::fresh(config, stored.created, stored.not_after, now) => stored,
+            _ => {
+                let stored = state.ca.mint(host).map_err(std::io::Error::other)?;
+                if !config.cache_dir.is_empty() {
+                    if let Err(e) = TlsCertCache::store(&config.cache_dir, &key, &state.ca_fingerprint, &stored) {
+                        println!("TLS certificate cache {}: save {} failed: {}", config.cache_dir, host, e);
+                    }
+                }
+                stored
+            }
+        };
+
+        let mut chain = vec![stored.leaf];
+        chain.extend(state.ca.chain.iter().cloned());
+        let server_config = Self::build_server_config(chain, stored.key)?;
+        let entry = TlsCertEntry {
+            server_config: server_config.clone(),
+            created: stored.created,
+            not_after: stored.not_after,
+        };
+        self.cache.insert(&key, Arc::new(entry), config.cache_size);
+        return Ok(server_config);
+    }
+
+    fn build_server_config(
+        chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<Arc<ServerConfig>, std::io::Error> {
+        let server_config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(std::io::Error::other)?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(std::io::Error::other)?;
+        return Ok(Arc::new(server_config));
+    }
+
+    /*
+    * 解密一个TLS连接，hello为预读的ClientHello
+    * 先使用SNI连接原始目的地址，校验http server证书，再完成与http client端的握手
+    * http server证书校验失败时按配置的策略:
+    * Block: 不完成与http client端的握手
+    * Passthrough: 返回PASSTHROUGH，由调用者原样转发
+    * Untrusted: 使用重现错误的证书完成握手，http client端会看到证书错误
+    * ALPN: 向http server端提供http client端列表中支持的协议，再以http server端选择的协议与http client端握手
+    */
+    pub async fn intercept(
+        &self,
+        config: &LocalConfigTls,
+        down_socket: TcpStream,
+        orig_dst: SocketAddr,
+        hello: &ProtoTlsHello,
+    ) -> Result<TlsMitmOutcome, std::io::Error> {
+        let state = self.state(config)?;
+        // 没有SNI时使用目的ip
+        let host = match &hello.sni {
+            Some(sni) => sni.clone(),
+            None => orig_dst.ip().to_string(),
+        };
+
+        let server_name = ServerName::try_from(host.clone()).map_err(std::io::Error::other)?;
+        let mut client_config = (*state.client_config).clone();
+        client_config.alpn_protocols = Self::alpn(config, hello);
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let up_socket = TcpStream::connect(orig_dst).await?;
+        let up_socket = match Self::timeout(connector.connect(server_name.clone(), up_socket)).await {
+            Ok(up_socket) => up_socket,
+            Err(e) => {
+                println!("ALERT: TLS upstream {} ({}) handshake failed: {}", host, orig_dst, e);
+                return Err(e);
+            }
+        };
+
+        let chain = up_socket.get_ref().1.peer_certificates().unwrap_or(&[]).to_vec();
+        let Some(leaf) = chain.first() else {
+            return Err(std::io::Error::other(format!("TLS upstream {} ({}) sent no certificate", host, orig_dst)));
+        };
+        let server_config = match state.verifier.verify_server_cert(leaf, &chain[1..], &server_name, &[], UnixTime::now()) {
+            Ok(_) => {
+                println!("TLS upstream {} ({}) certificate valid, intercept", host, orig_dst);
+                self.server_config(config, &state, &host, &TlsCertCache::fingerprint(leaf))?
+            }
+            Err(e) => {
+                let error = TlsUpstreamError::classify(&e, &chain);
+                let decision = format!("TLS upstream {} ({}) certificate {}: {}", host, orig_dst, error.name(), e);
+                match error.policy(config) {
+                    LocalConfigTlsPolicy::Block => {
+                        println!("ALERT: {}, block", decision);
+                        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, decision));
+                    }
+                    LocalConfigTlsPolicy::Passthrough => {
+                        println!("ALERT: {}, pass through", decision);
+                        return Ok(TlsMitmOutcome::PASSTHROUGH(down_socket, format!("upstream certificate {}", error.name())));
+                    }
+                    LocalConfigTlsPolicy::Untrusted => {
+                        println!("ALERT: {}, intercept with untrusted certificate", decision);
+                        // 不缓存，每次重新签发
+                        let (chain, key) = state.ca.mint_untrusted(&host, leaf, error).map_err(std::io::Error::other)?;
+                        Self::build_server_config(chain, key)?
+                    }
+                }
+            }
+        };
+        let server_config = match up_socket.get_ref().1.alpn_protocol() {
+            Some(protocol) => {
+                let mut server_config = (*server_config).clone();
+                server_config.alpn_protocols = vec![protocol.to_vec()];
+                Arc::new(server_config)
+            }
+            None => server_config,
+        };
+        let start = Self::timeout(LazyConfigAcceptor::new(Acceptor::default(), down_socket)).await?;
+        let down_socket = Self::timeout(start.into_stream(server_config)).await?;
+        return Ok(TlsMitmOutcome::INTERCEPT(Box::new(down_socket), Box::new(up_socket), host));
+    }
+
+    /* http client端ALPN列表中可以解密处理的协议，保持http client端的顺序 */
+    fn alpn(config: &LocalConfigTls, hello: &ProtoTlsHello) -> Vec<Vec<u8>> {
+        return hello
+            .alpn
+            .iter()
+            .filter(|p| p.as_str() == "http/1.1" || (config.http2 && p.as_str() == "h2"))
+            .map(|p| p.as_bytes().to_vec())
+            .collect();
+    }
+
+    /*
+    * 读取ClientHello但不取走数据，之后的握手或者转发仍然从头读取
+    * 数据不完整时等待后续数据到达: 没有新数据时清除socket的可读状态，新数据到达后才再次唤醒
+    */
+    pub async fn peek_hello(socket: &TcpStream) -> Result<ProtoTlsHello, std::io::Error> {
+        return Self::timeout(async {
+            let mut buffer = vec![0u8; 4096];
+            let mut last = 0;
+            loop {
+                let ready = socket.ready(Interest::READABLE).await?;
+                let peeked = socket.try_io(Interest::READABLE, || {
+                    let n = common_try_peek(socket, &mut buffer)?;
+                    if n > 0 && n == last && n < buffer.len() {
+                        // 对端已关闭，不会再有数据
+                        if ready.is_read_closed() {
+                            return Ok(0);
+                        }
+                        return Err(std::io::ErrorKind::WouldBlock.into());
+                    }
+                    return Ok(n);
+                });
+                let n = match peeked {
+                    Ok(n) => n,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                };
+                if n == 0 {
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed before TLS ClientHello"));
+                }
+                if n > last {
+                    match ProtoTlsHello::parse(&buffer[..n]) {
+                        Ok(Some(hello)) => return Ok(hello),
+                        Ok(None) => {}
+                        Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+                    }
+                    last = n;
+                }
+                if n == buffer.len() {
+                    if n > TLS_HELLO_MAX_SIZE {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "TLS ClientHello too large"));
+                    }
+                    buffer.resize(n * 2, 0);
+                }
+            }
+        })
+        .await;
+    }
+
+    /*
+    * 判断连接是否不解密，返回命中的规则
+    * 主机名与通配符匹配SNI，ip与网段匹配原始目的地址
+    */
+    pub fn bypass(config: &LocalConfigTls, hello: Option<&ProtoTlsHello>, dst: IpAddr) -> Option<String> {
+        if let Some(sni) = hello.and_then(|h| h.sni.as_deref()) {
+            if let Some(domain) = config.bypass_domains.iter().find(|d| *d == sni) {
+                return Some(domain.clone());
+            }
+            // *.example.com 匹配任意层级的子域名，不匹配example.com本身
+            let wildcard = config.bypass_wildcards.iter().find(|w| match w.strip_prefix('*') {
+                Some(suffix) => suffix.starts_with('.') && sni.ends_with(suffix) && sni.len() > suffix.len(),
+                None => *w == sni,
+            });
+            if let Some(wildcard) = wildcard {
+                return Some(wildcard.clone());
+            }
+        }
+        let (ip, prefix) = config.bypass_ips.iter().find(|(ip, prefix)| Self::ip_match(*ip, *prefix, dst))?;
+        return Some(format!("{}/{}", ip, prefix));
+    }
+
+    fn ip_match(net: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+        let (net, ip, bits) = match (net, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net) as u128, u32::from(ip) as u128, 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        if prefix == 0 {
+            return true;
+        }
+        let shift = bits - prefix as u32;
+        return net >> shift == ip >> shift;
+    }
+
+    async fn timeout<T>(future: impl std::future::Future<Output = Result<T, std::io::Error>>) -> Result<T, std::io::Error> {
+        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, future).await {
+            Ok(result) => return result,
+            Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS handshake timed out")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::local_json::LocalConfigTlsUpstream;
+
+    fn config(domains: &[&str], wildcards: &[&str], ips: &[(&str, u8)]) -> LocalConfigTls {
+        let names = |list: &[&str]| list.iter().map(|v| v.to_string()).collect();
+        return LocalConfigTls {
+            intercept: true,
+            ports: vec![443],
+            ca_cert: String::new(),
+            ca_key: String::new(),
+            upstream_ca: String::new(),
+            upstream_policy: LocalConfigTlsUpstream {
+                expired: LocalConfigTlsPolicy::Block,
+                self_signed: LocalConfigTlsPolicy::Block,
+                hostname_mismatch: LocalConfigTlsPolicy::Block,
+                other: LocalConfigTlsPolicy::Block,
+            },
+            http2: true,
+            cache_size: 16,
+            cache_dir: String::new(),
+            key_rotation: 3600,
+            bypass_domains: names(domains),
+            bypass_wildcards: names(wildcards),
+            bypass_ips: ips.iter().map(|(ip, prefix)| (ip.parse().unwrap(), *prefix)).collect(),
+        };
+    }
+
+    fn hello(sni: &str) -> ProtoTlsHello {
+        return ProtoTlsHello {
+            sni: Some(sni.to_string()),
+            alpn: Vec::new(),
+        };
+    }
+
+    #[tokio::test]
+    async fn peek_hello_waits_for_more_data() {
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        // 没有扩展的ClientHello，分两次发送
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        let mut data = vec![0x16, 0x03, 0x01, 0, body.len() as u8 + 4, 0x01, 0, 0, body.len() as u8];
+        data.extend_from_slice(&body);
+        client.write_all(&data[..10]).await.unwrap();
+        let rest = data[10..].to_vec();
+        let writer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.write_all(&rest).await.unwrap();
+            client
+        });
+        let hello = TlsMitm::peek_hello(&server).await.unwrap();
+        assert!(hello.sni.is_none());
+        // 数据没有被取走
+        let mut buffer = vec![0u8; data.len()];
+        assert_eq!(server.peek(&mut buffer).await.unwrap(), data.len());
+        assert_eq!(buffer, data);
+
+        // 数据不完整时对端关闭
+        drop(writer.await.unwrap());
+        let (server, _) = {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let accepted = listener.accept().await.unwrap();
+            client.write_all(&data[..10]).await.unwrap();
+            accepted
+        };
+        let err = TlsMitm::peek_hello(&server).await.err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn bypass_domains_and_wildcards() {
+        let config = config(&["bank.com"], &["*.pay.com", "exact.org"], &[]);
+        let dst: IpAddr = "192.0.2.1".parse().unwrap();
+        let bypass = |sni: &str| TlsMitm::bypass(&config, Some(&hello(sni)), dst);
+        assert_eq!(bypass("bank.com").as_deref(), Some("bank.com"));
+        assert_eq!(bypass("www.bank.com"), None);
+        // 通配符匹配任意层级的子域名，不匹配域名本身与相同后缀的其它域名
+        assert_eq!(bypass("a.pay.com").as_deref(), Some("*.pay.com"));
+        assert_eq!(bypass("a.b.pay.com").as_deref(), Some("*.pay.com"));
+        assert_eq!(bypass("pay.com"), None);
+        assert_eq!(bypass("evilpay.com"), None);
+        assert_eq!(bypass("exact.org").as_deref(), Some("exact.org"));
+        assert_eq!(bypass("a.exact.org"), None);
+        // 没有SNI时只匹配ip
+        assert_eq!(TlsMitm::bypass(&config, None, dst), None);
+    }
+
+    #[test]
+    fn bypass_ips() {
+        let all = config(&[], &[], &[("10.1.0.0", 16), ("192.0.2.7", 32), ("2001:db8::", 32), ("0.0.0.0", 0)]);
+        let bypass = |dst: &str| TlsMitm::bypass(&all, Some(&hello("a.com")), dst.parse().unwrap());
+        assert_eq!(bypass("10.1.255.3").as_deref(), Some("10.1.0.0/16"));
+        assert_eq!(bypass("192.0.2.7").as_deref(), Some("192.0.2.7/32"));
+        assert_eq!(bypass("2001:db8:1::1").as_deref(), Some("2001:db8::/32"));
+        // /0 匹配所有同一地址族的地址
+        assert_eq!(bypass("10.2.0.1").as_deref(), Some("0.0.0.0/0"));
+        assert_eq!(bypass("2001:db9::1"), None);
+
+        let net = config(&[], &[], &[("10.1.0.0", 16)]);
+        assert_eq!(TlsMitm::bypass(&net, None, "10.1.0.1".parse().unwrap()).as_deref(), Some("10.1.0.0/16"));
+        assert_eq!(TlsMitm::bypass(&net, None, "10.2.0.1".parse().unwrap()), None);
+    }
+}
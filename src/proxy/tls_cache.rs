@@ -0,0 +1,322 @@
+use base64::Engine;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// 写入临时文件的序号，避免多个runtime同时写同一个证书时互相覆盖
+static TLS_CACHE_TMP_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/* 内存中缓存的证书 */
+pub struct TlsCertEntry {
+    // 由证书链与私钥构造的TLS服务端配置
+    pub server_config: Arc<ServerConfig>,
+    // 签发时间与证书过期时间(unix时间，秒)
+    pub created: u64,
+    pub not_after: u64,
+}
+
+/* 签发或者从磁盘读取的证书，CA证书链不保存，使用时补上 */
+pub struct TlsCertStored {
+    pub leaf: CertificateDer<'static>,
+    pub key: PrivateKeyDer<'static>,
+    pub created: u64,
+    pub not_after: u64,
+}
+
+struct TlsCertLru {
+    // 证书以及最近使用的序号
+    entries: HashMap<String, (Arc<TlsCertEntry>, u64)>,
+    // 按最近使用排序，第一个为最久未使用
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/*
+* 签发证书的缓存
+* 1. key为 SNI|http server证书指纹，http server更换证书时重新签发
+* 2. 内存中为LRU，所有runtime共享
+* 3. 配置了目录时每个证书保存为一个PEM文件，重启后继续使用
+*    文件中记录签发CA的指纹，CA更换后的文件不再使用并被删除
+*/
+pub struct TlsCertCache {
+    lru: Mutex<TlsCertLru>,
+}
+
+impl TlsCertCache {
+    pub fn new() -> Self {
+        Self {
+            lru: Mutex::new(TlsCertLru {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /* 查找证书，找到时标记为最近使用 */
+    pub fn get(&self, key: &str) -> Option<Arc<TlsCertEntry>> {
+        let mut lru = self.lru.lock().unwrap();
+        let lru = &mut *lru;
+        lru.tick += 1;
+        let (entry, used) = lru.entries.get_mut(key)?;
+        lru.order.remove(used);
+        *used = lru.tick;
+        lru.order.insert(lru.tick, key.to_string());
+        return Some(entry.clone());
+    }
+
+    /* 插入证书，超过容量时淘汰最久未使用的证书 */
+    pub fn insert(&self, key: &str, entry: Arc<TlsCertEntry>, capacity: usize) {
+        let mut lru = self.lru.lock().unwrap();
+        let lru = &mut *lru;
+        lru.tick += 1;
+        if let Some((_, used)) = lru.entries.insert(key.to_string(), (entry, lru.tick)) {
+            lru.order.remove(&used);
+        }
+        lru.order.insert(lru.tick, key.to_string());
+        while lru.entries.len() > capacity {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            lru.entries.remove(&oldest);
+        }
+    }
+
+    pub fn remove(&self, key: &str) {
+        let mut lru = self.lru.lock().unwrap();
+        if let Some((_, used)) = lru.entries.remove(key) {
+            lru.order.remove(&used);
+        }
+    }
+
+    /* CA更换时清空 */
+    pub fn clear(&self) {
+        let mut lru = self.lru.lock().unwrap();
+        lru.entries.clear();
+        lru.order.clear();
+    }
+
+    /* 证书或者其它数据的SHA-256指纹(十六进制) */
+    pub fn fingerprint(data: &[u8]) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, data);
+        return digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    }
+
+    /* 证书文件的路径，文件名为key的指纹 */
+    fn path(dir: &str, key: &str) -> PathBuf {
+        return PathBuf::from(dir).join(format!("{}.pem", Self::fingerprint(key.as_bytes())));
+    }
+
+    /*
+    * 证书文件的格式: 注释行记录元数据，之后是leaf证书与私钥
+    * # key: <SNI|http server证书指纹>
+    * # ca: <CA证书指纹>
+    * # created: <unix时间>
+    * # not-after: <unix时间>
+    */
+    fn parse_meta(content: &str) -> HashMap<&str, &str> {
+        let mut meta = HashMap::new();
+        for line in content.lines() {
+            let Some(line) = line.strip_prefix("# ") else {
+                break;
+            };
+            if let Some((name, value)) = line.split_once(": ") {
+                meta.insert(name, value);
+            }
+        }
+        return meta;
+    }
+
+    /* 从磁盘读取证书，CA不一致或者格式错误时返回None */
+    pub fn load(dir: &str, key: &str, ca: &str) -> Option<TlsCertStored> {
+        let content = std::fs::read_to_string(Self::path(dir, key)).ok()?;
+        let meta = Self::parse_meta(&content);
+        if meta.get("key") != Some(&key) || meta.get("ca") != Some(&ca) {
+            return None;
+        }
+        let created = meta.get("created")?.parse().ok()?;
+        let not_after = meta.get("not-after")?.parse().ok()?;
+        let leaf = rustls_pemfile::certs(&mut content.as_bytes()).next()?.ok()?;
+        let key = rustls_pemfile::private_key(&mut content.as_bytes()).ok()??;
+        return Some(TlsCertStored {
+            leaf,
+            key,
+            created,
+            not_after,
+        });
+    }
+
+    /* 保存证书到磁盘，先写临时文件再改名，私钥文件只有属主可读 */
+    pub fn store(dir: &str, key: &str, ca: &str, stored: &TlsCertStored) -> Result<(), std::io::Error> {
+        std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        let mut content = format!(
+            "# key: {}\n# ca: {}\n# created: {}\n# not-after: {}\n",
+            key, ca, stored.created, stored.not_after
+        );
+        content.push_str(&Self::pem("CERTIFICATE", stored.leaf.as_ref()));
+        content.push_str(&Self::pem("PRIVATE KEY", stored.key.secret_der()));
+
+        let path = Self::path(dir, key);
+        let tmp = path.with_extension(format!("{}.tmp", TLS_CACHE_TMP_SEQ.fetch_add(1, Ordering::Relaxed)));
+        let result = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .and_then(|mut file| file.write_all(content.as_bytes()))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        return result;
+    }
+
+    /*
+    * 删除不是当前CA签发的、已经过期的证书文件，以及残留的临时文件
+    * 返回删除的文件数
+    */
+    pub fn prune(dir: &str, ca: &str, now: u64) -> usize {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return 0;
+        };
+        let mut removed = 0;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let stale = match path.extension().and_then(|e| e.to_str()) {
+                Some("pem") => match std::fs::read_to_string(&path) {
+                    Ok(content) => {
+                        let meta = Self::parse_meta(&content);
+                        let not_after = meta.get("not-after").and_then(|t| t.parse::<u64>().ok()).unwrap_or(0);
+                        meta.get("ca") != Some(&ca) || not_after <= now
+                    }
+                    Err(_) => false,
+                },
+                Some("tmp") => true,
+                _ => false,
+            };
+            if stale && std::fs::remove_file(&path).is_ok() {
+                removed += 1;
+            }
+        }
+        return removed;
+    }
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(der);
+        let mut pem = format!("-----BEGIN {}-----\n", label);
+        for line in encoded.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).unwrap_or(""));
+            pem.push('\n');
+        }
+        pem.push_str(&format!("-----END {}-----\n", label));
+        return pem;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn stored(created: u64, not_after: u64) -> TlsCertStored {
+        let cert = rcgen::generate_simple_self_signed(vec!["a.example".to_string()]).unwrap();
+        return TlsCertStored {
+            leaf: cert.cert.der().clone(),
+            key: PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der())),
+            created,
+            not_after,
+        };
+    }
+
+    fn entry() -> Arc<TlsCertEntry> {
+        let stored = stored(0, 0);
+        let server_config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![stored.leaf], stored.key)
+            .unwrap();
+        return Arc::new(TlsCertEntry {
+            server_config: Arc::new(server_config),
+            created: 0,
+            not_after: 0,
+        });
+    }
+
+    /* 每个测试使用单独的目录 */
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rt_proxy_tls_cache_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        return dir.to_string_lossy().to_string();
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let cache = TlsCertCache::new();
+        let entry = entry();
+        cache.insert("a", entry.clone(), 2);
+        cache.insert("b", entry.clone(), 2);
+        // a 最近使用，插入c时淘汰b
+        assert!(cache.get("a").is_some());
+        cache.insert("c", entry.clone(), 2);
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some() && cache.get("c").is_some());
+
+        // 重复插入同一个key不增加数量
+        cache.insert("c", entry.clone(), 2);
+        cache.insert("d", entry.clone(), 2);
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("c").is_some() && cache.get("d").is_some());
+
+        cache.remove("c");
+        assert!(cache.get("c").is_none());
+        cache.clear();
+        assert!(cache.get("d").is_none());
+    }
+
+    #[test]
+    fn store_and_load() {
+        let dir = test_dir("store");
+        let cert = stored(100, 200);
+        TlsCertCache::store(&dir, "a.example|fp", "ca1", &cert).unwrap();
+        let loaded = TlsCertCache::load(&dir, "a.example|fp", "ca1").unwrap();
+        assert_eq!(loaded.leaf, cert.leaf);
+        assert_eq!(loaded.key.secret_der(), cert.key.secret_der());
+        assert_eq!((loaded.created, loaded.not_after), (100, 200));
+        // 私钥文件只有属主可读
+        let path = TlsCertCache::path(&dir, "a.example|fp");
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // CA更换或者key不同时不使用
+        assert!(TlsCertCache::load(&dir, "a.example|fp", "ca2").is_none());
+        assert!(TlsCertCache::load(&dir, "b.example|fp", "ca1").is_none());
+        // 格式错误
+        std::fs::write(&path, "# key: a.example|fp\n# ca: ca1\n# created: x\n").unwrap();
+        assert!(TlsCertCache::load(&dir, "a.example|fp", "ca1").is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prune_stale_files() {
+        let dir = test_dir("prune");
+        TlsCertCache::store(&dir, "valid", "ca1", &stored(100, 1000)).unwrap();
+        TlsCertCache::store(&dir, "expired", "ca1", &stored(100, 500)).unwrap();
+        TlsCertCache::store(&dir, "old-ca", "ca0", &stored(100, 1000)).unwrap();
+        std::fs::write(PathBuf::from(&dir).join("x.1.tmp"), "partial").unwrap();
+        std::fs::write(PathBuf::from(&dir).join("other.txt"), "keep").unwrap();
+
+        assert_eq!(TlsCertCache::prune(&dir, "ca1", 500), 3);
+        assert!(TlsCertCache::load(&dir, "valid", "ca1").is_some());
+        assert!(PathBuf::from(&dir).join("other.txt").exists());
+        assert_eq!(TlsCertCache::prune(&dir, "ca1", 500), 0);
+        assert_eq!(TlsCertCache::prune(&format!("{}/missing", dir), "ca1", 500), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}